@@ -0,0 +1,13 @@
+-- The table the catalog started with. The catalogs created before the
+-- migrations already have it, so it is only created when it is missing.
+create table if not exists files(
+    id integer primary key,
+    source_file text not null,
+    file_name text not null,
+    file_size text not null,
+    file_type text,
+    file_type_extension text,
+    image_width integer,
+    date_time_original text,
+    creation_date text
+);
@@ -0,0 +1,60 @@
+-- The file groups (the files with the same name in the same directory).
+create table groups(
+    id integer primary key,
+    group_key text not null unique
+);
+
+-- Collecting the same directory twice used to insert the files again,
+-- only the latest row of every file is kept. The hash columns of the old
+-- catalogs are not carried over, they are only a cache and `analyze`
+-- fills them in again.
+create table files_next(
+    id integer primary key,
+    source_file text not null unique,
+    file_name text not null,
+    file_size text not null,
+    file_type text,
+    file_type_extension text,
+    image_width integer,
+    date_time_original text,
+    creation_date text,
+    size_bytes integer,
+    group_id integer references groups(id) on delete set null
+);
+
+insert into files_next(id, source_file, file_name, file_size, file_type, file_type_extension, image_width, date_time_original, creation_date)
+select id, source_file, file_name, file_size, file_type, file_type_extension, image_width, date_time_original, creation_date
+from files
+where id in (select max(id) from files group by source_file);
+
+drop table files;
+alter table files_next rename to files;
+
+-- The full exiftool output of a file.
+create table metadata(
+    file_id integer primary key references files(id) on delete cascade,
+    json text not null
+);
+
+create table hashes(
+    file_id integer primary key references files(id) on delete cascade,
+    partial_hash text,
+    content_hash text,
+    perceptual_hash integer
+);
+
+-- Every rename we did, so a file can be traced back to its old name.
+create table renames(
+    id integer primary key,
+    file_id integer references files(id) on delete set null,
+    prev_path text not null,
+    next_path text not null,
+    renamed_at text not null default current_timestamp
+);
+
+create index files_size_bytes on files(size_bytes);
+create index files_date_time_original on files(date_time_original);
+create index files_group_id on files(group_id);
+create index hashes_partial_hash on hashes(partial_hash);
+create index hashes_content_hash on hashes(content_hash);
+create index renames_file_id on renames(file_id);
@@ -0,0 +1,5 @@
+-- What tells a changed file from an unchanged one on the next collect.
+alter table files add column mtime integer;
+alter table files add column inode integer;
+-- The files that were not found on the last collect of their directory.
+alter table files add column deleted_at text;
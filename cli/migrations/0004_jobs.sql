@@ -0,0 +1,21 @@
+-- A collect run. It is finished when none of its files are pending.
+create table jobs(
+    id integer primary key,
+    root text not null,
+    -- The collect options as JSON, so a resumed job scans the same way.
+    options text not null,
+    started_at text not null default current_timestamp,
+    -- Set once the whole directory was scanned and the files queued.
+    scanned_at text,
+    finished_at text
+);
+
+create table job_files(
+    job_id integer not null references jobs(id) on delete cascade,
+    source_file text not null,
+    state text not null default 'pending' check(state in ('pending', 'done', 'failed')),
+    error text,
+    primary key(job_id, source_file)
+);
+
+create index job_files_state on job_files(job_id, state);
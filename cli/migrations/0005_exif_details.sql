@@ -0,0 +1,16 @@
+-- The fields we query often, the rest is in the metadata JSON.
+alter table files add column make text;
+alter table files add column model text;
+alter table files add column lens text;
+alter table files add column gps_latitude real;
+alter table files add column gps_longitude real;
+alter table files add column duration real;
+alter table files add column orientation text;
+alter table files add column content_identifier text;
+alter table files add column sub_sec_date_time_original text;
+
+create index files_make_model on files(make, model);
+create index files_content_identifier on files(content_identifier);
+
+-- The files collected before have none of it, the next collect reads them again.
+update files set mtime = null where id not in (select file_id from metadata);
@@ -0,0 +1,4 @@
+-- The size and the modification time of the file when it was hashed. The
+-- hashes of a file that changed since are not used.
+alter table hashes add column size_bytes integer;
+alter table hashes add column mtime integer;
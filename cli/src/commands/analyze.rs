@@ -1,14 +1,27 @@
 use crate::commands::collect::connect_database;
+use core::catalog::FileStamp;
+use core::dupes::{Confidence, DuplicateCluster, DuplicateFinder, FileHashes, MemoryStore};
 use core::exif::ExifMetadata;
+use core::utils;
 use eyre::{eyre, Result};
+use indicatif::{ProgressBar, ProgressStyle};
 use sqlx::sqlite::SqliteRow;
-use sqlx::{FromRow, Row};
+use sqlx::{FromRow, Row, SqlitePool};
 use std::collections::HashSet;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 struct DbFile(ExifMetadata);
 
+// The collect command saves the dates with `NaiveDateTime::to_string`,
+// not in the exif format.
+pub(crate) fn parse_db_date(value: Option<String>) -> Option<chrono::NaiveDateTime> {
+    let value = value?;
+    chrono::NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(&value, "%Y:%m:%d %H:%M:%S"))
+        .ok()
+}
+
 impl<'r> FromRow<'r, SqliteRow> for DbFile {
     fn from_row(row: &'r SqliteRow) -> sqlx::Result<Self> {
         Ok(Self(ExifMetadata {
@@ -22,57 +35,178 @@ impl<'r> FromRow<'r, SqliteRow> for DbFile {
             image_width: row
                 .try_get("image_width")
                 .map(|x: Option<u64>| x.map(|y| y as usize))?,
-            date_time_original: row.try_get("date_time_original").map(|x: Option<String>| {
-                match chrono::NaiveDateTime::parse_from_str(
-                    x.unwrap_or("".into()).as_str(),
-                    "%Y:%m:%d %H:%M:%S",
-                ) {
-                    Ok(dt) => Some(dt),
-                    Err(_) => None,
-                }
-            })?,
-            creation_date: row.try_get("creation_date").map(|x: Option<String>| {
-                match chrono::NaiveDateTime::parse_from_str(
-                    x.unwrap_or("".into()).as_str(),
-                    "%Y:%m:%d %H:%M:%S",
-                ) {
-                    Ok(dt) => Some(dt),
-                    Err(_) => None,
-                }
-            })?,
+            date_time_original: row.try_get("date_time_original").map(parse_db_date)?,
+            creation_date: row.try_get("creation_date").map(parse_db_date)?,
             ..Default::default()
         }))
     }
 }
 
+#[derive(Debug, FromRow)]
+struct HashRow {
+    source_file: String,
+    size_bytes: Option<i64>,
+    mtime: Option<i64>,
+    partial_hash: Option<String>,
+    content_hash: Option<String>,
+    perceptual_hash: Option<i64>,
+}
+
+// The duplicate finder works on a store of hashes. We load the one we have
+// from the catalog and save back what the finder computed, so the next run
+// only reads the files it has not seen yet, or that changed since they were
+// hashed. SQLite has no unsigned integers,
+// the perceptual hash is kept as the same bits in an i64.
+pub(crate) async fn find_duplicates(
+    pool: &SqlitePool,
+    similar_threshold: Option<u32>,
+) -> Result<Vec<DuplicateCluster>> {
+    let rows: Vec<HashRow> = sqlx::query_as(
+        r#"
+        select f.source_file, h.size_bytes, h.mtime, h.partial_hash, h.content_hash, h.perceptual_hash
+        from files f left join hashes h on h.file_id = f.id
+        where f.deleted_at is null
+        order by f.id
+    "#,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| eyre!("{e}"))?;
+
+    let mut store = MemoryStore::new();
+    let mut files = vec![];
+    for row in rows {
+        let path = PathBuf::from(&row.source_file);
+        let stamp = row
+            .size_bytes
+            .zip(row.mtime)
+            .map(|(size, mtime)| FileStamp {
+                size: size as u64,
+                mtime,
+                inode: None,
+            });
+        store.load(
+            &path,
+            stamp,
+            FileHashes {
+                size: row.size_bytes.map(|x| x as u64),
+                partial: row.partial_hash,
+                full: row.content_hash,
+                perceptual: row.perceptual_hash.map(|x| x as u64),
+            },
+        );
+        files.push(path);
+    }
+
+    let mut finder = DuplicateFinder::new();
+    if let Some(threshold) = similar_threshold {
+        finder = finder.similar(threshold);
+    }
+    let progress = ProgressBar::new(0).with_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} {msg}")?,
+    );
+    let mut clusters = vec![];
+    let summary = finder.find(
+        &mut store,
+        &files,
+        |x| {
+            progress.set_message(format!("{:?}", x.stage));
+            progress.set_length(x.total as u64);
+            progress.set_position(x.done as u64);
+        },
+        |x| clusters.push(x),
+    );
+    progress.finish_and_clear();
+    for (path, err) in summary.skipped {
+        eprintln!("Skipping '{}': {}", path.display(), err);
+    }
+
+    for (path, stamp, hashes) in store.changed() {
+        let path = utils::path_to_string(path);
+        sqlx::query("update files set size_bytes = ?1 where source_file = ?2")
+            .bind(hashes.size.map(|x| x as i64))
+            .bind(&path)
+            .execute(pool)
+            .await
+            .map_err(|e| eyre!("{e}"))?;
+        sqlx::query(
+            r#"
+            insert into hashes(file_id, partial_hash, content_hash, perceptual_hash, size_bytes, mtime)
+            select id, ?1, ?2, ?3, ?4, ?5 from files where source_file = ?6
+            on conflict(file_id) do update set
+                partial_hash = excluded.partial_hash,
+                content_hash = excluded.content_hash,
+                perceptual_hash = excluded.perceptual_hash,
+                size_bytes = excluded.size_bytes,
+                mtime = excluded.mtime
+        "#,
+        )
+        .bind(&hashes.partial)
+        .bind(&hashes.full)
+        .bind(hashes.perceptual.map(|x| x as i64))
+        .bind(stamp.map(|x| x.size as i64))
+        .bind(stamp.map(|x| x.mtime))
+        .bind(&path)
+        .execute(pool)
+        .await
+        .map_err(|e| eyre!("{e}"))?;
+    }
+
+    Ok(clusters)
+}
+
 #[tokio::main]
-pub async fn exec(db: &PathBuf) -> Result<()> {
+pub async fn exec(db: &Path, similar_threshold: Option<u32>) -> Result<()> {
     let time = std::time::Instant::now();
 
     println!("Finding duplicates...");
     let pool = connect_database(db, false).await?;
 
+    let clusters = find_duplicates(&pool, similar_threshold).await?;
+    let (exact, similar_images): (Vec<_>, Vec<_>) = clusters
+        .into_iter()
+        .map(|x| {
+            (
+                x.confidence,
+                x.files
+                    .iter()
+                    .map(|x| utils::path_to_string(x))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .partition(|x| x.0 == Confidence::Exact);
+    let exact = exact.into_iter().map(|x| x.1).collect::<Vec<_>>();
+    let similar_images = similar_images.into_iter().map(|x| x.1).collect::<Vec<_>>();
+    let exact_files = exact.iter().flatten().cloned().collect::<HashSet<_>>();
+
+    // The metadata heuristic can still point at the re-encoded copies,
+    // the content hash would never find those.
     let mut set: HashSet<ExifMetadata> = HashSet::new();
     let mut dups = vec![];
+    let mut cursor = 0;
 
-    let count: i64 = sqlx::query_as("select count(1) from files")
-        .fetch_one(&pool)
+    loop {
+        let data: Vec<DbFile> = sqlx::query_as(
+            "select * from files where deleted_at is null order by id limit 100 offset ?1",
+        )
+        .bind(cursor)
+        .fetch_all(&pool)
         .await
-        .map_err(|e| eyre!("{e}"))
-        .map(|(count,)| count)?;
-    let mut cursor = 1;
-
-    while cursor < count {
-        let data: Vec<DbFile> = sqlx::query_as("select * from files where id >= ?1 limit 100")
-            .bind(cursor)
-            .fetch_all(&pool)
-            .await
-            .map_err(|e| eyre!("{e}"))?;
+        .map_err(|e| eyre!("{e}"))?;
+        if data.is_empty() {
+            break;
+        }
         let round_count: i64 = data.len().try_into().unwrap();
 
         for item in data {
             let item = item.0;
             if let Some(dup) = set.get(&item) {
+                // Already reported with the higher confidence.
+                if exact_files.contains(&dup.source_file) && exact_files.contains(&item.source_file)
+                {
+                    continue;
+                }
                 dups.push((dup.source_file.clone(), item.source_file));
             } else {
                 set.insert(item);
@@ -82,8 +216,22 @@ pub async fn exec(db: &PathBuf) -> Result<()> {
     }
 
     let mut stdout = std::io::stdout();
+    for item in exact.iter() {
+        writeln!(stdout, "Exact duplicates (same content)::")?;
+        for path in item {
+            writeln!(stdout, "{}", path)?;
+        }
+        writeln!(stdout, "::")?;
+    }
+    for item in similar_images.iter() {
+        writeln!(stdout, "Similar images::")?;
+        for path in item {
+            writeln!(stdout, "{}", path)?;
+        }
+        writeln!(stdout, "::")?;
+    }
     for item in dups.iter() {
-        writeln!(stdout, "Possible duplicates::")?;
+        writeln!(stdout, "Possible duplicates (same metadata)::")?;
         writeln!(stdout, "{}", item.0)?;
         writeln!(stdout, "{}", item.1)?;
         writeln!(stdout, "::")?;
@@ -92,7 +240,15 @@ pub async fn exec(db: &PathBuf) -> Result<()> {
     stdout.flush()?;
     let duration = indicatif::HumanDuration(time.elapsed());
 
-    println!("Found {} possible duplicates in {}", dups.len(), duration);
+    if similar_threshold.is_some() {
+        println!("Found {} sets of similar images", similar_images.len());
+    }
+    println!(
+        "Found {} exact duplicate sets and {} possible duplicates in {}",
+        exact.len(),
+        dups.len(),
+        duration
+    );
 
     Ok(())
 }
@@ -1,18 +1,19 @@
-use core::dir::collect_files;
-use core::exif::{self, ExifMetadata};
+use core::catalog::{self, FileStamp};
+use core::dir::{collect_files_with_options, CollectOptions};
+use core::exif::{self, ExifDetails, ExifMetadata};
 use core::file::{FilePath, InputFile};
 use core::utils;
 use eyre::{eyre, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::SqlitePool;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 pub async fn connect_database(path: &Path, force: bool) -> Result<SqlitePool> {
-    println!("path {path:?}");
     if let Some(parent_dir) = path.parent() {
         if !parent_dir.exists() {
             fs::create_dir_all(parent_dir)?;
@@ -22,40 +23,134 @@ pub async fn connect_database(path: &Path, force: bool) -> Result<SqlitePool> {
     let options = SqliteConnectOptions::from_str(path.to_str().unwrap())?.create_if_missing(true);
     let pool = SqlitePool::connect_with(options).await?;
 
+    // The catalogs created before the migrations upgrade in place.
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .map_err(|e| eyre!("Failed to migrate database {e}"))?;
+
+    // Start the catalog over, the rename history is kept.
     if force {
-        sqlx::query("drop table if exists files")
-            .execute(&pool)
-            .await
-            .map_err(|e| eyre!("Failed to migrate database {e}"))?;
+        for table in ["files", "groups"] {
+            sqlx::query(&format!("delete from {table}"))
+                .execute(&pool)
+                .await
+                .map_err(|e| eyre!("Failed to clear database {e}"))?;
+        }
     }
 
-    sqlx::query(
+    Ok(pool)
+}
+
+// A catalog in memory for the tests. One connection, every connection to
+// `:memory:` is a database of its own.
+#[cfg(test)]
+pub async fn memory_catalog() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}
+
+// The catalog can hold more than one collected directory, so the group is
+// keyed by the full directory, not by the relative one.
+fn catalog_group_key(file: &InputFile) -> String {
+    let dir = file
+        .src
+        .value()
+        .parent()
+        .map(utils::path_to_string)
+        .unwrap_or_default();
+    let stem = file.stem.to_str().map(utils::nfc).unwrap_or_default();
+    format!("{}/{}", dir, stem)
+}
+
+struct CollectedFile {
+    group_key: String,
+    stamp: FileStamp,
+    metadata: ExifMetadata,
+    details: ExifDetails,
+    // The full exiftool output.
+    json: String,
+}
+
+fn read_file(file: &InputFile) -> Result<CollectedFile, String> {
+    let stamp = FileStamp::from_path(file.src.value())?;
+    let json = exif::read_exif_json("exiftool", &file.src)?;
+    let metadata =
+        serde_json::from_str::<ExifMetadata>(&json).map_err(|err| format!("Error: {}", err))?;
+    let value = serde_json::from_str::<serde_json::Value>(&json)
+        .map_err(|err| format!("Error: {}", err))?;
+
+    Ok(CollectedFile {
+        group_key: catalog_group_key(file),
+        stamp,
+        metadata,
+        details: ExifDetails::from_json(&value),
+        json,
+    })
+}
+
+async fn save_to_database(collected: &CollectedFile, pool: &SqlitePool) -> Result<()> {
+    let CollectedFile {
+        group_key,
+        stamp,
+        metadata: file,
+        details,
+        json,
+    } = collected;
+
+    let (group_id,): (i64,) = sqlx::query_as(
         r#"
-        create table if not exists files(
-            id integer primary key, 
-            source_file text not null,
-            file_name text not null,
-            file_size text not null,
-            file_type text,
-            file_type_extension text,
-            image_width integer,
-            date_time_original text,
-            creation_date text
-            )
-    "#,
+                insert into groups(group_key) values(?1)
+                on conflict(group_key) do update set group_key = excluded.group_key
+                returning id
+            "#,
     )
-    .execute(&pool)
+    .bind(group_key)
+    .fetch_one(pool)
     .await
-    .map_err(|e| eyre!("Failed to migrate database {e}"))?;
+    .map_err(|e| eyre!("Failed to save item to database {e}"))?;
 
-    Ok(pool)
-}
+    // The file changed, the hashes of the old content are no good.
+    sqlx::query(
+        "delete from hashes where file_id in (select id from files where source_file = ?1)",
+    )
+    .bind(&file.source_file)
+    .execute(pool)
+    .await
+    .map_err(|e| eyre!("Failed to save item to database {e}"))?;
 
-async fn save_to_database(exif_files: &Vec<ExifMetadata>, pool: &SqlitePool) -> Result<()> {
-    for file in exif_files {
-        sqlx::query(r#"
-                insert into files(source_file, file_name, file_size, file_type, file_type_extension, image_width, date_time_original, creation_date) 
-                values(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+    // Collecting the same file again updates its row.
+    sqlx::query(r#"
+                insert into files(source_file, file_name, file_size, file_type, file_type_extension, image_width, date_time_original, creation_date, group_id, size_bytes, mtime, inode,
+                    make, model, lens, gps_latitude, gps_longitude, duration, orientation, content_identifier, sub_sec_date_time_original)
+                values(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)
+                on conflict(source_file) do update set
+                    file_name = excluded.file_name,
+                    file_size = excluded.file_size,
+                    file_type = excluded.file_type,
+                    file_type_extension = excluded.file_type_extension,
+                    image_width = excluded.image_width,
+                    date_time_original = excluded.date_time_original,
+                    creation_date = excluded.creation_date,
+                    group_id = excluded.group_id,
+                    size_bytes = excluded.size_bytes,
+                    mtime = excluded.mtime,
+                    inode = excluded.inode,
+                    make = excluded.make,
+                    model = excluded.model,
+                    lens = excluded.lens,
+                    gps_latitude = excluded.gps_latitude,
+                    gps_longitude = excluded.gps_longitude,
+                    duration = excluded.duration,
+                    orientation = excluded.orientation,
+                    content_identifier = excluded.content_identifier,
+                    sub_sec_date_time_original = excluded.sub_sec_date_time_original,
+                    deleted_at = null
             "#)
                 .bind(&file.source_file)
                 .bind(&file.file_name)
@@ -65,40 +160,254 @@ async fn save_to_database(exif_files: &Vec<ExifMetadata>, pool: &SqlitePool) ->
                 .bind(file.image_width.map(|x| x as i64))
                 .bind(file.date_time_original.map(|x| x.to_string()))
                 .bind(file.creation_date.map(|x| x.to_string()))
+                .bind(group_id)
+                .bind(stamp.size as i64)
+                .bind(stamp.mtime)
+                .bind(stamp.inode.map(|x| x as i64))
+                .bind(&details.make)
+                .bind(&details.model)
+                .bind(&details.lens)
+                .bind(details.gps_latitude)
+                .bind(details.gps_longitude)
+                .bind(details.duration)
+                .bind(&details.orientation)
+                .bind(&details.content_identifier)
+                .bind(&details.sub_sec_date_time_original)
                 .execute(pool)
                 .await
                 .map_err(|e| eyre!("Failed to save item to database {e}"))?;
+
+    sqlx::query(
+        r#"
+            insert into metadata(file_id, json)
+            select id, ?1 from files where source_file = ?2
+            on conflict(file_id) do update set json = excluded.json
+        "#,
+    )
+    .bind(json)
+    .bind(&file.source_file)
+    .execute(pool)
+    .await
+    .map_err(|e| eyre!("Failed to save item to database {e}"))?;
+
+    Ok(())
+}
+
+fn get_files(path: &Path, options: &CollectOptions) -> Result<Vec<InputFile>> {
+    collect_files_with_options(path, options).map_err(|x| eyre!("{x}"))
+}
+
+#[derive(sqlx::FromRow)]
+struct StampRow {
+    source_file: String,
+    size_bytes: Option<i64>,
+    mtime: Option<i64>,
+    inode: Option<i64>,
+}
+
+async fn known_files(pool: &SqlitePool) -> Result<HashMap<PathBuf, Option<FileStamp>>> {
+    let rows: Vec<StampRow> = sqlx::query_as(
+        "select source_file, size_bytes, mtime, inode from files where deleted_at is null",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| eyre!("Failed to read the catalog {e}"))?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let stamp = row
+                .size_bytes
+                .zip(row.mtime)
+                .map(|(size, mtime)| FileStamp {
+                    size: size as u64,
+                    mtime,
+                    inode: row.inode.map(|x| x as u64),
+                });
+            (PathBuf::from(row.source_file), stamp)
+        })
+        .collect())
+}
+
+async fn mark_removed(files: &[PathBuf], pool: &SqlitePool) -> Result<()> {
+    for path in files {
+        sqlx::query("update files set deleted_at = current_timestamp where source_file = ?1")
+            .bind(utils::path_to_string(path))
+            .execute(pool)
+            .await
+            .map_err(|e| eyre!("Failed to save item to database {e}"))?;
     }
+    Ok(())
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Job {
+    id: i64,
+    root: String,
+    options: String,
+    scanned_at: Option<String>,
+}
+
+async fn create_job(root: &Path, options: &CollectOptions, pool: &SqlitePool) -> Result<Job> {
+    sqlx::query_as(
+        "insert into jobs(root, options) values(?1, ?2) returning id, root, options, scanned_at",
+    )
+    .bind(utils::path_to_string(root))
+    .bind(serde_json::to_string(options)?)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| eyre!("Failed to create the job {e}"))
+}
+
+// The last job of the root (the canonical path) that is unfinished, or that
+// has failed files. An older job can hold the failures when a later one
+// went fine.
+async fn last_job(pool: &SqlitePool, root: &Path, mode: CollectMode) -> Result<Option<Job>> {
+    let query = match mode {
+        CollectMode::RetryFailed => {
+            r#"
+            select id, root, options, scanned_at from jobs j
+            where root = ?1 and exists (
+                select 1 from job_files f where f.job_id = j.id and f.state = 'failed'
+            )
+            order by id desc limit 1
+        "#
+        }
+        _ => {
+            "select id, root, options, scanned_at from jobs where root = ?1 and finished_at is null order by id desc limit 1"
+        }
+    };
+    sqlx::query_as(query)
+        .bind(utils::path_to_string(root))
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| eyre!("Failed to read the job {e}"))
+}
+
+// Scans the job directory and queues the new and the changed files. A job
+// that crashed in the middle of the scan is scanned again, the files that
+// are already queued keep their state.
+async fn scan_job(job: &Job, pool: &SqlitePool) -> Result<()> {
+    let root = PathBuf::from(&job.root);
+    let options: CollectOptions = serde_json::from_str(&job.options)?;
+    let mut scanned = vec![];
+    for file in get_files(&root, &options)? {
+        // The catalog stores the paths as text, we would not be able to
+        // find the file again from a lossy path.
+        if !file.src.is_utf8() {
+            println!("Skipping '{}': the path is not valid UTF-8", file.src);
+            continue;
+        }
+        match FileStamp::from_path(file.src.value()) {
+            Ok(stamp) => scanned.push((file.src.value().to_path_buf(), stamp)),
+            Err(err) => println!("Skipping '{}': {}", file.src, err),
+        }
+    }
+
+    let known = known_files(pool).await?;
+    let diff = catalog::diff(&root, &known, &scanned, |x| {
+        std::fs::symlink_metadata(x).is_ok()
+    });
+
+    // Only the new and the changed files go through exiftool again.
+    let mut tx = pool.begin().await?;
+    for path in diff.added.iter().chain(diff.changed.iter()) {
+        sqlx::query("insert or ignore into job_files(job_id, source_file) values(?1, ?2)")
+            .bind(job.id)
+            .bind(utils::path_to_string(path))
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| eyre!("Failed to queue the file {e}"))?;
+    }
+    sqlx::query("update jobs set scanned_at = current_timestamp where id = ?1")
+        .bind(job.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| eyre!("Failed to save the job {e}"))?;
+    tx.commit().await?;
+
+    mark_removed(&diff.removed, pool).await?;
+
+    println!(
+        "Added {}, changed {}, removed {}, unchanged {} files",
+        diff.added.len(),
+        diff.changed.len(),
+        diff.removed.len(),
+        diff.unchanged.len()
+    );
 
     Ok(())
 }
 
-fn get_files(path: &Path) -> Result<Vec<InputFile>> {
-    collect_files(path).map_err(|x| eyre!("{x}"))
+async fn set_file_state(
+    job: &Job,
+    path: &str,
+    error: Option<&str>,
+    pool: &SqlitePool,
+) -> Result<()> {
+    sqlx::query(
+        "update job_files set state = ?1, error = ?2 where job_id = ?3 and source_file = ?4",
+    )
+    .bind(if error.is_some() { "failed" } else { "done" })
+    .bind(error)
+    .bind(job.id)
+    .bind(path)
+    .execute(pool)
+    .await
+    .map_err(|e| eyre!("Failed to save the file state {e}"))?;
+    Ok(())
 }
 
-async fn collect_metadata(files: Vec<InputFile>, pool: &SqlitePool) -> Result<()> {
-    let progress = ProgressBar::new(files.len().try_into()?).with_style(
+// Goes through the pending files of the job. Every file is marked done or
+// failed as soon as it is saved, so a new run starts right where this one
+// stopped.
+async fn collect_metadata(job: &Job, limit: Option<usize>, pool: &SqlitePool) -> Result<()> {
+    let (pending,): (i64,) =
+        sqlx::query_as("select count(*) from job_files where job_id = ?1 and state = 'pending'")
+            .bind(job.id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| eyre!("Failed to read the job {e}"))?;
+    let total = limit.map_or(pending as usize, |x| x.min(pending as usize));
+    let progress = ProgressBar::new(total.try_into()?).with_style(
         ProgressStyle::default_spinner()
             .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} items")?,
     );
 
-    let mut cursor = 0;
-    let mut exif_buff = vec![];
+    let root = PathBuf::from(&job.root);
+    let mut done = 0;
     let step = 10;
 
-    while cursor < files.len() {
-        for (i, file) in files.iter().skip(cursor).take(step).enumerate() {
-            if let Some(exif_file) = exif::get_exif_file_from_input(&"exiftool", file).metadata {
-                exif_buff.push(exif_file);
-            }
-            progress.set_message(format!("Processing item {}", cursor + i));
-            progress.set_position((cursor + i).try_into()?);
+    while done < total {
+        let paths: Vec<(String,)> = sqlx::query_as(
+            "select source_file from job_files where job_id = ?1 and state = 'pending' order by rowid limit ?2",
+        )
+        .bind(job.id)
+        .bind(step.min(total - done) as i64)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| eyre!("Failed to read the job {e}"))?;
+        if paths.is_empty() {
+            break;
         }
 
-        save_to_database(&exif_buff, &pool).await?;
-        exif_buff.clear();
-        cursor += step;
+        for (path,) in paths {
+            let file = InputFile::new(&FilePath::new(Path::new(&path)), &root);
+            let result = read_file(&file);
+            match result {
+                Ok(collected) => {
+                    save_to_database(&collected, pool).await?;
+                    set_file_state(job, &path, None, pool).await?;
+                }
+                Err(err) => {
+                    progress.println(format!("Failed '{}': {}", path, err));
+                    set_file_state(job, &path, Some(&err), pool).await?;
+                }
+            }
+            done += 1;
+            progress.set_message(format!("Processing item {}", done));
+            progress.set_position(done.try_into()?);
+        }
     }
 
     progress.finish_and_clear();
@@ -106,57 +415,163 @@ async fn collect_metadata(files: Vec<InputFile>, pool: &SqlitePool) -> Result<()
     Ok(())
 }
 
-// The full function to collect and print all the possible
-// duplicates that were found.
+async fn finish_job(job: &Job, pool: &SqlitePool) -> Result<()> {
+    let counts: Vec<(String, i64)> =
+        sqlx::query_as("select state, count(*) from job_files where job_id = ?1 group by state")
+            .bind(job.id)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| eyre!("Failed to read the job {e}"))?;
+    let count = |state: &str| counts.iter().find(|x| x.0 == state).map_or(0, |x| x.1);
+
+    if count("pending") == 0 {
+        sqlx::query("update jobs set finished_at = current_timestamp where id = ?1")
+            .bind(job.id)
+            .execute(pool)
+            .await
+            .map_err(|e| eyre!("Failed to save the job {e}"))?;
+    } else {
+        println!(
+            "{} files are still pending, run `collect --resume` to continue",
+            count("pending")
+        );
+    }
+    if count("failed") > 0 {
+        println!(
+            "{} files failed, run `collect --retry-failed` to try them again",
+            count("failed")
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollectMode {
+    // Start a new job for the directory.
+    New,
+    // Continue the last unfinished job.
+    Resume,
+    // Run the failed files of the last job again.
+    RetryFailed,
+}
+
 #[tokio::main]
 pub async fn exec(
-    path: &PathBuf,
-    db: &PathBuf,
+    path: &Path,
+    db: &Path,
+    options: &CollectOptions,
     force: bool,
-    file_cache: bool,
-    skip: Option<usize>,
+    mode: CollectMode,
     limit: Option<usize>,
     exec: bool,
 ) -> Result<(), Box<dyn Error>> {
     let time = std::time::Instant::now();
-    let path_string = utils::path_to_string(path);
-
-    println!("Collecting file paths in '{}'", path_string);
-    let mut files: Vec<InputFile> = if file_cache {
-        println!("Reading file cache.");
-        let buff = fs::read_to_string("file_list.txt")?;
-        let data: Vec<InputFile> = serde_json::from_str(&buff)?;
-        if let Some(skip) = skip {
-            data.into_iter().skip(skip).collect()
-        } else {
-            data
-        }
-    } else {
-        let files = get_files(path)?;
-        let data = serde_json::to_string(&files)?;
-        fs::write("file_list.txt", data.as_bytes())?;
-        files
-    };
-
-    if let Some(limit) = limit {
-        files = files.into_iter().take(limit).collect();
-    }
-
-    println!("File list data saved to file_list.txt");
 
     if !exec {
-        println!("{:?}", files.first().unwrap());
-        println!("next file: {:?}", files);
-        std::process::exit(0);
+        let path_string = utils::path_to_string(path);
+        println!("Collecting file paths in '{}'", path_string);
+        let files = get_files(path, options)?;
+        println!("Found {} files in '{}'", files.len(), path_string);
+        println!("DRY RUN:: run `collect --exec` to collect the metadata");
+        return Ok(());
     }
 
-    println!("Collecting file metadata in '{}'", path_string);
+    // The catalog keys the files by their path, the same folder has to give
+    // the same paths whatever the cwd or the spelling of the path.
+    let path = &path
+        .canonicalize()
+        .map_err(|e| eyre!("Failed to read '{}': {e}", path.display()))?;
     let pool = connect_database(db, force).await?;
+    let job = match mode {
+        CollectMode::New => create_job(path, options, &pool).await?,
+        CollectMode::Resume => last_job(&pool, path, mode).await?.ok_or_else(|| {
+            eyre!(
+                "There is no unfinished job in '{}' to resume",
+                path.display()
+            )
+        })?,
+        CollectMode::RetryFailed => {
+            let job = last_job(&pool, path, mode).await?.ok_or_else(|| {
+                eyre!("There are no failed files in '{}' to retry", path.display())
+            })?;
+            sqlx::query(
+                "update job_files set state = 'pending', error = null where job_id = ?1 and state = 'failed'",
+            )
+            .bind(job.id)
+            .execute(&pool)
+            .await?;
+            sqlx::query("update jobs set finished_at = null where id = ?1")
+                .bind(job.id)
+                .execute(&pool)
+                .await?;
+            job
+        }
+    };
 
-    collect_metadata(files, &pool).await?;
+    if job.scanned_at.is_none() {
+        println!("Collecting file paths in '{}'", job.root);
+        scan_job(&job, &pool).await?;
+    }
+
+    println!("Collecting file metadata in '{}'", job.root);
+    collect_metadata(&job, limit, &pool).await?;
+    finish_job(&job, &pool).await?;
 
     let duration = indicatif::HumanDuration(time.elapsed());
     println!("Saved in the {db:?}. Took {}", duration);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // `#[tokio::test]` looks for `::core`, our crate of the same name hides it.
+    #[test]
+    fn jobs_are_found_by_their_root() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(jobs_are_found_by_their_root_async());
+    }
+
+    async fn jobs_are_found_by_their_root_async() {
+        let pool = memory_catalog().await;
+        sqlx::query(
+            r#"
+            insert into jobs(id, root, options, finished_at) values
+                (1, '/a', '{}', current_timestamp),
+                (2, '/a', '{}', current_timestamp),
+                (3, '/b', '{}', null);
+            insert into job_files(job_id, source_file, state) values
+                (1, '/a/IMG_1.JPG', 'failed'),
+                (2, '/a/IMG_2.JPG', 'done');
+        "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let id = |job: Option<Job>| job.map(|x| x.id);
+
+        let a = Path::new("/a");
+        let b = Path::new("/b");
+        assert_eq!(
+            id(last_job(&pool, a, CollectMode::Resume).await.unwrap()),
+            None
+        );
+        assert_eq!(
+            id(last_job(&pool, b, CollectMode::Resume).await.unwrap()),
+            Some(3)
+        );
+        // The last job went fine, the failures are in the one before.
+        assert_eq!(
+            id(last_job(&pool, a, CollectMode::RetryFailed).await.unwrap()),
+            Some(1)
+        );
+        assert_eq!(
+            id(last_job(&pool, b, CollectMode::RetryFailed).await.unwrap()),
+            None
+        );
+    }
+}
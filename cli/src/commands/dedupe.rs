@@ -0,0 +1,218 @@
+use crate::commands::analyze::{find_duplicates, parse_db_date};
+use crate::commands::collect::connect_database;
+use core::config::{FileSystem, RunType};
+use core::dedupe::{self, Candidate, DedupeAction, KeepPolicy, UndoEntry, UndoLog};
+use core::dupes::Confidence;
+use core::utils;
+use eyre::{eyre, Result};
+use sqlx::{Row, SqlitePool};
+use std::path::{Path, PathBuf};
+
+// The quarantine keeps the paths relative to the folder all the duplicates share.
+fn common_root(sets: &[Vec<String>]) -> PathBuf {
+    let mut paths = sets.iter().flatten().map(Path::new);
+    let Some(first) = paths.next() else {
+        return PathBuf::new();
+    };
+    let mut root = first.parent().unwrap_or(first).to_path_buf();
+    for path in paths {
+        while !path.starts_with(&root) {
+            if !root.pop() {
+                break;
+            }
+        }
+    }
+    root
+}
+
+async fn get_candidate(pool: &SqlitePool, path: &str) -> Result<Candidate> {
+    let row = sqlx::query(
+        r#"
+        select size_bytes, file_type, file_type_extension, image_width, date_time_original, creation_date
+        from files where source_file = ?1 limit 1
+    "#,
+    )
+    .bind(path)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| eyre!("{e}"))?;
+
+    let taken = parse_db_date(row.try_get("date_time_original")?);
+    let metadata_fields = [
+        row.try_get::<Option<String>, _>("file_type")?.is_some(),
+        row.try_get::<Option<String>, _>("file_type_extension")?
+            .is_some(),
+        row.try_get::<Option<i64>, _>("image_width")?.is_some(),
+        taken.is_some(),
+        row.try_get::<Option<String>, _>("creation_date")?.is_some(),
+    ]
+    .into_iter()
+    .filter(|x| *x)
+    .count();
+    let modified = std::fs::metadata(path)
+        .and_then(|x| x.modified())
+        .ok()
+        .map(|x| chrono::DateTime::<chrono::Local>::from(x).naive_local());
+
+    Ok(Candidate {
+        path: PathBuf::from(path),
+        size: row.try_get::<Option<i64>, _>("size_bytes")?.unwrap_or(0) as u64,
+        taken,
+        modified,
+        metadata_fields,
+    })
+}
+
+// The moved files are gone from their path, the next runs should not find
+// them again. The undo brings them back.
+async fn mark_moved(pool: &SqlitePool, entry: &UndoEntry, deleted: bool) -> Result<()> {
+    let UndoEntry::Moved { from, .. } = entry else {
+        return Ok(());
+    };
+    let query = if deleted {
+        "update files set deleted_at = current_timestamp where source_file = ?1"
+    } else {
+        "update files set deleted_at = null where source_file = ?1"
+    };
+    sqlx::query(query)
+        .bind(utils::path_to_string(from))
+        .execute(pool)
+        .await
+        .map_err(|e| eyre!("{e}"))?;
+    Ok(())
+}
+
+fn print_entry(entry: &UndoEntry) {
+    match entry {
+        UndoEntry::Moved { from, to, .. } => {
+            println!("{} -> {}", from.display(), to.display())
+        }
+        UndoEntry::Linked { path, original } => {
+            println!("{} -> hardlink of {}", path.display(), original.display())
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn exec<F: FileSystem>(
+    fs: &F,
+    mode: &RunType,
+    db: &Path,
+    policy: &KeepPolicy,
+    action: DedupeAction,
+    undo_log: &Path,
+) -> Result<()> {
+    let pool = connect_database(db, false).await?;
+    // Only the exact copies are safe to get rid of without looking at them.
+    let sets = find_duplicates(&pool, None)
+        .await?
+        .into_iter()
+        .filter(|x| x.confidence == Confidence::Exact)
+        .map(|x| x.files.iter().map(|x| utils::path_to_string(x)).collect())
+        .collect::<Vec<Vec<String>>>();
+    // The quarantine root depends on the sets, so it is only known here.
+    let action = match action {
+        DedupeAction::Quarantine { dir, .. } => DedupeAction::Quarantine {
+            dir,
+            root: common_root(&sets),
+        },
+        action => action,
+    };
+
+    // Nothing really happens in the dry run, so there is nothing to undo.
+    let mut log = match mode {
+        RunType::Exec => Some(UndoLog::create(undo_log).map_err(|e| eyre!("{e}"))?),
+        RunType::Dry => None,
+    };
+    let mut log_error = None;
+    let mut removed = 0;
+
+    println!();
+    println!("-");
+    for set in sets.iter() {
+        let mut candidates = vec![];
+        for path in set {
+            candidates.push(get_candidate(&pool, path).await?);
+        }
+        let Some(resolution) = dedupe::resolve_set(fs, &candidates, policy, &action, &mut |x| {
+            if let Some(Err(err)) = log.as_mut().map(|log| log.append(x)) {
+                log_error = Some(err);
+            }
+        }) else {
+            continue;
+        };
+
+        println!("{} (KEEP)", resolution.kept.display());
+        for outcome in resolution.outcomes {
+            for entry in outcome.entries.iter() {
+                print_entry(entry);
+                if mode == &RunType::Exec {
+                    mark_moved(&pool, entry, true).await?;
+                }
+            }
+            if let Some(err) = outcome.error {
+                eprintln!("{} -> {}", outcome.path.display(), err);
+            } else {
+                removed += 1;
+            }
+        }
+        println!("-");
+
+        // Without the log we could not put the files back, so we stop here.
+        if let Some(err) = log_error.take() {
+            return Err(eyre!("{err}"));
+        }
+    }
+
+    println!();
+    println!("Resolved {} duplicates in {} sets", removed, sets.len());
+    if log.is_some() {
+        println!("Undo log: {}", undo_log.display());
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn undo<F: FileSystem>(
+    fs: &F,
+    mode: &RunType,
+    db: Option<&Path>,
+    undo_log: &Path,
+) -> Result<()> {
+    // Only the entries that could not be undone stay in the log.
+    let results = match mode {
+        RunType::Exec => dedupe::undo_log(fs, undo_log).map_err(|e| eyre!("{e}"))?,
+        RunType::Dry => dedupe::undo(fs, &UndoLog::read(undo_log).map_err(|e| eyre!("{e}"))?),
+    };
+    let pool = match db {
+        Some(db) if mode == &RunType::Exec => Some(connect_database(db, false).await?),
+        _ => None,
+    };
+    for (entry, result) in results {
+        if let (Some(pool), Ok(())) = (&pool, &result) {
+            mark_moved(pool, &entry, false).await?;
+        }
+        match (entry, result) {
+            (UndoEntry::Moved { from, to, .. }, Ok(())) => {
+                println!("{} -> {} (UNDO)", to.display(), from.display())
+            }
+            (UndoEntry::Linked { path, .. }, Ok(())) => {
+                println!("{} -> own copy (UNDO)", path.display())
+            }
+            (UndoEntry::Moved { to: path, .. } | UndoEntry::Linked { path, .. }, Err(err)) => {
+                eprintln!("ERROR: undoing {}: {}", path.display(), err)
+            }
+        }
+    }
+    if mode == &RunType::Exec && !undo_log.exists() {
+        println!("Everything was put back, removed {}", undo_log.display());
+    }
+    Ok(())
+}
+
+pub fn print_mode(mode: &RunType) {
+    if mode == &RunType::Dry {
+        println!("DRY RUN:: run `dedupe --exec` to commit")
+    }
+}
@@ -1,3 +1,6 @@
+pub mod analyze;
 pub mod collect;
+pub mod dedupe;
+pub mod query;
 pub mod rename;
-pub mod analyze;
+pub mod stats;
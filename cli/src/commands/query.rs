@@ -0,0 +1,334 @@
+use crate::commands::collect::connect_database;
+use crate::output::{self, OutputFormat};
+use chrono::NaiveDate;
+use clap::{Args, ValueEnum};
+use core::utils;
+use eyre::{eyre, Result};
+use sqlx::{FromRow, QueryBuilder, Sqlite};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum FileKind {
+    Image,
+    Video,
+    Other,
+}
+
+#[derive(Args, Debug)]
+pub struct QueryFilter {
+    /// Taken on this day or later (YYYY-MM-DD)
+    #[arg(long)]
+    from: Option<NaiveDate>,
+    /// Taken on this day or earlier (YYYY-MM-DD)
+    #[arg(long)]
+    to: Option<NaiveDate>,
+    /// The make or the model of the camera contains this
+    #[arg(long)]
+    camera: Option<String>,
+    #[arg(long)]
+    lens: Option<String>,
+    #[arg(long = "type", value_enum)]
+    kind: Option<FileKind>,
+    /// Only the files without a date
+    #[arg(long, conflicts_with_all = ["from", "to"])]
+    no_date: bool,
+    #[arg(long)]
+    has_gps: bool,
+    /// The file extension, without the dot (can be repeated)
+    #[arg(long)]
+    ext: Vec<String>,
+    /// Match the full path with a glob (`*` matches across the directories)
+    #[arg(long)]
+    path_glob: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct QueryRow {
+    source_file: String,
+    date: Option<String>,
+    file_type: Option<String>,
+    make: Option<String>,
+    model: Option<String>,
+    lens: Option<String>,
+    size_bytes: Option<i64>,
+    gps_latitude: Option<f64>,
+    gps_longitude: Option<f64>,
+}
+
+impl QueryRow {
+    fn camera(&self) -> Option<String> {
+        match (&self.make, &self.model) {
+            (Some(make), Some(model)) if model.starts_with(make.as_str()) => Some(model.clone()),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.clone().or(model.clone()),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.source_file,
+            "date": self.date,
+            "file_type": self.file_type,
+            "make": self.make,
+            "model": self.model,
+            "lens": self.lens,
+            "size": self.size_bytes,
+            "gps_latitude": self.gps_latitude,
+            "gps_longitude": self.gps_longitude,
+        })
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.date.clone().unwrap_or_default(),
+            self.file_type.clone().unwrap_or_default(),
+            self.camera().unwrap_or_default(),
+            self.lens.clone().unwrap_or_default(),
+            self.size_bytes.map(|x| x.to_string()).unwrap_or_default(),
+            self.source_file.clone(),
+        ]
+    }
+}
+
+const HEADERS: &[&str] = &["date", "type", "camera", "lens", "size", "path"];
+
+// The `%` and `_` of the user are no wildcards, the `like` gets an
+// `escape '\'` for them.
+fn like_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// The filters are ANDed, the repeated `--ext` values are ORed.
+fn build_query(filter: &QueryFilter) -> QueryBuilder<'_, Sqlite> {
+    let mut query = QueryBuilder::new(
+        r#"
+        select source_file, coalesce(date_time_original, creation_date) as date, file_type,
+            make, model, lens, size_bytes, gps_latitude, gps_longitude
+        from files where deleted_at is null
+    "#,
+    );
+
+    // The dates are saved as "YYYY-MM-DD HH:MM:SS", so they compare as text.
+    if let Some(from) = filter.from {
+        query
+            .push(" and coalesce(date_time_original, creation_date) >= ")
+            .push_bind(from.to_string());
+    }
+    if let Some(to) = filter.to.and_then(|x| x.succ_opt()) {
+        query
+            .push(" and coalesce(date_time_original, creation_date) < ")
+            .push_bind(to.to_string());
+    }
+    if filter.no_date {
+        query.push(" and date_time_original is null and creation_date is null");
+    }
+    if let Some(camera) = &filter.camera {
+        let pattern = format!("%{}%", like_escape(camera));
+        query
+            .push(" and (make like ")
+            .push_bind(pattern.clone())
+            .push(" escape '\\' or model like ")
+            .push_bind(pattern)
+            .push(" escape '\\')");
+    }
+    if let Some(lens) = &filter.lens {
+        query
+            .push(" and lens like ")
+            .push_bind(format!("%{}%", like_escape(lens)))
+            .push(" escape '\\'");
+    }
+    if filter.has_gps {
+        query.push(" and gps_latitude is not null and gps_longitude is not null");
+    }
+    if let Some(kind) = filter.kind {
+        let known = utils::IMGS.iter().chain(utils::VIDEOS.iter());
+        let (negate, exts) = match kind {
+            FileKind::Image => ("", utils::IMGS.iter().collect::<Vec<_>>()),
+            FileKind::Video => ("", utils::VIDEOS.iter().collect()),
+            FileKind::Other => ("not ", known.collect()),
+        };
+        query.push(format!(
+            " and coalesce(lower(file_type_extension), '') {}in (",
+            negate
+        ));
+        let mut list = query.separated(", ");
+        for ext in exts {
+            list.push_bind(ext.to_string());
+        }
+        query.push(")");
+    }
+    if !filter.ext.is_empty() {
+        query.push(" and (");
+        let mut list = query.separated(" or ");
+        for ext in filter.ext.iter() {
+            let ext = ext.trim_start_matches('.').to_lowercase();
+            list.push("lower(file_name) like ")
+                .push_bind_unseparated(format!("%.{}", like_escape(&ext)))
+                .push_unseparated(" escape '\\'");
+        }
+        query.push(")");
+    }
+    if let Some(glob) = &filter.path_glob {
+        query.push(" and source_file glob ").push_bind(glob.clone());
+    }
+
+    query.push(" order by date is null, date, source_file");
+    query
+}
+
+#[tokio::main]
+pub async fn exec(db: &Path, filter: &QueryFilter, format: OutputFormat, null: bool) -> Result<()> {
+    let pool = connect_database(db, false).await?;
+    let rows: Vec<QueryRow> = build_query(filter)
+        .build_query_as()
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| eyre!("{e}"))?;
+
+    let mut stdout = std::io::stdout().lock();
+    match format {
+        OutputFormat::Table => {
+            let rows = rows.iter().map(|x| x.fields()).collect::<Vec<_>>();
+            output::write_table(&mut stdout, HEADERS, &rows)?;
+        }
+        OutputFormat::Json => {
+            let rows = rows.iter().map(|x| x.to_json()).collect::<Vec<_>>();
+            writeln!(stdout, "{}", serde_json::to_string_pretty(&rows)?)?;
+        }
+        OutputFormat::Ndjson => {
+            for row in rows.iter() {
+                writeln!(stdout, "{}", row.to_json())?;
+            }
+        }
+        OutputFormat::Csv => {
+            let headers = HEADERS.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            output::write_csv_row(&mut stdout, &headers)?;
+            for row in rows.iter() {
+                output::write_csv_row(&mut stdout, &row.fields())?;
+            }
+        }
+        // For `xargs -0`, the names can have new lines in them.
+        OutputFormat::Paths => {
+            let end = if null { "\0" } else { "\n" };
+            for row in rows.iter() {
+                write!(stdout, "{}{}", row.source_file, end)?;
+            }
+        }
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::commands::collect::memory_catalog;
+    use sqlx::SqlitePool;
+
+    fn filter() -> QueryFilter {
+        QueryFilter {
+            from: None,
+            to: None,
+            camera: None,
+            lens: None,
+            kind: None,
+            no_date: false,
+            has_gps: false,
+            ext: vec![],
+            path_glob: None,
+        }
+    }
+
+    async fn paths(pool: &SqlitePool, filter: &QueryFilter) -> Vec<String> {
+        build_query(filter)
+            .build_query_as::<QueryRow>()
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.source_file)
+            .collect()
+    }
+
+    // `#[tokio::test]` looks for `::core`, our crate of the same name hides it.
+    #[test]
+    fn filters_of_the_query() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(filters_of_the_query_async());
+    }
+
+    async fn filters_of_the_query_async() {
+        let pool = memory_catalog().await;
+        sqlx::query(
+            r#"
+            insert into files(source_file, file_name, file_size, file_type_extension, date_time_original, make, model, gps_latitude, gps_longitude)
+            values
+                ('a/IMG_1.jpeg', 'IMG_1.jpeg', '', 'jpg', '2024-08-16 10:00:00', 'Apple', 'iPhone 12', 52.3, 4.9),
+                ('a/IMG_2.jp_g', 'IMG_2.jp_g', '', null, '2024-08-17 23:59:59', 'FUJIFILM', 'X-T4', null, null),
+                ('b/MOV_1.MOV', 'MOV_1.MOV', '', 'mov', null, null, null, null, null),
+                ('b/notes.pdf', 'notes.pdf', '', 'pdf', '2024-08-18 00:00:00', null, null, null, null)
+        "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // No filters, the files without a date come last.
+        assert_eq!(
+            paths(&pool, &filter()).await,
+            vec!["a/IMG_1.jpeg", "a/IMG_2.jp_g", "b/notes.pdf", "b/MOV_1.MOV"]
+        );
+        let day = |x| NaiveDate::parse_from_str(x, "%Y-%m-%d").ok();
+        // The `to` day is taken whole.
+        let dates = QueryFilter {
+            from: day("2024-08-17"),
+            to: day("2024-08-17"),
+            ..filter()
+        };
+        assert_eq!(paths(&pool, &dates).await, vec!["a/IMG_2.jp_g"]);
+        let no_date = QueryFilter {
+            no_date: true,
+            ..filter()
+        };
+        assert_eq!(paths(&pool, &no_date).await, vec!["b/MOV_1.MOV"]);
+        let camera = QueryFilter {
+            camera: Some("fuji".to_string()),
+            ..filter()
+        };
+        assert_eq!(paths(&pool, &camera).await, vec!["a/IMG_2.jp_g"]);
+        // The `_` is no wildcard, `jpeg` does not match.
+        let ext = QueryFilter {
+            ext: vec![".jp_g".to_string()],
+            ..filter()
+        };
+        assert_eq!(paths(&pool, &ext).await, vec!["a/IMG_2.jp_g"]);
+        let exts = QueryFilter {
+            ext: vec!["JPEG".to_string(), "mov".to_string()],
+            ..filter()
+        };
+        assert_eq!(
+            paths(&pool, &exts).await,
+            vec!["a/IMG_1.jpeg", "b/MOV_1.MOV"]
+        );
+        let other = QueryFilter {
+            kind: Some(FileKind::Other),
+            ..filter()
+        };
+        assert_eq!(
+            paths(&pool, &other).await,
+            vec!["a/IMG_2.jp_g", "b/notes.pdf"]
+        );
+        let gps = QueryFilter {
+            has_gps: true,
+            path_glob: Some("a/*".to_string()),
+            ..filter()
+        };
+        assert_eq!(paths(&pool, &gps).await, vec!["a/IMG_1.jpeg"]);
+    }
+}
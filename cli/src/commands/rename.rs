@@ -1,101 +1,1273 @@
-use super::super::config::RunType;
-use core::exif::{self, ExifNotifier, FileNameGroup};
-use core::file::{FilePath, InputFile};
+use super::super::config::{RenameOptions, RunType};
+use crate::commands::collect::connect_database;
+use crate::output;
+use clap::ValueEnum;
+use core::batch::{self, BatchError, BatchPlanner, BatchRename, MoveOutcome, Recovery};
+use core::busy::{self, BusyCheck};
+use core::case::CaseFolding;
+use core::events::{self, EventOptions};
+use core::exif::{self, ExifNotifier, FileNameGroup, NameState, NameTemplate};
+use core::file::FilePath;
+use core::name::{NamePattern, Sequence};
+use core::pipeline::{Exiftool, MetadataPipeline};
 use core::utils;
-use std::path::Path;
+use indicatif::{ProgressBar, ProgressStyle};
+use sqlx::SqlitePool;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-struct ConsoleNotifier;
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum RenameOutput {
+    // The `a -> b` lines for the people.
+    Text,
+    Json,
+    Ndjson,
+    Csv,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum RenameSort {
+    // By the directory, then the name. The groups stream in as they are read.
+    Name,
+    // By the date the files get named after. All the metadata is read first.
+    Date,
+}
 
-impl ConsoleNotifier {
-    fn new() -> Self {
-        Self {}
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FileStatus {
+    Renamed,
+    // A dry run, nothing was renamed.
+    Planned,
+    Failed,
+    RolledBack,
+    RollbackFailed,
+    // An other file of the group failed first.
+    Skipped,
+    // The group has no date to name it after.
+    NoDate,
+    Uncertain,
+    Unsupported,
+    // The files already have their names.
+    Unchanged,
+    // Named with an older template, left alone without `--migrate`.
+    OldTemplate,
+    // In use by an other app, left alone.
+    Busy,
+}
+
+impl FileStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileStatus::Renamed => "renamed",
+            FileStatus::Planned => "planned",
+            FileStatus::Failed => "failed",
+            FileStatus::RolledBack => "rolled_back",
+            FileStatus::RollbackFailed => "rollback_failed",
+            FileStatus::Skipped => "skipped",
+            FileStatus::NoDate => "no_date",
+            FileStatus::Uncertain => "uncertain",
+            FileStatus::Unsupported => "unsupported",
+            FileStatus::Unchanged => "unchanged",
+            FileStatus::OldTemplate => "old_template",
+            FileStatus::Busy => "busy",
+        }
     }
 }
 
-impl ExifNotifier for ConsoleNotifier {
-    fn rename_success(&self, prev: &FilePath, next: &Path) -> () {
-        println!("{} -> {}", prev.as_str(), utils::path_to_string(next));
+#[derive(Debug, Clone)]
+struct FileRecord {
+    // The paths as they are, two names can have the same lossy string.
+    old_path: PathBuf,
+    new_path: Option<PathBuf>,
+    status: FileStatus,
+    error: Option<String>,
+    rollback: bool,
+}
+
+#[derive(Debug)]
+struct GroupRecord {
+    key: String,
+    kind: &'static str,
+    date_source: Option<&'static str>,
+    files: Vec<FileRecord>,
+}
+
+impl GroupRecord {
+    // The worst status of the files. A group with some of the files already
+    // named is renamed.
+    fn status(&self) -> FileStatus {
+        let has = |status| self.files.iter().any(|x| x.status == status);
+        [
+            FileStatus::RollbackFailed,
+            FileStatus::Failed,
+            FileStatus::RolledBack,
+        ]
+        .into_iter()
+        .find(|x| has(*x))
+        .map(|x| match x {
+            FileStatus::RolledBack => FileStatus::Failed,
+            x => x,
+        })
+        .or(self
+            .files
+            .iter()
+            .find(|x| x.status != FileStatus::Unchanged)
+            .or(self.files.first())
+            .map(|x| x.status))
+        .unwrap_or(FileStatus::NoDate)
     }
-    fn rename_error(&self, prev: &FilePath, err: String) -> () {
-        eprintln!("{} -> {}", prev.as_str(), err);
+
+    fn file_json(&self, file: &FileRecord) -> serde_json::Value {
+        serde_json::json!({
+            "type": "file",
+            "group": self.key,
+            "kind": self.kind,
+            "old_path": utils::path_to_string(&file.old_path),
+            "new_path": file.new_path.as_deref().map(utils::path_to_string),
+            "status": file.status.as_str(),
+            "date_source": self.date_source,
+            "error": file.error,
+            "rollback": file.rollback,
+        })
     }
 
-    fn rollback_success(&self, next: &Path, prev: &FilePath) -> () {
-        println!(
-            "{} -> {} (ROLLBACK)",
-            utils::path_to_string(next),
-            prev.as_str(),
+    fn to_json(&self, nested: bool) -> serde_json::Value {
+        let mut value = serde_json::json!({
+            "type": "group",
+            "group": self.key,
+            "kind": self.kind,
+            "status": self.status().as_str(),
+            "date_source": self.date_source,
+        });
+        if nested {
+            value["files"] = self.files.iter().map(|x| self.file_json(x)).collect();
+        }
+        value
+    }
+
+    // A row per file, in the order of `CSV_HEADERS`.
+    fn csv_rows(&self) -> Vec<Vec<String>> {
+        self.files
+            .iter()
+            .map(|x| {
+                vec![
+                    self.key.clone(),
+                    self.kind.to_string(),
+                    utils::path_to_string(&x.old_path),
+                    x.new_path
+                        .as_deref()
+                        .map(utils::path_to_string)
+                        .unwrap_or_default(),
+                    x.status.as_str().to_string(),
+                    self.date_source.unwrap_or_default().to_string(),
+                    x.error.clone().unwrap_or_default(),
+                    x.rollback.to_string(),
+                ]
+            })
+            .collect()
+    }
+}
+
+const CSV_HEADERS: &[&str] = &[
+    "group",
+    "kind",
+    "old_path",
+    "new_path",
+    "status",
+    "date_source",
+    "error",
+    "rollback",
+];
+
+// Keeps what happened to every file of the group. With the text output it
+// also prints the lines as they happen.
+struct RecordNotifier {
+    output: RenameOutput,
+    dry: bool,
+    files: RefCell<Vec<FileRecord>>,
+}
+
+impl RecordNotifier {
+    fn new(output: RenameOutput, dry: bool) -> Self {
+        Self {
+            output,
+            dry,
+            files: RefCell::new(vec![]),
+        }
+    }
+
+    fn text(&self) -> bool {
+        self.output == RenameOutput::Text
+    }
+
+    fn push(&self, src: &FilePath, status: FileStatus) {
+        self.files.borrow_mut().push(FileRecord {
+            old_path: src.value().clone(),
+            new_path: None,
+            status,
+            error: None,
+            rollback: false,
+        });
+    }
+
+    fn old_template(&self, src: &FilePath, template: NameTemplate) {
+        if self.text() {
+            println!(
+                "{} -> Old name template ({}), run with --migrate to rename",
+                src,
+                template.pattern()
+            );
+        }
+        self.push(src, FileStatus::OldTemplate);
+    }
+}
+
+impl ExifNotifier for RecordNotifier {
+    fn rename_success(&self, prev: &FilePath, next: &Path) {
+        if self.text() {
+            println!("{} -> {}", prev, utils::path_to_string(next));
+        }
+        self.files.borrow_mut().push(FileRecord {
+            old_path: prev.value().clone(),
+            new_path: Some(next.to_path_buf()),
+            status: if self.dry {
+                FileStatus::Planned
+            } else {
+                FileStatus::Renamed
+            },
+            error: None,
+            rollback: false,
+        });
+    }
+
+    fn rename_error(&self, prev: &FilePath, err: String) {
+        if self.text() {
+            eprintln!("{} -> {}", prev, err);
+        }
+        self.files.borrow_mut().push(FileRecord {
+            old_path: prev.value().clone(),
+            new_path: None,
+            status: FileStatus::Failed,
+            error: Some(err),
+            rollback: false,
+        });
+    }
+
+    fn rollback_success(&self, next: &Path, prev: &FilePath) {
+        if self.text() {
+            println!("{} -> {} (ROLLBACK)", utils::path_to_string(next), prev,);
+        }
+        if let Some(file) = self
+            .files
+            .borrow_mut()
+            .iter_mut()
+            .find(|x| x.old_path == *prev.value())
+        {
+            file.status = FileStatus::RolledBack;
+            file.rollback = true;
+        }
+    }
+
+    fn rollback_error(&self, next: &Path, err: String) {
+        if self.text() {
+            eprintln!(
+                "ERROR: rolling back the {}: {}",
+                utils::path_to_string(next),
+                err
+            )
+        }
+        if let Some(file) = self
+            .files
+            .borrow_mut()
+            .iter_mut()
+            .find(|x| x.new_path.as_deref() == Some(next))
+        {
+            file.status = FileStatus::RollbackFailed;
+            file.error = Some(err);
+            file.rollback = true;
+        }
+    }
+
+    fn uncertain(&self, src: &FilePath) {
+        if self.text() {
+            println!("{} -> Uncertain Primary file", src);
+        }
+        self.push(src, FileStatus::Uncertain);
+    }
+
+    fn unsupported(&self, src: &FilePath) {
+        if self.text() {
+            println!("{} -> Unsupported file", src);
+        }
+        self.push(src, FileStatus::Unsupported);
+    }
+
+    fn unchanged(&self, src: &FilePath) {
+        self.push(src, FileStatus::Unchanged);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RenameSummary {
+    pub groups: usize,
+    pub failed: usize,
+    pub rollback_failed: usize,
+    pub unchanged: usize,
+    pub old_template: usize,
+    pub busy: usize,
+    // Dated by their names, their metadata was not read.
+    pub from_name: usize,
+    // The directories (or ignore files) the scan could not read, their
+    // files are not in the groups.
+    pub scan_errors: usize,
+    // The files renamed, from their old path to the new one.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+impl RenameSummary {
+    // 0 when everything went fine, 2 when some groups failed and were put
+    // back (or some directories were not read), 3 when a rollback failed
+    // and some files are left half renamed.
+    pub fn exit_code(&self) -> i32 {
+        if self.rollback_failed > 0 {
+            3
+        } else if self.failed > 0 || self.scan_errors > 0 {
+            2
+        } else {
+            0
+        }
+    }
+}
+
+pub struct RunOptions {
+    pub jobs: Option<usize>,
+    pub output: RenameOutput,
+    pub sort: RenameSort,
+    // The intent log when all the groups are renamed in one batch.
+    pub batch_log: Option<PathBuf>,
+    // Check that the files are not in use, and leave alone the ones
+    // changed more recently than this.
+    pub settle: Option<Duration>,
+    // The names from `--name` instead of the date ones, `seq` numbers them.
+    pub name: Option<NamePattern>,
+    pub seq: Sequence,
+    pub events: Option<EventRun>,
+    // Read the metadata of the groups already named with the date too.
+    pub recheck: bool,
+}
+
+// Moves the groups into the folders of their events, under `root`.
+pub struct EventRun {
+    pub options: EventOptions,
+    pub root: PathBuf,
+    // The labels of the events, by the day they start on.
+    pub labels: HashMap<chrono::NaiveDate, String>,
+}
+
+// Next to the files, the next run in the folder picks it up.
+pub const BATCH_LOG: &str = ".eximd-rename.jsonl";
+
+pub fn process_files<F: core::config::FileSystem>(
+    fs: &F,
+    groups: impl Iterator<Item = FileNameGroup> + Send,
+    options: &RenameOptions,
+    mode: &RunType,
+    run: &RunOptions,
+) -> std::io::Result<RenameSummary> {
+    let output = run.output;
+    let source = Exiftool::new("exiftool");
+    // The renames are only planned while the groups come in. The name of a
+    // group can be the current name of an other one, so they are done at
+    // the end, in an order that works for all of them.
+    let planner = BatchPlanner::new();
+    let nf = RecordNotifier::new(output, true);
+    let progress = ProgressBar::new(0).with_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} groups")
+            .expect("valid progress template"),
+    );
+    let mut pipeline = MetadataPipeline::new();
+    if let Some(jobs) = run.jobs {
+        pipeline = pipeline.workers(jobs);
+    }
+
+    let mut summary = RenameSummary::default();
+    let mut records = vec![];
+    let mut stdout = std::io::stdout();
+    let mut result = Ok(());
+    if output == RenameOutput::Csv {
+        let headers = CSV_HEADERS
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        result = output::write_csv_row(&mut stdout, &headers);
+    }
+
+    if nf.text() {
+        println!();
+        println!("-");
+    }
+    let mut emit = |record: GroupRecord| {
+        summary.groups += 1;
+        match record.status() {
+            FileStatus::RollbackFailed => summary.rollback_failed += 1,
+            FileStatus::Failed => summary.failed += 1,
+            FileStatus::Unchanged => summary.unchanged += 1,
+            FileStatus::OldTemplate => summary.old_template += 1,
+            FileStatus::Busy => summary.busy += 1,
+            _ => {}
+        }
+        if record.date_source == Some("FileName") {
+            summary.from_name += 1;
+        }
+        for file in &record.files {
+            if let (FileStatus::Renamed, Some(next)) = (file.status, &file.new_path) {
+                summary.renamed.push((file.old_path.clone(), next.clone()));
+            }
+        }
+        if result.is_err() {
+            return;
+        }
+        result = match output {
+            RenameOutput::Text => Ok(()),
+            RenameOutput::Json => {
+                records.push(record.to_json(true));
+                Ok(())
+            }
+            RenameOutput::Ndjson => progress.suspend(|| {
+                record
+                    .files
+                    .iter()
+                    .try_for_each(|x| writeln!(stdout, "{}", record.file_json(x)))
+                    .and_then(|_| writeln!(stdout, "{}", record.to_json(false)))
+            }),
+            RenameOutput::Csv => progress.suspend(|| {
+                record
+                    .csv_rows()
+                    .iter()
+                    .try_for_each(|x| output::write_csv_row(&mut stdout, x))
+            }),
+        };
+    };
+    let mut planned = vec![];
+    let mut handle =
+        |group: FileNameGroup, number: Option<u64>, dir: Option<PathBuf>| {
+            let next_stem = next_stem(&group, run, number);
+            planned.push(progress.suspend(|| {
+                process_group(&planner, &nf, &group, dir.as_deref(), next_stem, options)
+            }))
+        };
+
+    // The metadata is fetched in parallel but the groups come back in order,
+    // so the renames and the output stay sequential. The numbers of `{seq}`
+    // go by the date, they are known once all the groups are in. So are
+    // the events.
+    let numbered = run.name.as_ref().is_some_and(|x| x.has_seq());
+    let buffer = numbered || run.events.is_some();
+    let mut buffered = vec![];
+    // The groups named with the current template were done before, their
+    // date is in the name and exiftool is not run for them. The `--name`
+    // and the distance of the events need more than the date.
+    let from_name = !run.recheck
+        && run.name.is_none()
+        && run
+            .events
+            .as_ref()
+            .is_none_or(|x| x.options.distance_km.is_none());
+    let groups = groups.map(move |mut group| {
+        if from_name {
+            group.date_from_name();
+        }
+        group
+    });
+    pipeline.run_stream(
+        &source,
+        groups,
+        |x| {
+            // The total grows while the directories are being walked.
+            progress.set_length(x.total as u64);
+            progress.set_position(x.done as u64);
+        },
+        |_, group| match run.sort {
+            RenameSort::Name if !buffer => handle(group, None, None),
+            _ => buffered.push(group),
+        },
+    );
+    if run.sort == RenameSort::Date {
+        exif::sort_groups_by_date(&mut buffered);
+    }
+    let numbers = if numbered {
+        run.seq.number_groups(&buffered)
+    } else {
+        vec![None; buffered.len()]
+    };
+    let dirs = match &run.events {
+        Some(events) => progress.suspend(|| event_dirs(&buffered, events, nf.text())),
+        None => vec![None; buffered.len()],
+    };
+    for ((group, number), dir) in buffered.into_iter().zip(numbers).zip(dirs) {
+        handle(group, number, dir);
+    }
+    progress.finish_and_clear();
+
+    for record in run_plan(fs, &planner, planned, run, mode, &nf) {
+        emit(record);
+    }
+    result?;
+
+    if output == RenameOutput::Json {
+        writeln!(stdout, "{}", serde_json::to_string_pretty(&records)?)?;
+    }
+    if nf.text() {
+        if summary.unchanged > 0 {
+            println!("{} groups already have their names", summary.unchanged);
+        }
+        if summary.old_template > 0 {
+            println!(
+                "{} groups are named with an old template, run with --migrate to rename them",
+                summary.old_template
+            );
+        }
+        if summary.busy > 0 {
+            println!(
+                "{} groups are in use and were left alone, run again later",
+                summary.busy
+            );
+        }
+        if summary.from_name > 0 {
+            println!(
+                "{} groups were dated by their names, run with --recheck to read their metadata",
+                summary.from_name
+            );
+        }
+        println!();
+    }
+
+    Ok(summary)
+}
+
+// The catalog keys the files by their canonical path. The old path is gone,
+// its folder is still there.
+fn catalog_path(path: &Path) -> String {
+    let path = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => dir
+            .canonicalize()
+            .map(|x| x.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    };
+    utils::path_to_string(&path)
+}
+
+// Keeps the rename history in the catalog and moves the files of the
+// catalog to their new paths, their metadata and hashes go with them.
+#[tokio::main]
+pub async fn record_renames(db: &Path, renamed: &[(PathBuf, PathBuf)]) -> eyre::Result<()> {
+    let pool = connect_database(db, false).await?;
+    let renamed = renamed
+        .iter()
+        .map(|(prev, next)| (catalog_path(prev), catalog_path(next)))
+        .collect::<Vec<_>>();
+    save_renames(&pool, &renamed).await
+}
+
+async fn save_renames(pool: &SqlitePool, renamed: &[(String, String)]) -> eyre::Result<()> {
+    let mut tx = pool.begin().await?;
+    let mut moved = vec![];
+    for (prev, next) in renamed {
+        let id: Option<i64> = sqlx::query_scalar("select id from files where source_file = ?1")
+            .bind(prev)
+            .fetch_optional(&mut *tx)
+            .await?;
+        sqlx::query("insert into renames(file_id, prev_path, next_path) values (?1, ?2, ?3)")
+            .bind(id)
+            .bind(prev)
+            .bind(next)
+            .execute(&mut *tx)
+            .await?;
+        if let Some(id) = id {
+            moved.push((id, next));
+        }
+    }
+    // The files can swap their names, they are all moved out of the way
+    // first. A file left at a new path is an old one the collect didn't
+    // see go.
+    for (id, _) in &moved {
+        sqlx::query("update files set source_file = 'renaming:' || id where id = ?1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    for (id, next) in &moved {
+        sqlx::query("delete from files where source_file = ?1")
+            .bind(next)
+            .execute(&mut *tx)
+            .await?;
+        let name = Path::new(next).file_name().unwrap_or_default();
+        sqlx::query("update files set source_file = ?1, file_name = ?2 where id = ?3")
+            .bind(next)
+            .bind(name.to_string_lossy())
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Renames the planned groups. The groups with a taken name are left out.
+// With a batch log the rest is renamed, or reverted, all together,
+// otherwise every group is renamed or rolled back on its own.
+fn run_plan<F: core::config::FileSystem>(
+    fs: &F,
+    planner: &BatchPlanner,
+    mut records: Vec<GroupRecord>,
+    run: &RunOptions,
+    mode: &RunType,
+    nf: &RecordNotifier,
+) -> Vec<GroupRecord> {
+    let id = chrono::Utc::now().timestamp_millis().to_string();
+    // The target directories are probed for a case insensitive file system,
+    // a temporary file is created and removed. A dry run writes nothing, it
+    // only looks at the names already there.
+    let planned = planner.moves();
+    let dirs = planned.iter().filter_map(|x| x.1.parent());
+    let case = match mode {
+        RunType::Dry => CaseFolding::inspect(dirs),
+        _ => CaseFolding::probe(dirs),
+    };
+    let group_of = records
+        .iter()
+        .enumerate()
+        .flat_map(|(i, x)| x.files.iter().map(move |x| (&x.old_path, i)))
+        .collect::<HashMap<_, _>>();
+    // Every planned move has its record, a move without one is not done.
+    let (planned, groups): (Vec<_>, Vec<_>) = planned
+        .into_iter()
+        .filter_map(|x| group_of.get(&x.0).copied().map(|group| (x, group)))
+        .unzip();
+    let found = batch::conflicts_of_the_groups(fs, &planned, &groups, &HashSet::new(), &case);
+    let left_out = leave_out(&mut records, &planned, &groups, found, nf);
+    if mode == &RunType::Dry {
+        return records;
+    }
+    let (moves, groups) = without_groups(planned, groups, &left_out);
+    // The folders of the events are made right before the first file goes
+    // in, a group that can't have its folder fails.
+    let Some(log) = &run.batch_log else {
+        let outcomes = rename_in_order(fs, &id, &moves, &groups, &case, run.settle, nf);
+        set_outcomes(&mut records, &outcomes, nf);
+        return records;
+    };
+
+    // The locks can't be held for the whole batch (there can be more files
+    // than open files allowed). They are taken and let go right away, so
+    // this only finds the files that are locked, open or changed now. An
+    // app that opens a file after the check is not kept from it.
+    let mut busy = HashMap::new();
+    if let Some(settle) = run.settle {
+        let check = BusyCheck::new(
+            settle,
+            &moves.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
         );
+        for group in groups.iter().collect::<HashSet<_>>() {
+            if let Err((path, reason)) = check.lock_all(&group_sources(&moves, &groups, *group)) {
+                busy.insert(*group, format!("{}: {}", path.display(), reason));
+            }
+        }
     }
+    let outcomes = moves
+        .iter()
+        .zip(groups.iter())
+        .filter_map(|(x, group)| busy.get(group).map(|err| (x, err)))
+        .map(|(x, err)| (x.0.clone(), MoveOutcome::Busy(err.clone())))
+        .collect::<HashMap<_, _>>();
+    set_outcomes(&mut records, &outcomes, nf);
+    // The files of the busy groups stay, an other group can't have them.
+    let busy = busy.into_keys().collect::<HashSet<_>>();
+    let found = batch::conflicts_of_the_groups(fs, &moves, &groups, &busy, &case);
+    let mut left_out = leave_out(&mut records, &moves, &groups, found, nf);
+    left_out.extend(busy);
+    let (moves, groups) = without_groups(moves, groups, &left_out);
+    let found = moves
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| {
+            batch::make_dirs(fs, std::slice::from_ref(&x.1))
+                .err()
+                .map(|err| (i, err))
+        })
+        .collect();
+    let left_out = leave_out(&mut records, &moves, &groups, found, nf);
+    let (moves, _) = without_groups(moves, groups, &left_out);
 
-    fn rollback_error(&self, next: &Path, err: String) -> () {
-        eprintln!(
-            "ERROR: rolling back the {}: {}",
-            utils::path_to_string(next),
-            err
-        )
+    let result = BatchRename::new(&id, &moves).run(fs, log);
+    if let Err(err) = &result {
+        if nf.text() {
+            eprintln!("ERROR: the batch rename: {}", err);
+        }
+    }
+    for file in records
+        .iter_mut()
+        .flat_map(|x| x.files.iter_mut())
+        .filter(|x| x.status == FileStatus::Planned)
+    {
+        match &result {
+            Ok(_) => file.status = FileStatus::Renamed,
+            Err(err) => {
+                file.status = match err {
+                    BatchError::Reverted(_) => FileStatus::RolledBack,
+                    BatchError::Stuck(_) => FileStatus::RollbackFailed,
+                };
+                file.error = Some(err.to_string());
+                file.rollback = true;
+            }
+        }
     }
 
-    fn uncertain(&self, src: &FilePath) -> () {
-        println!("{} -> Uncertain Primary file", src.as_str());
+    records
+}
+
+// Marks the moves with a conflict as failed and the rest of their groups as
+// skipped. Returns the groups left out.
+fn leave_out(
+    records: &mut [GroupRecord],
+    moves: &[(PathBuf, PathBuf)],
+    groups: &[usize],
+    found: Vec<(usize, String)>,
+    nf: &RecordNotifier,
+) -> HashSet<usize> {
+    let mut left_out = HashSet::new();
+    for (i, err) in found {
+        if let Some(file) = records[groups[i]]
+            .files
+            .iter_mut()
+            .find(|x| x.old_path == moves[i].0)
+        {
+            if nf.text() {
+                eprintln!("{} -> {}", file.old_path.display(), err);
+            }
+            file.status = FileStatus::Failed;
+            file.error = Some(err);
+        }
+        left_out.insert(groups[i]);
     }
+    for file in records
+        .iter_mut()
+        .enumerate()
+        .filter(|x| left_out.contains(&x.0))
+        .flat_map(|x| x.1.files.iter_mut())
+        .filter(|x| x.status == FileStatus::Planned)
+    {
+        file.status = FileStatus::Skipped;
+    }
+    left_out
+}
 
-    fn unsupported(&self, src: &FilePath) -> () {
-        println!("{} -> Unsupported file", src.as_str());
+fn without_groups(
+    moves: Vec<(PathBuf, PathBuf)>,
+    groups: Vec<usize>,
+    left_out: &HashSet<usize>,
+) -> (Vec<(PathBuf, PathBuf)>, Vec<usize>) {
+    moves
+        .into_iter()
+        .zip(groups)
+        .filter(|x| !left_out.contains(&x.1))
+        .unzip()
+}
+
+fn group_sources(moves: &[(PathBuf, PathBuf)], groups: &[usize], group: usize) -> Vec<PathBuf> {
+    moves
+        .iter()
+        .zip(groups)
+        .filter(|x| *x.1 == group)
+        .map(|x| x.0 .0.clone())
+        .collect()
+}
+
+// With a `settle` time the files of a group are locked while it is renamed
+// and the busy groups are tried again after the others. The files that were
+// changed a moment ago get the time to settle first.
+fn rename_in_order<F: core::config::FileSystem>(
+    fs: &F,
+    id: &str,
+    moves: &[(PathBuf, PathBuf)],
+    groups: &[usize],
+    case: &CaseFolding,
+    settle: Option<Duration>,
+    nf: &RecordNotifier,
+) -> HashMap<PathBuf, MoveOutcome> {
+    let Some(settle) = settle else {
+        return batch::rename_in_order(fs, id, moves, groups, case, |_| Ok(()))
+            .into_iter()
+            .zip(moves)
+            .map(|(outcome, (src, _))| (src.clone(), outcome))
+            .collect();
+    };
+
+    let mut outcomes = HashMap::new();
+    let mut moves = moves.to_vec();
+    let mut groups = groups.to_vec();
+    for deferred in [false, true] {
+        if deferred {
+            let wait = moves
+                .iter()
+                .filter_map(|x| busy::modified_ago(&x.0))
+                .filter_map(|x| settle.checked_sub(x))
+                .max();
+            if let Some(wait) = wait {
+                if nf.text() {
+                    println!(
+                        "Waiting {}s for the busy groups to settle",
+                        wait.as_secs() + 1
+                    );
+                }
+                std::thread::sleep(wait + Duration::from_secs(1));
+            }
+        }
+        let check = BusyCheck::new(
+            settle,
+            &moves.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
+        );
+        let result = batch::rename_in_order(fs, id, &moves, &groups, case, |group| {
+            check
+                .lock_all(&group_sources(&moves, &groups, group))
+                .map_err(|(path, reason)| format!("{}: {}", path.display(), reason))
+        });
+
+        let mut busy = vec![];
+        for ((outcome, x), group) in result.into_iter().zip(moves.iter()).zip(groups.iter()) {
+            if matches!(outcome, MoveOutcome::Busy(_)) {
+                busy.push((x.clone(), *group));
+            }
+            outcomes.insert(x.0.clone(), outcome);
+        }
+        if busy.is_empty() {
+            break;
+        }
+        (moves, groups) = busy.into_iter().unzip();
     }
+    outcomes
 }
 
-pub fn process_files<F: core::config::FileSystem>(fs: &F, files: &[InputFile]) {
-    let cmd_path = "exiftool";
-    let nf = ConsoleNotifier::new();
-    println!("");
-    println!("-");
-    for mut group in exif::group_same_name_files(files) {
-        match &mut group {
-            FileNameGroup::Image { image, .. } => {
-                image.fetch_and_set_metadata(&cmd_path);
-                if let Some(next_stem) = image.next_file_stem_from_exif() {
-                    exif::rename_with_rollback(fs, &nf, group.merge_into_rename_refs(), &next_stem);
+fn set_outcomes(
+    records: &mut [GroupRecord],
+    outcomes: &HashMap<PathBuf, MoveOutcome>,
+    nf: &RecordNotifier,
+) {
+    for file in records.iter_mut().flat_map(|x| x.files.iter_mut()) {
+        let Some(outcome) = outcomes.get(&file.old_path) else {
+            continue;
+        };
+        let prev = file.old_path.display();
+        let next = file.new_path.as_deref().unwrap_or(Path::new("")).display();
+        match outcome {
+            MoveOutcome::Renamed => file.status = FileStatus::Renamed,
+            MoveOutcome::Skipped => file.status = FileStatus::Skipped,
+            MoveOutcome::Failed(err) => {
+                if nf.text() {
+                    eprintln!("{} -> {}", prev, err);
                 }
-                println!("-")
+                file.status = FileStatus::Failed;
+                file.error = Some(err.clone());
             }
-            FileNameGroup::Video { video, .. } => {
-                video.fetch_and_set_metadata(&cmd_path);
-                if let Some(next_stem) = video.next_file_stem_from_exif() {
-                    exif::rename_with_rollback(fs, &nf, group.merge_into_rename_refs(), &next_stem);
+            MoveOutcome::RolledBack => {
+                if nf.text() {
+                    println!("{} -> {} (ROLLBACK)", next, prev);
                 }
-                println!("-")
+                file.status = FileStatus::RolledBack;
+                file.rollback = true;
             }
-            FileNameGroup::LiveImage { image, .. } => {
-                image.fetch_and_set_metadata(&cmd_path);
-                if let Some(next_stem) = image.next_file_stem_from_exif() {
-                    exif::rename_with_rollback(fs, &nf, group.merge_into_rename_refs(), &next_stem);
+            MoveOutcome::RollbackFailed(err) => {
+                if nf.text() {
+                    eprintln!("ERROR: rolling back the {}: {}", next, err);
                 }
-                println!("-")
+                file.status = FileStatus::RollbackFailed;
+                file.error = Some(err.clone());
+                file.rollback = true;
             }
-            FileNameGroup::Uncertain {
-                primary, config, ..
-            } => {
-                let values = primary.iter().chain(config.iter()).collect::<Vec<_>>();
-                for item in values {
-                    nf.uncertain(&item.src)
+            MoveOutcome::Busy(err) => {
+                if nf.text() {
+                    eprintln!("{} -> Busy, {}", prev, err);
                 }
+                file.status = FileStatus::Busy;
+                file.error = Some(err.clone());
+            }
+        }
+    }
+}
+
+// Finishes or reverts the batch a previous run left behind.
+pub fn recover_batch(log: &Path, mode: &RunType) -> Result<(), String> {
+    if !log.exists() {
+        return Ok(());
+    }
+    if mode == &RunType::Dry {
+        println!(
+            "An unfinished batch rename was found in {}, run with --exec to finish it",
+            log.display()
+        );
+        return Ok(());
+    }
+    match batch::recover(&core::config::RealFileSystem::new(mode), log)? {
+        Some(Recovery::Completed(count)) => {
+            println!("Finished the unfinished batch rename ({} files)", count)
+        }
+        Some(Recovery::Reverted(count)) => {
+            println!("Reverted the unfinished batch rename ({} files)", count)
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+// The folders of the groups, in the order of `groups`. The events are
+// listed first so they can be checked before the run.
+fn event_dirs(groups: &[FileNameGroup], run: &EventRun, text: bool) -> Vec<Option<PathBuf>> {
+    let mut clusters = events::cluster(groups, &run.options);
+    events::label_events(&mut clusters, |x| run.labels.get(&x.start.date()).cloned());
+
+    let mut result = vec![None; groups.len()];
+    for event in clusters.iter() {
+        if text {
+            println!(
+                "Event {}: {} groups, {} - {}",
+                event.folder().display(),
+                event.groups.len(),
+                event.start.format("%Y-%m-%d %H:%M"),
+                event.end.format("%Y-%m-%d %H:%M")
+            );
+        }
+        for i in event.groups.iter() {
+            result[*i] = Some(run.root.join(event.folder()));
+        }
+    }
+    if text && !clusters.is_empty() {
+        println!("-");
+    }
+    result
+}
+
+// The stem from the `--name` pattern, or the date one. `number` is the
+// `{seq}` of the group.
+fn next_stem(group: &FileNameGroup, run: &RunOptions, number: Option<u64>) -> Option<String> {
+    match &run.name {
+        Some(pattern) => group
+            .date()
+            .and_then(|date| pattern.stem(&date, number.map(|x| run.seq.format(x)).as_deref())),
+        None => group.primary().and_then(|x| x.next_file_stem_from_exif()),
+    }
+}
+
+fn process_group<F: core::config::FileSystem>(
+    fs: &F,
+    nf: &RecordNotifier,
+    group: &FileNameGroup,
+    dir: Option<&Path>,
+    next_stem: Option<String>,
+    options: &RenameOptions,
+) -> GroupRecord {
+    let kind = match group {
+        FileNameGroup::Image { .. } => "image",
+        FileNameGroup::Video { .. } => "video",
+        FileNameGroup::LiveImage { .. } => "live_image",
+        FileNameGroup::Uncertain { .. } => "uncertain",
+        FileNameGroup::Unsupported { .. } => "unsupported",
+    };
+    nf.files.borrow_mut().clear();
+
+    match group {
+        FileNameGroup::Image { .. }
+        | FileNameGroup::Video { .. }
+        | FileNameGroup::LiveImage { .. } => {
+            if let Some(next_stem) = next_stem {
+                match exif::name_state(group, dir, &next_stem, options) {
+                    // Quietly, running it again on a done folder would
+                    // print every file.
+                    NameState::Unchanged => {
+                        for item in group.merge_into_rename_refs() {
+                            nf.unchanged(&item.src);
+                        }
+                        return group_record(nf, group, kind);
+                    }
+                    NameState::OldTemplate(template) if !options.migrate_old_names => {
+                        for item in group.merge_into_rename_refs() {
+                            nf.old_template(&item.src, template);
+                        }
+                    }
+                    _ => {
+                        exif::rename_into_with_rollback(
+                            fs,
+                            nf,
+                            group.merge_into_rename_refs(),
+                            dir,
+                            &next_stem,
+                            options,
+                        );
+                        // The files after the failed one are not tried at all.
+                        for item in group.merge_into_rename_refs() {
+                            let src = item.src.value();
+                            if !nf.files.borrow().iter().any(|x| x.old_path == *src) {
+                                nf.push(&item.src, FileStatus::Skipped);
+                            }
+                        }
+                    }
+                }
+            } else {
+                for item in group.merge_into_rename_refs() {
+                    nf.push(&item.src, FileStatus::NoDate);
+                }
+            }
+            if nf.text() {
                 println!("-")
             }
-            FileNameGroup::Unsupported { config, .. } => {
-                for item in config {
-                    nf.unsupported(&item.src);
+        }
+        FileNameGroup::Uncertain {
+            primary, config, ..
+        } => {
+            let values = primary.iter().chain(config.iter()).collect::<Vec<_>>();
+            for item in values {
+                nf.uncertain(&item.src)
+            }
+            if nf.text() {
+                println!("-")
+            }
+        }
+        FileNameGroup::Unsupported { config, .. } => {
+            for item in config {
+                nf.unsupported(&item.src);
+                if nf.text() {
                     println!("-");
                 }
             }
         }
     }
-    println!("");
+
+    group_record(nf, group, kind)
+}
+
+fn group_record(nf: &RecordNotifier, group: &FileNameGroup, kind: &'static str) -> GroupRecord {
+    GroupRecord {
+        key: group.group_key().to_string(),
+        kind,
+        date_source: group.primary().and_then(|x| x.date_source()),
+        files: nf.files.take(),
+    }
 }
 
 pub fn print_mode(mode: &RunType) {
-    match mode {
-        RunType::Dry => println!("DRY RUN:: run `rename --exec 'path/to' to commit"),
-        _ => {}
+    if mode == &RunType::Dry {
+        println!("DRY RUN:: run `rename --exec 'path/to' to commit")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(old_path: &str, new_path: Option<&str>, status: FileStatus) -> FileRecord {
+        FileRecord {
+            old_path: PathBuf::from(old_path),
+            new_path: new_path.map(PathBuf::from),
+            status,
+            error: None,
+            rollback: false,
+        }
+    }
+
+    fn group(files: Vec<FileRecord>) -> GroupRecord {
+        GroupRecord {
+            key: "a/IMG_1".to_string(),
+            kind: "image",
+            date_source: Some("DateTimeOriginal"),
+            files,
+        }
+    }
+
+    #[test]
+    fn the_group_has_the_worst_status_of_its_files() {
+        let status = |files| group(files).status();
+
+        assert_eq!(status(vec![]), FileStatus::NoDate);
+        assert_eq!(
+            status(vec![
+                file("a.jpg", None, FileStatus::Unchanged),
+                file("a.xmp", Some("b.xmp"), FileStatus::Renamed),
+            ]),
+            FileStatus::Renamed
+        );
+        assert_eq!(
+            status(vec![file("a.jpg", None, FileStatus::Unchanged)]),
+            FileStatus::Unchanged
+        );
+        // The group was put back, it failed.
+        assert_eq!(
+            status(vec![
+                file("a.jpg", Some("b.jpg"), FileStatus::RolledBack),
+                file("a.xmp", Some("b.xmp"), FileStatus::Failed),
+            ]),
+            FileStatus::Failed
+        );
+        assert_eq!(
+            status(vec![
+                file("a.jpg", Some("b.jpg"), FileStatus::RollbackFailed),
+                file("a.xmp", Some("b.xmp"), FileStatus::Failed),
+            ]),
+            FileStatus::RollbackFailed
+        );
+    }
+
+    #[test]
+    fn records_as_json_and_csv() {
+        let mut failed = file("a/IMG_1.xmp", Some("a/b.xmp"), FileStatus::Failed);
+        failed.error = Some("denied, really".to_string());
+        let record = group(vec![
+            file("a/IMG_1.jpg", Some("a/b.jpg"), FileStatus::RolledBack),
+            failed,
+        ]);
+
+        assert_eq!(
+            record.to_json(false),
+            serde_json::json!({
+                "type": "group",
+                "group": "a/IMG_1",
+                "kind": "image",
+                "status": "failed",
+                "date_source": "DateTimeOriginal",
+            })
+        );
+        let nested = record.to_json(true);
+        assert_eq!(nested["files"][0]["status"], "rolled_back");
+        assert_eq!(nested["files"][1]["error"], "denied, really");
+        assert_eq!(nested["files"][1]["type"], "file");
+
+        let rows = record.csv_rows();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].len(), CSV_HEADERS.len());
+        let mut out = vec![];
+        output::write_csv_row(&mut out, &rows[1]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a/IMG_1,image,a/IMG_1.xmp,a/b.xmp,failed,DateTimeOriginal,\"denied, really\",false\n"
+        );
+    }
+
+    #[test]
+    fn exit_codes_of_the_summary() {
+        let summary = |failed, rollback_failed, scan_errors| RenameSummary {
+            groups: 3,
+            failed,
+            rollback_failed,
+            scan_errors,
+            unchanged: 1,
+            busy: 1,
+            ..Default::default()
+        };
+
+        // The unchanged and the busy groups are not errors.
+        assert_eq!(summary(0, 0, 0).exit_code(), 0);
+        assert_eq!(summary(1, 0, 0).exit_code(), 2);
+        assert_eq!(summary(0, 0, 1).exit_code(), 2);
+        assert_eq!(summary(1, 1, 0).exit_code(), 3);
+    }
+
+    // The two names are not UTF-8 and have the same lossy string.
+    #[cfg(unix)]
+    #[test]
+    fn outcomes_of_the_files_with_the_same_lossy_name() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let a = PathBuf::from(OsStr::from_bytes(b"a/\xff.jpg"));
+        let b = PathBuf::from(OsStr::from_bytes(b"a/\xfe.jpg"));
+        assert_eq!(utils::path_to_string(&a), utils::path_to_string(&b));
+        let planned = |path: &PathBuf| FileRecord {
+            old_path: path.clone(),
+            new_path: Some(PathBuf::from("a/b.jpg")),
+            status: FileStatus::Planned,
+            error: None,
+            rollback: false,
+        };
+        let mut records = [group(vec![planned(&a)]), group(vec![planned(&b)])];
+        let outcomes = HashMap::from([
+            (a.clone(), MoveOutcome::Renamed),
+            (b.clone(), MoveOutcome::Failed("taken".to_string())),
+        ]);
+
+        set_outcomes(
+            &mut records,
+            &outcomes,
+            &RecordNotifier::new(RenameOutput::Json, false),
+        );
+        assert_eq!(records[0].files[0].status, FileStatus::Renamed);
+        assert_eq!(records[1].files[0].status, FileStatus::Failed);
+    }
+
+    async fn renames_are_kept_in_the_catalog_async() {
+        let pool = crate::commands::collect::memory_catalog().await;
+        for path in ["a/IMG_1.JPG", "a/IMG_2.JPG", "a/IMG_3.JPG", "a/old.JPG"] {
+            sqlx::query(
+                "insert into files(source_file, file_name, file_size) values (?1, 'x', '')",
+            )
+            .bind(path)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        let id_of = |path: &'static str| {
+            sqlx::query_scalar::<_, i64>("select id from files where source_file = ?1")
+                .bind(path)
+                .fetch_one(&pool)
+        };
+        let (one, two) = (
+            id_of("a/IMG_1.JPG").await.unwrap(),
+            id_of("a/IMG_2.JPG").await.unwrap(),
+        );
+
+        // The two files swap their names, the third one takes the name of
+        // a file that is gone and the last one was never collected.
+        let renamed = [
+            ("a/IMG_1.JPG", "a/IMG_2.JPG"),
+            ("a/IMG_2.JPG", "a/IMG_1.JPG"),
+            ("a/IMG_3.JPG", "a/old.JPG"),
+            ("a/IMG_4.JPG", "a/IMG_5.JPG"),
+        ]
+        .map(|(prev, next)| (prev.to_string(), next.to_string()));
+        save_renames(&pool, &renamed).await.unwrap();
+
+        assert_eq!(id_of("a/IMG_2.JPG").await.unwrap(), one);
+        assert_eq!(id_of("a/IMG_1.JPG").await.unwrap(), two);
+        let three = id_of("a/old.JPG").await.unwrap();
+        let names = sqlx::query_scalar::<_, String>("select file_name from files order by id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(names, ["IMG_2.JPG", "IMG_1.JPG", "old.JPG"]);
+        let history = sqlx::query_as::<_, (Option<i64>, String, String)>(
+            "select file_id, prev_path, next_path from renames order by id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            history,
+            [
+                (Some(one), "a/IMG_1.JPG".into(), "a/IMG_2.JPG".into()),
+                (Some(two), "a/IMG_2.JPG".into(), "a/IMG_1.JPG".into()),
+                (Some(three), "a/IMG_3.JPG".into(), "a/old.JPG".into()),
+                (None, "a/IMG_4.JPG".into(), "a/IMG_5.JPG".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn renames_are_kept_in_the_catalog() {
+        // `#[tokio::test]` looks for `::core`, our crate of the same name hides it.
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(renames_are_kept_in_the_catalog_async());
     }
 }
@@ -0,0 +1,304 @@
+use crate::commands::collect::connect_database;
+use crate::output;
+use core::utils;
+use eyre::{eyre, Result};
+use indicatif::HumanBytes;
+use sqlx::{FromRow, SqlitePool};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, FromRow)]
+struct Bucket {
+    name: Option<String>,
+    count: i64,
+    size: Option<i64>,
+}
+
+impl Bucket {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "count": self.count,
+            "size": self.size.unwrap_or(0),
+        })
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.name.clone().unwrap_or_else(|| "(unknown)".to_string()),
+            self.count.to_string(),
+            HumanBytes(self.size.unwrap_or(0) as u64).to_string(),
+        ]
+    }
+}
+
+// The file name ends with one of the extensions. Only our own constants
+// go into the SQL, so there is nothing to escape.
+fn has_ext_sql<'a>(column: &str, exts: impl Iterator<Item = &'a &'a str>) -> String {
+    let conditions = exts
+        .map(|ext| format!("lower({}) like '%.{}'", column, ext))
+        .collect::<Vec<_>>();
+    format!("({})", conditions.join(" or "))
+}
+
+fn is_media_sql(column: &str) -> String {
+    has_ext_sql(column, utils::IMGS.iter().chain(utils::VIDEOS.iter()))
+}
+
+#[derive(Debug, PartialEq)]
+struct Totals {
+    files: i64,
+    size: i64,
+    no_date: i64,
+    duplicate_waste: i64,
+    orphan_sidecars: i64,
+}
+
+async fn buckets(pool: &SqlitePool, name: &str) -> Result<Vec<Bucket>> {
+    sqlx::query_as(&format!(
+        r#"
+        select {name} as name, count(*) as count, sum(size_bytes) as size
+        from files where deleted_at is null
+        group by 1 order by 1
+    "#
+    ))
+    .fetch_all(pool)
+    .await
+    .map_err(|e| eyre!("{e}"))
+}
+
+async fn count(pool: &SqlitePool, query: &str) -> Result<i64> {
+    let (count,): (Option<i64>,) = sqlx::query_as(query)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| eyre!("{e}"))?;
+    Ok(count.unwrap_or(0))
+}
+
+async fn totals(pool: &SqlitePool) -> Result<Totals> {
+    let files = count(pool, "select count(*) from files where deleted_at is null").await?;
+    let size = count(
+        pool,
+        "select sum(size_bytes) from files where deleted_at is null",
+    )
+    .await?;
+    let no_date = count(
+        pool,
+        "select count(*) from files where deleted_at is null and date_time_original is null and creation_date is null",
+    )
+    .await?;
+    // Every copy but one of the same content is wasted space. The content
+    // hash is only there for the files `analyze` looked at.
+    let duplicate_waste = count(
+        pool,
+        r#"
+        select sum(size * (copies - 1)) from (
+            select max(f.size_bytes) as size, count(*) as copies
+            from files f join hashes h on h.file_id = f.id
+            where f.deleted_at is null and h.content_hash is not null
+            group by h.content_hash
+        )
+    "#,
+    )
+    .await?;
+    // The sidecars (.xmp, .aae, ...) with no photo or video next to them.
+    // The other files (.pdf, .txt) are no sidecars, they are left out.
+    let orphan_sidecars = count(
+        pool,
+        &format!(
+            r#"
+            select count(*) from files f
+            where f.deleted_at is null and f.group_id is not null and {}
+            and not exists (
+                select 1 from files m
+                where m.group_id = f.group_id and m.deleted_at is null and {}
+            )
+        "#,
+            has_ext_sql("f.file_name", utils::SIDECARS.iter()),
+            is_media_sql("m.file_name")
+        ),
+    )
+    .await?;
+
+    Ok(Totals {
+        files,
+        size,
+        no_date,
+        duplicate_waste,
+        orphan_sidecars,
+    })
+}
+
+#[tokio::main]
+pub async fn exec(db: &Path, json: bool) -> Result<()> {
+    let pool = connect_database(db, false).await?;
+
+    let date = "coalesce(date_time_original, creation_date)";
+    let sections = [
+        (
+            "year",
+            buckets(&pool, &format!("substr({date}, 1, 4)")).await?,
+        ),
+        (
+            "month",
+            buckets(&pool, &format!("substr({date}, 1, 7)")).await?,
+        ),
+        (
+            "camera",
+            buckets(
+                &pool,
+                "trim(coalesce(make, '') || ' ' || coalesce(model, ''))",
+            )
+            .await?
+            .into_iter()
+            .map(|x| Bucket {
+                name: x.name.filter(|x| !x.is_empty()),
+                ..x
+            })
+            .collect(),
+        ),
+        ("lens", buckets(&pool, "lens").await?),
+        ("file_type", buckets(&pool, "file_type").await?),
+        (
+            "date_source",
+            buckets(
+                &pool,
+                r#"
+                case
+                    when date_time_original is not null then 'DateTimeOriginal'
+                    when creation_date is not null then 'CreationDate'
+                    else 'none'
+                end
+            "#,
+            )
+            .await?,
+        ),
+    ];
+
+    let Totals {
+        files,
+        size,
+        no_date,
+        duplicate_waste,
+        orphan_sidecars,
+    } = totals(&pool).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    if json {
+        let mut value = serde_json::json!({
+            "files": files,
+            "size": size,
+            "no_date": no_date,
+            "duplicate_waste": duplicate_waste,
+            "orphan_sidecars": orphan_sidecars,
+        });
+        for (name, buckets) in sections.iter() {
+            value[name] = buckets.iter().map(|x| x.to_json()).collect();
+        }
+        writeln!(stdout, "{}", serde_json::to_string_pretty(&value)?)?;
+    } else {
+        for (name, buckets) in sections.iter() {
+            let rows = buckets.iter().map(|x| x.fields()).collect::<Vec<_>>();
+            output::write_table(&mut stdout, &[name, "files", "size"], &rows)?;
+            writeln!(stdout)?;
+        }
+        writeln!(
+            stdout,
+            "Files:            {} ({})",
+            files,
+            HumanBytes(size as u64)
+        )?;
+        writeln!(stdout, "Without a date:   {}", no_date)?;
+        writeln!(
+            stdout,
+            "Duplicate waste:  {}",
+            HumanBytes(duplicate_waste as u64)
+        )?;
+        writeln!(stdout, "Orphan sidecars:  {}", orphan_sidecars)?;
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::commands::collect::memory_catalog;
+
+    async fn insert(pool: &SqlitePool, path: &str, group: i64, date: Option<&str>, size: i64) {
+        sqlx::query("insert or ignore into groups(id, group_key) values (?1, ?1)")
+            .bind(group)
+            .execute(pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"
+            insert into files(source_file, file_name, file_size, date_time_original, size_bytes, group_id)
+            values (?1, ?2, '', ?3, ?4, ?5)
+        "#,
+        )
+        .bind(path)
+        .bind(path.rsplit('/').next().unwrap())
+        .bind(date)
+        .bind(size)
+        .bind(group)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    // `#[tokio::test]` looks for `::core`, our crate of the same name hides it.
+    #[test]
+    fn totals_of_the_catalog() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(totals_of_the_catalog_async());
+    }
+
+    async fn totals_of_the_catalog_async() {
+        let pool = memory_catalog().await;
+        insert(&pool, "a/IMG_1.JPG", 1, Some("2024-08-16 10:00:00"), 100).await;
+        insert(&pool, "a/IMG_1.xmp", 1, None, 1).await;
+        insert(&pool, "b/IMG_1.JPG", 2, Some("2024-08-16 10:00:00"), 100).await;
+        // Its photo is gone, the pdf next to it is no sidecar.
+        insert(&pool, "a/IMG_2.XMP", 3, None, 1).await;
+        insert(&pool, "a/notes.pdf", 4, None, 10).await;
+        insert(&pool, "a/IMG_3.aae", 5, None, 1).await;
+        insert(&pool, "a/IMG_3.HEIC", 5, None, 50).await;
+        sqlx::query(
+            "update files set deleted_at = current_timestamp where file_name = 'IMG_3.HEIC'",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "insert into hashes(file_id, content_hash) select id, 'same' from files where file_name = 'IMG_1.JPG'",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            totals(&pool).await.unwrap(),
+            Totals {
+                files: 6,
+                size: 213,
+                no_date: 4,
+                duplicate_waste: 100,
+                orphan_sidecars: 2,
+            }
+        );
+
+        let years = buckets(&pool, "substr(date_time_original, 1, 4)")
+            .await
+            .unwrap();
+        assert_eq!(
+            years.iter().map(|x| x.fields()).collect::<Vec<_>>(),
+            vec![
+                vec!["(unknown)".to_string(), "4".to_string(), "13 B".to_string()],
+                vec!["2024".to_string(), "2".to_string(), "200 B".to_string()],
+            ]
+        );
+    }
+}
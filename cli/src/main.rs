@@ -1,10 +1,15 @@
 mod commands;
+mod output;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use commands::rename;
 use core::config;
+use core::dedupe::{DedupeAction, KeepPolicy, Trash};
+use core::dir::CollectOptions;
 use std::error::Error;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -13,6 +18,58 @@ struct Cli {
     command: Option<Commands>,
 }
 
+#[derive(Args)]
+struct WalkArgs {
+    /// Only collect the files matching the glob (can be repeated)
+    #[arg(long = "include", value_name = "GLOB")]
+    include: Vec<String>,
+    /// Skip the files and directories matching the glob (can be repeated)
+    #[arg(long = "exclude", value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Collect the hidden and OS metadata files too (.DS_Store, Thumbs.db, ...)
+    #[arg(long)]
+    hidden: bool,
+    /// Do not read the .eximdignore files
+    #[arg(long)]
+    no_ignore: bool,
+    #[arg(long)]
+    max_depth: Option<usize>,
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// How many directories are read at the same time
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+}
+
+impl From<WalkArgs> for CollectOptions {
+    fn from(args: WalkArgs) -> Self {
+        Self {
+            include: args.include,
+            exclude: args.exclude,
+            skip_hidden: !args.hidden,
+            use_ignore_files: !args.no_ignore,
+            max_depth: args.max_depth,
+            follow_symlinks: args.follow_symlinks,
+            threads: args.threads,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum KeepArg {
+    Oldest,
+    Largest,
+    BestMetadata,
+    Folder,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DedupeActionArg {
+    Trash,
+    Hardlink,
+    Quarantine,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Test,
@@ -22,25 +79,187 @@ enum Commands {
         db: PathBuf,
         #[arg(long)]
         force: bool,
+        /// Continue the last collect of the path that did not finish
+        #[arg(long, conflicts_with = "retry_failed")]
+        resume: bool,
+        /// Collect the files of the path that failed again, from the last
+        /// collect that had failures
         #[arg(long)]
-        file_cache: bool,
-        #[arg(long)]
-        skip: Option<usize>,
+        retry_failed: bool,
+        /// Collect the metadata of at most this many files, the rest stays pending
         #[arg(long)]
         limit: Option<usize>,
         #[arg(long)]
         exec: bool,
+        #[command(flatten)]
+        walk: WalkArgs,
     },
     Analyze {
         db: PathBuf,
+        /// Also look for the images that look alike (re-exported, resized, recompressed).
+        /// Only JPEG and PNG are decoded.
+        #[arg(long)]
+        similar: bool,
+        /// How many bits (out of 64) two similar images can differ in
+        #[arg(long, default_value_t = 10)]
+        threshold: u32,
     },
+    /// Keep one file of every exact duplicate set and get rid of the rest
+    Dedupe {
+        /// The catalog created by the `collect` command. With `--undo`, the
+        /// files put back are in the catalog again.
+        #[arg(required_unless_present = "undo")]
+        db: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = KeepArg::Oldest)]
+        keep: KeepArg,
+        /// The folder whose copies are kept (with `--keep folder`)
+        #[arg(long, required_if_eq("keep", "folder"))]
+        prefer: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = DedupeActionArg::Trash)]
+        action: DedupeActionArg,
+        /// Where the copies are moved (with `--action quarantine`)
+        #[arg(long, required_if_eq("action", "quarantine"))]
+        quarantine: Option<PathBuf>,
+        /// Every change is written here, to be able to put the files back
+        #[arg(long, default_value = "eximd-undo.jsonl")]
+        undo_log: PathBuf,
+        /// Put back the files from an undo log
+        #[arg(long, value_name = "UNDO_LOG")]
+        undo: Option<PathBuf>,
+        #[arg(short, long)]
+        exec: bool,
+    },
+    /// List the files in the catalog that match the filters
+    Query {
+        #[arg(short, long)]
+        db: PathBuf,
+        #[command(flatten)]
+        filter: commands::query::QueryFilter,
+        #[arg(long, value_enum, default_value_t = output::OutputFormat::Table)]
+        format: output::OutputFormat,
+        /// End the paths with a NUL instead of a new line (with `--format paths`)
+        #[arg(short = '0', long)]
+        null: bool,
+    },
+    /// Summarise the catalog: per year, camera, lens and file type
+    Stats {
+        #[arg(short, long)]
+        db: PathBuf,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rename the photos and videos after the date they were taken
+    ///
+    /// The files already named with a date (`YYYY-MM-DD_HH.MM.SS`) keep the
+    /// date of their name, their metadata is not read. After fixing the
+    /// dates in the metadata (a wrong camera clock), run with `--recheck`.
     Rename {
         path: Option<PathBuf>,
         #[arg(short, long)]
         exec: bool,
+        /// Write the new file names in the NFC unicode form
+        #[arg(long)]
+        normalize_nfc: bool,
+        /// Also rename the files named with an old template (`YYYY-MM-DD HH.MM.SS`)
+        #[arg(long)]
+        migrate: bool,
+        /// Read the metadata of the files already named with their date too,
+        /// to rename them when the date changed (e.g. a camera clock fixed
+        /// since). Without it their date is taken from the name, exiftool is
+        /// not run for them and they keep their names.
+        #[arg(long)]
+        recheck: bool,
+        /// The case of the extensions in the new names, the sidecars included:
+        /// keep, lower or upper
+        #[arg(long, default_value = "keep")]
+        ext_case: config::ExtCase,
+        /// Write an extension with an other spelling, e.g. `--ext-alias jpeg=jpg
+        /// --ext-alias tiff=tif`
+        #[arg(long, value_parser = parse_ext_alias)]
+        ext_alias: Vec<(String, String)>,
+        /// Name the files with a date format instead, `{seq}` is the number
+        /// of the group in the order of the dates, e.g. `%Y-%m-%d_{seq}`
+        /// or `Wedding_{seq}`
+        #[arg(long, value_parser = core::name::NamePattern::parse)]
+        name: Option<core::name::NamePattern>,
+        /// What `{seq}` starts over for: global, day or folder
+        #[arg(long, default_value = "global")]
+        seq_scope: core::name::SeqScope,
+        /// The first number of `{seq}`
+        #[arg(long, default_value_t = 1)]
+        seq_start: u64,
+        /// The digits of `{seq}`, padded with zeros
+        #[arg(long, default_value_t = 4)]
+        seq_width: usize,
+        /// Move the groups into the folders of their events under the root
+        /// folder, `YYYY/YYYY-MM-DD label`. On a day with more events, the
+        /// start time is added, `YYYY-MM-DD HH.MM label`. The events are
+        /// listed first.
+        #[arg(long)]
+        events: bool,
+        /// A longer gap between two groups (in hours) starts a new event
+        #[arg(long, default_value_t = 3.0, requires = "events")]
+        event_gap: f64,
+        /// A group taken further away (in km) from the one before starts a
+        /// new event too
+        #[arg(long, requires = "events")]
+        event_distance: Option<f64>,
+        /// The label of the events that start on a day, e.g.
+        /// `--event-label 2024-08-16=Wedding`. The labels are not looked up
+        /// from the GPS positions.
+        #[arg(long, value_parser = parse_event_label, requires = "events")]
+        event_label: Vec<(chrono::NaiveDate, String)>,
+        /// How many exiftool processes run at once (defaults to the number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Print a record per file and group. The exit code is 2 when some
+        /// groups failed or some folders could not be read, and 3 when a
+        /// rollback failed too.
+        #[arg(long, value_enum, default_value_t = rename::RenameOutput::Text)]
+        output: rename::RenameOutput,
+        /// Rename all the groups in one batch. An intent log is kept in the
+        /// folder and an interrupted batch is finished or reverted on the next run.
+        #[arg(long)]
+        transaction: bool,
+        /// Leave alone the groups with a file in use: locked or open in an
+        /// other app, or changed in the last `--settle` seconds. The files
+        /// are locked while their group is renamed, except with
+        /// `--transaction` where they are only checked before the batch
+        #[arg(long)]
+        check_busy: bool,
+        #[arg(long, default_value_t = 30, requires = "check_busy")]
+        settle: u64,
+        /// The order the groups are renamed and printed in
+        #[arg(long, value_enum, default_value_t = rename::RenameSort::Name)]
+        sort: rename::RenameSort,
+        /// The catalog created by the `collect` command. The renames are
+        /// kept in it and its files get their new paths.
+        #[arg(long)]
+        db: Option<PathBuf>,
+        #[command(flatten)]
+        walk: WalkArgs,
     },
 }
 
+fn parse_ext_alias(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok((
+            from.trim_start_matches('.').to_string(),
+            to.trim_start_matches('.').to_string(),
+        )),
+        _ => Err(format!("'{}' is not FROM=TO", value)),
+    }
+}
+
+fn parse_event_label(value: &str) -> Result<(chrono::NaiveDate, String), String> {
+    let (day, label) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not YYYY-MM-DD=LABEL", value))?;
+    let day = chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .map_err(|err| format!("'{}' is not a YYYY-MM-DD date: {}", day, err))?;
+    Ok((day, label.to_string()))
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
@@ -53,21 +272,108 @@ fn main() -> Result<(), Box<dyn Error>> {
             path,
             db,
             force,
-            file_cache,
-            skip,
+            resume,
+            retry_failed,
             limit,
-            exec
+            exec,
+            walk,
         }) => {
             let path_buf = path.unwrap_or_else(|| {
                 std::env::current_dir()
                     .expect("Did not provide path and couldn't read current dir.")
             });
-            commands::collect::exec(&path_buf, &db, force, file_cache, skip, limit, exec)?;
+            let mode = if resume {
+                commands::collect::CollectMode::Resume
+            } else if retry_failed {
+                commands::collect::CollectMode::RetryFailed
+            } else {
+                commands::collect::CollectMode::New
+            };
+            commands::collect::exec(&path_buf, &db, &walk.into(), force, mode, limit, exec)?;
+        }
+        Some(Commands::Analyze {
+            db,
+            similar,
+            threshold,
+        }) => {
+            let threshold = similar.then_some(threshold);
+            commands::analyze::exec(&db, threshold)?;
+        }
+        Some(Commands::Dedupe {
+            db,
+            keep,
+            prefer,
+            action,
+            quarantine,
+            undo_log,
+            undo,
+            exec,
+        }) => {
+            let mode = if exec {
+                config::RunType::Exec
+            } else {
+                config::RunType::Dry
+            };
+            let fs = config::RealFileSystem::new(&mode);
+            commands::dedupe::print_mode(&mode);
+            if let Some(undo) = undo {
+                commands::dedupe::undo(&fs, &mode, db.as_deref(), &undo)?;
+            } else {
+                let policy = match keep {
+                    KeepArg::Oldest => KeepPolicy::Oldest,
+                    KeepArg::Largest => KeepPolicy::Largest,
+                    KeepArg::BestMetadata => KeepPolicy::BestMetadata,
+                    KeepArg::Folder => KeepPolicy::Folder(prefer.unwrap_or_default()),
+                };
+                let action = match action {
+                    DedupeActionArg::Trash => DedupeAction::Trash(Trash::from_env()?),
+                    DedupeActionArg::Hardlink => DedupeAction::Hardlink,
+                    DedupeActionArg::Quarantine => DedupeAction::Quarantine {
+                        dir: quarantine.unwrap_or_default(),
+                        root: PathBuf::new(),
+                    },
+                };
+                let db = db.expect("clap to require the db without --undo");
+                commands::dedupe::exec(&fs, &mode, &db, &policy, action, &undo_log)?;
+            }
+            commands::dedupe::print_mode(&mode);
         }
-        Some(Commands::Analyze { db }) => {
-            commands::analyze::exec(&db)?;
+        Some(Commands::Query {
+            db,
+            filter,
+            format,
+            null,
+        }) => {
+            commands::query::exec(&db, &filter, format, null)?;
         }
-        Some(Commands::Rename { exec, path }) => {
+        Some(Commands::Stats { db, json }) => {
+            commands::stats::exec(&db, json)?;
+        }
+        Some(Commands::Rename {
+            exec,
+            path,
+            normalize_nfc,
+            migrate,
+            recheck,
+            ext_case,
+            ext_alias,
+            name,
+            seq_scope,
+            seq_start,
+            seq_width,
+            events,
+            event_gap,
+            event_distance,
+            event_label,
+            jobs,
+            output,
+            sort,
+            transaction,
+            check_busy,
+            settle,
+            db,
+            walk,
+        }) => {
             let mode = if exec {
                 config::RunType::Exec
             } else {
@@ -78,10 +384,68 @@ fn main() -> Result<(), Box<dyn Error>> {
                 std::env::current_dir()
                     .expect("Did not provide path and couldn't read current dir.")
             });
-            let files = core::dir::collect_files(&path_buf)?;
-            rename::print_mode(&mode);
-            rename::process_files(&fs, &files);
-            rename::print_mode(&mode);
+            let batch_dir = if path_buf.is_file() {
+                path_buf.parent().unwrap_or(&path_buf)
+            } else {
+                &path_buf
+            };
+            let batch_log = batch_dir.join(rename::BATCH_LOG);
+            rename::recover_batch(&batch_log, &mode)?;
+            // The metadata of the first groups is read while the rest
+            // of the tree is still being walked.
+            let scanner = core::dir::scan(&path_buf, &walk.into())?;
+            // The structured output is only the records.
+            let text = output == rename::RenameOutput::Text;
+            if text {
+                rename::print_mode(&mode);
+            }
+            let options = config::RenameOptions {
+                normalize_nfc,
+                migrate_old_names: migrate,
+                ext_case,
+                ext_aliases: ext_alias.into_iter().collect(),
+            };
+            let run = rename::RunOptions {
+                jobs,
+                output,
+                sort,
+                batch_log: transaction.then_some(batch_log),
+                settle: check_busy.then_some(std::time::Duration::from_secs(settle)),
+                name,
+                seq: core::name::Sequence {
+                    scope: seq_scope,
+                    start: seq_start,
+                    width: seq_width,
+                },
+                events: events.then(|| rename::EventRun {
+                    options: core::events::EventOptions {
+                        gap_minutes: (event_gap * 60.0).round() as i64,
+                        distance_km: event_distance,
+                    },
+                    root: batch_dir.to_path_buf(),
+                    labels: event_label.into_iter().collect(),
+                }),
+                recheck,
+            };
+            let scan_errors = Arc::new(AtomicUsize::new(0));
+            let groups = scanner.sorted_groups({
+                let scan_errors = Arc::clone(&scan_errors);
+                move |err| {
+                    eprintln!("{}", err);
+                    scan_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            let mut summary = rename::process_files(&fs, groups, &options, &mode, &run)?;
+            summary.scan_errors = scan_errors.load(Ordering::Relaxed);
+            if let Some(db) = db.filter(|_| mode == config::RunType::Exec) {
+                rename::record_renames(&db, &summary.renamed)?;
+            }
+            if text {
+                rename::print_mode(&mode);
+            }
+            if summary.exit_code() != 0 {
+                std::process::exit(summary.exit_code());
+            }
         }
         _ => {
             println!("Incorrect usage");
@@ -0,0 +1,103 @@
+use clap::ValueEnum;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    Ndjson,
+    Paths,
+}
+
+// Quotes the field only when it has to.
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_csv_row<W: Write>(out: &mut W, fields: &[String]) -> std::io::Result<()> {
+    let line = fields
+        .iter()
+        .map(|x| csv_field(x))
+        .collect::<Vec<_>>()
+        .join(",");
+    writeln!(out, "{}", line)
+}
+
+// The columns are as wide as their longest value. The last one is not
+// padded, it is usually the path.
+pub fn write_table<W: Write>(
+    out: &mut W,
+    headers: &[&str],
+    rows: &[Vec<String>],
+) -> std::io::Result<()> {
+    let mut widths = headers
+        .iter()
+        .map(|x| x.chars().count())
+        .collect::<Vec<_>>();
+    for row in rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+
+    let line = |values: Vec<&str>| {
+        let last = values.len().saturating_sub(1);
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                if i == last {
+                    x.to_string()
+                } else {
+                    format!("{:width$}", x, width = widths[i])
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    writeln!(out, "{}", line(headers.to_vec()))?;
+    for row in rows {
+        writeln!(out, "{}", line(row.iter().map(|x| x.as_str()).collect()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("a/IMG_1.jpg"), "a/IMG_1.jpg");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+
+        let mut out = vec![];
+        write_csv_row(
+            &mut out,
+            &["a".to_string(), "b,c".to_string(), String::new()],
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a,\"b,c\",\n");
+    }
+
+    #[test]
+    fn table_columns_are_as_wide_as_their_values() {
+        let rows = vec![
+            vec!["2024".to_string(), "ä/long/path.jpg".to_string()],
+            vec!["".to_string(), "b.jpg".to_string()],
+        ];
+        let mut out = vec![];
+        write_table(&mut out, &["year", "path"], &rows).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "year  path\n2024  ä/long/path.jpg\n      b.jpg\n"
+        );
+    }
+}
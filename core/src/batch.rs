@@ -0,0 +1,1063 @@
+use super::case::CaseFolding;
+use super::config::FileSystem;
+use super::utils;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// A rename of the batch. The file goes to `tmp` first and to `dst` only
+// when every file of the batch is out of the way, so the swaps and the
+// cycles (A -> B, B -> A) work as well.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BatchEntry {
+    pub src: PathBuf,
+    pub tmp: PathBuf,
+    pub dst: PathBuf,
+}
+
+// The intent log is a JSON line per step. All the entries are written
+// before the first rename, the markers tell how far the batch got.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum BatchStep {
+    Intent(BatchEntry),
+    // Every file is at its `tmp` name, from here on the batch is finished
+    // instead of reverted.
+    Prepared,
+    // Every file is at its `tmp` name again and goes back to `src`.
+    Reverting,
+    Done,
+}
+
+pub struct BatchLog {
+    path: PathBuf,
+    file: std::fs::File,
+}
+
+// The lines that were written whole. A crash in the middle of an append
+// leaves the last line without its newline, that step never happened.
+fn written_lines(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().rposition(|x| *x == b'\n') {
+        Some(end) => &bytes[..=end],
+        None => &[],
+    }
+}
+
+impl BatchLog {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Error: opening the batch log: {}", err))?;
+        // The next steps don't go after a cut line.
+        let bytes = std::fs::read(path).unwrap_or_default();
+        let written = written_lines(&bytes).len();
+        if written < bytes.len() {
+            file.set_len(written as u64)
+                .map_err(|err| format!("Error: writing the batch log: {}", err))?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    // The steps are synced together, a marker is only there when
+    // everything before it is.
+    pub fn append(&mut self, steps: &[BatchStep]) -> Result<(), String> {
+        let mut lines = String::new();
+        for step in steps {
+            lines.push_str(&serde_json::to_string(step).map_err(|err| err.to_string())?);
+            lines.push('\n');
+        }
+        self.file
+            .write_all(lines.as_bytes())
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| format!("Error: writing the batch log: {}", err))
+    }
+
+    pub fn read(path: &Path) -> Result<Vec<BatchStep>, String> {
+        let bytes =
+            std::fs::read(path).map_err(|err| format!("Error: opening the batch log: {}", err))?;
+        String::from_utf8_lossy(written_lines(&bytes))
+            .lines()
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| serde_json::from_str(x).map_err(|err| err.to_string()))
+            .collect()
+    }
+
+    fn remove(self) -> Result<(), String> {
+        std::fs::remove_file(&self.path)
+            .map_err(|err| format!("Error: removing the batch log: {}", err))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchError {
+    // Nothing was renamed in the end.
+    Reverted(String),
+    // The files are left half renamed, the log stays for the next run.
+    Stuck(String),
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Reverted(err) => write!(f, "{} (reverted)", err),
+            BatchError::Stuck(err) => write!(f, "{} (the batch log is kept)", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    Completed(usize),
+    Reverted(usize),
+}
+
+#[derive(Debug)]
+pub struct BatchRename {
+    pub entries: Vec<BatchEntry>,
+}
+
+impl BatchRename {
+    // The `tmp` names are hidden files next to the source, a rename in the
+    // same directory is atomic.
+    pub fn new(id: &str, moves: &[(PathBuf, PathBuf)]) -> Self {
+        let entries = moves
+            .iter()
+            .enumerate()
+            .map(|(i, (src, dst))| BatchEntry {
+                src: src.clone(),
+                tmp: tmp_name(src, id, i),
+                dst: dst.clone(),
+            })
+            .collect();
+        Self { entries }
+    }
+
+    pub fn run<F: FileSystem>(&self, fs: &F, log_path: &Path) -> Result<usize, BatchError> {
+        let mut log = BatchLog::create(log_path).map_err(BatchError::Reverted)?;
+        let intents = self
+            .entries
+            .iter()
+            .map(|x| BatchStep::Intent(x.clone()))
+            .collect::<Vec<_>>();
+        log.append(&intents).map_err(BatchError::Reverted)?;
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if let Err(err) = fs.rename(&entry.src, &entry.tmp) {
+                let err = format!("{}: {}", entry.src.display(), err);
+                return match put_back(fs, &self.entries[..i]) {
+                    Ok(_) => {
+                        log.remove().map_err(BatchError::Stuck)?;
+                        Err(BatchError::Reverted(err))
+                    }
+                    Err(revert_err) => Err(BatchError::Stuck(format!("{}, {}", err, revert_err))),
+                };
+            }
+        }
+        log.append(&[BatchStep::Prepared])
+            .map_err(BatchError::Stuck)?;
+
+        for entry in self.entries.iter() {
+            // Every file of the batch is at its `tmp` name, a file at the
+            // target is not ours and the rename would replace it.
+            let result = if fs.exists(&entry.dst) {
+                Err(format!("'{}' already exists", entry.dst.display()))
+            } else {
+                fs.rename(&entry.tmp, &entry.dst)
+                    .map_err(|err| err.to_string())
+            };
+            if let Err(err) = result {
+                let err = format!("{}: {}", entry.src.display(), err);
+                return match revert(fs, &self.entries, &mut log) {
+                    Ok(_) => {
+                        log.remove().map_err(BatchError::Stuck)?;
+                        Err(BatchError::Reverted(err))
+                    }
+                    Err(revert_err) => Err(BatchError::Stuck(format!("{}, {}", err, revert_err))),
+                };
+            }
+        }
+        log.append(&[BatchStep::Done]).map_err(BatchError::Stuck)?;
+        log.remove().map_err(BatchError::Stuck)?;
+
+        Ok(self.entries.len())
+    }
+}
+
+fn tmp_name(src: &Path, id: &str, index: usize) -> PathBuf {
+    src.with_file_name(format!(".eximd-{}-{}.tmp", id, index))
+}
+
+// The renames that can't be done: the target is taken by a file outside
+// of the moves (in any of its NFC/NFD spellings), or by an other move.
+pub fn conflicts<F: FileSystem>(
+    fs: &F,
+    moves: &[(PathBuf, PathBuf)],
+    case: &CaseFolding,
+) -> Vec<(PathBuf, String)> {
+    let sources = moves.iter().map(|x| case.key(&x.0)).collect::<HashSet<_>>();
+    let mut targets = HashMap::new();
+    let mut result = vec![];
+    for (src, dst) in moves.iter() {
+        if let Some(other) = targets.insert(case.key(dst), src) {
+            result.push((
+                src.clone(),
+                format!(
+                    "'{}' is also renamed to '{}'",
+                    other.display(),
+                    dst.display()
+                ),
+            ));
+        } else if let Some(existing) = utils::file_name_variants(dst)
+            .into_iter()
+            .filter(|x| x != src && !sources.contains(&case.key(x)) && fs.exists(x))
+            .find(|x| !fs.same_file(x, src))
+        {
+            result.push((
+                src.clone(),
+                format!("'{}' already exists", existing.display()),
+            ));
+        }
+    }
+    result
+}
+
+// `conflicts` for the moves of the groups. A group with a conflict is left
+// out as a whole and its files stay where they are, so they can take the
+// target of an other group in turn: it goes on until nothing changes.
+// `left_out` has the groups left out before (the busy ones). Returns the
+// index of the moves with a conflict and the reason.
+pub fn conflicts_of_the_groups<F: FileSystem>(
+    fs: &F,
+    moves: &[(PathBuf, PathBuf)],
+    groups: &[usize],
+    left_out: &HashSet<usize>,
+    case: &CaseFolding,
+) -> Vec<(usize, String)> {
+    let mut left_out = left_out.clone();
+    let mut result = vec![];
+    loop {
+        let rest = (0..moves.len())
+            .filter(|i| !left_out.contains(&groups[*i]))
+            .collect::<Vec<_>>();
+        let index = rest
+            .iter()
+            .map(|i| (&moves[*i].0, *i))
+            .collect::<HashMap<_, _>>();
+        let found = conflicts(
+            fs,
+            &rest.iter().map(|i| moves[*i].clone()).collect::<Vec<_>>(),
+            case,
+        );
+        if found.is_empty() {
+            return result;
+        }
+        for (src, err) in found {
+            let i = index[&src];
+            left_out.insert(groups[i]);
+            result.push((i, err));
+        }
+    }
+}
+
+// A single rename of `order_moves`, `index` is the move it is part of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderedMove {
+    pub index: usize,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+// Orders the moves so the target of every rename is free by the time it
+// runs. A chain (A -> B, B -> C) is renamed from its end, a cycle (A -> B,
+// B -> A) first moves one of its files to a temporary name. The targets
+// must be unique, see `conflicts`.
+pub fn order_moves(id: &str, moves: &[(PathBuf, PathBuf)], case: &CaseFolding) -> Vec<OrderedMove> {
+    let sources = moves
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (case.key(&x.0), i))
+        .collect::<HashMap<_, _>>();
+    // The move that frees the target of this one.
+    let blocker = |i: usize| {
+        sources
+            .get(&case.key(&moves[i].1))
+            .copied()
+            .filter(|x| *x != i)
+    };
+    // A rename that only changes the case goes through a temporary name,
+    // the file system takes both names for the same file.
+    let step = |i: usize| {
+        let (src, dst) = &moves[i];
+        if case.same_name(src, dst) {
+            let tmp = tmp_name(src, id, i);
+            vec![
+                OrderedMove {
+                    index: i,
+                    from: src.clone(),
+                    to: tmp.clone(),
+                },
+                OrderedMove {
+                    index: i,
+                    from: tmp,
+                    to: dst.clone(),
+                },
+            ]
+        } else {
+            vec![OrderedMove {
+                index: i,
+                from: src.clone(),
+                to: dst.clone(),
+            }]
+        }
+    };
+
+    let mut done = vec![false; moves.len()];
+    let mut on_path = vec![false; moves.len()];
+    let mut result = vec![];
+    for start in 0..moves.len() {
+        // Every move of the path waits on the next one.
+        let mut path = vec![];
+        let mut next = Some(start);
+        while let Some(i) = next.filter(|x| !done[*x] && !on_path[*x]) {
+            on_path[i] = true;
+            path.push(i);
+            next = blocker(i);
+        }
+
+        match next.filter(|x| on_path[*x]) {
+            Some(first) => {
+                let k = path.iter().position(|x| *x == first).unwrap_or(0);
+                let tmp = tmp_name(&moves[first].0, id, first);
+                result.push(OrderedMove {
+                    index: first,
+                    from: moves[first].0.clone(),
+                    to: tmp.clone(),
+                });
+                result.extend(path[k + 1..].iter().rev().flat_map(|x| step(*x)));
+                result.push(OrderedMove {
+                    index: first,
+                    from: tmp,
+                    to: moves[first].1.clone(),
+                });
+                result.extend(path[..k].iter().rev().flat_map(|x| step(*x)));
+            }
+            None => result.extend(path.iter().rev().flat_map(|x| step(*x))),
+        }
+        for i in path {
+            done[i] = true;
+            on_path[i] = false;
+        }
+    }
+    result
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MoveOutcome {
+    Renamed,
+    Failed(String),
+    RolledBack,
+    RollbackFailed(String),
+    // An other move of the group failed first.
+    Skipped,
+    // The guard of the group said no, nothing of it was touched.
+    Busy(String),
+}
+
+// Runs the moves in the order of `order_moves`. The moves with the same
+// group go together: when one of them fails, the others are put back and
+// the rest of the group is skipped. The other groups go on.
+//
+// `guard` is called before the first rename of a group, what it returns
+// is kept until the last one is done (the locks of the files). An error
+// leaves the group alone.
+pub fn rename_in_order<F, G>(
+    fs: &F,
+    id: &str,
+    moves: &[(PathBuf, PathBuf)],
+    groups: &[usize],
+    case: &CaseFolding,
+    mut guard: impl FnMut(usize) -> Result<G, String>,
+) -> Vec<MoveOutcome>
+where
+    F: FileSystem,
+{
+    let ordered = order_moves(id, moves, case);
+    let mut outcomes = vec![MoveOutcome::Skipped; moves.len()];
+    let mut done: Vec<OrderedMove> = vec![];
+    let mut failed = HashSet::new();
+    let mut left = HashMap::new();
+    for step in ordered.iter() {
+        *left.entry(groups[step.index]).or_insert(0) += 1;
+    }
+    let mut guards = HashMap::new();
+
+    for step in ordered {
+        let group = groups[step.index];
+        if failed.contains(&group) {
+            continue;
+        }
+        if let Entry::Vacant(entry) = guards.entry(group) {
+            match guard(group) {
+                Ok(x) => {
+                    entry.insert(x);
+                }
+                Err(err) => {
+                    for (i, _) in groups.iter().enumerate().filter(|x| *x.1 == group) {
+                        outcomes[i] = MoveOutcome::Busy(err.clone());
+                    }
+                    failed.insert(group);
+                    continue;
+                }
+            }
+        }
+        // The target of a failed group is still there.
+        let result = if fs.exists(&step.to) && !fs.same_file(&step.to, &step.from) {
+            Err(format!("'{}' already exists", step.to.display()))
+        } else {
+            make_dirs(fs, std::slice::from_ref(&step.to)).and_then(|_| {
+                fs.rename(&step.from, &step.to)
+                    .map_err(|err| err.to_string())
+            })
+        };
+        match result {
+            Ok(_) => {
+                if step.to == moves[step.index].1 {
+                    outcomes[step.index] = MoveOutcome::Renamed;
+                }
+                done.push(step);
+                let left = left.entry(group).or_insert(1);
+                *left -= 1;
+                if *left == 0 {
+                    guards.remove(&group);
+                }
+            }
+            Err(err) => {
+                outcomes[step.index] = MoveOutcome::Failed(err);
+                failed.insert(group);
+                for prev in done.iter().rev().filter(|x| groups[x.index] == group) {
+                    // In a cycle with an other group, its file can be at
+                    // our old name already. It is not replaced.
+                    let result = if fs.exists(&prev.from) && !fs.same_file(&prev.from, &prev.to) {
+                        Err(format!("'{}' already exists", prev.from.display()))
+                    } else {
+                        fs.rename(&prev.to, &prev.from)
+                            .map_err(|err| err.to_string())
+                    };
+                    match result {
+                        Ok(_) if outcomes[prev.index] == MoveOutcome::Renamed => {
+                            outcomes[prev.index] = MoveOutcome::RolledBack
+                        }
+                        Ok(_) => {}
+                        Err(err) => outcomes[prev.index] = MoveOutcome::RollbackFailed(err),
+                    }
+                }
+                guards.remove(&group);
+            }
+        }
+    }
+    outcomes
+}
+
+// The folders the files are moved into (the events), only made once a
+// file goes there.
+pub fn make_dirs<F: FileSystem>(fs: &F, targets: &[PathBuf]) -> Result<(), String> {
+    for dir in targets.iter().filter_map(|x| x.parent()) {
+        if dir.as_os_str().is_empty() || fs.exists(dir) {
+            continue;
+        }
+        fs.create_dir_all(dir)
+            .map_err(|err| format!("creating {}: {}", dir.display(), err))?;
+    }
+    Ok(())
+}
+
+// Before `Prepared`: the files with a `tmp` name go back.
+fn put_back<F: FileSystem>(fs: &F, entries: &[BatchEntry]) -> Result<usize, String> {
+    let mut count = 0;
+    for entry in entries.iter().filter(|x| fs.exists(&x.tmp)) {
+        fs.rename(&entry.tmp, &entry.src)
+            .map_err(|err| format!("Error: putting back {}: {}", entry.src.display(), err))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// After `Prepared` every file without a `tmp` name is at its `dst`. They
+// all go back to `tmp` first, the `src` names can be taken by the `dst`
+// of an other file.
+fn revert<F: FileSystem>(
+    fs: &F,
+    entries: &[BatchEntry],
+    log: &mut BatchLog,
+) -> Result<usize, String> {
+    for entry in entries.iter().rev().filter(|x| !fs.exists(&x.tmp)) {
+        fs.rename(&entry.dst, &entry.tmp)
+            .map_err(|err| format!("Error: reverting {}: {}", entry.dst.display(), err))?;
+    }
+    log.append(&[BatchStep::Reverting])?;
+    put_back(fs, entries)
+}
+
+fn complete<F: FileSystem>(fs: &F, entries: &[BatchEntry]) -> Result<usize, String> {
+    let mut count = 0;
+    for entry in entries.iter().filter(|x| fs.exists(&x.tmp)) {
+        fs.rename(&entry.tmp, &entry.dst)
+            .map_err(|err| format!("Error: finishing {}: {}", entry.dst.display(), err))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Picks up a batch that was stopped halfway. Before `Prepared` it is
+// reverted, after it the renames are finished (or reverted when that
+// doesn't work out). Nothing to do when there is no log.
+pub fn recover<F: FileSystem>(fs: &F, log_path: &Path) -> Result<Option<Recovery>, String> {
+    if !log_path.exists() {
+        return Ok(None);
+    }
+    let steps = BatchLog::read(log_path)?;
+    let entries = steps
+        .iter()
+        .filter_map(|x| match x {
+            BatchStep::Intent(entry) => Some(entry.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let has = |step: BatchStep| steps.contains(&step);
+
+    let mut log = BatchLog::create(log_path)?;
+    let result = if has(BatchStep::Done) {
+        None
+    } else if has(BatchStep::Reverting) || !has(BatchStep::Prepared) {
+        Some(Recovery::Reverted(put_back(fs, &entries)?))
+    } else {
+        match complete(fs, &entries) {
+            Ok(count) => Some(Recovery::Completed(count)),
+            Err(_) => Some(Recovery::Reverted(revert(fs, &entries, &mut log)?)),
+        }
+    };
+    log.remove()?;
+
+    Ok(result)
+}
+
+// Collects the renames of the groups instead of doing them, they are
+// done together by `BatchRename`. A target is taken only by an other
+// rename of the batch, the disk is checked for the whole batch at once.
+#[derive(Debug, Default)]
+pub struct BatchPlanner {
+    moves: RefCell<Vec<(PathBuf, PathBuf)>>,
+}
+
+impl BatchPlanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn moves(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.moves.borrow().clone()
+    }
+}
+
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "only the renames are part of a batch",
+    )
+}
+
+impl FileSystem for BatchPlanner {
+    fn rename(&self, prev: &Path, next: &Path) -> std::io::Result<()> {
+        self.moves
+            .borrow_mut()
+            .push((prev.to_path_buf(), next.to_path_buf()));
+        Ok(())
+    }
+
+    fn hard_link(&self, _original: &Path, _link: &Path) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn copy(&self, _from: &Path, _to: &Path) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn write(&self, _path: &Path, _contents: &[u8]) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn remove_file(&self, _path: &Path) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.moves.borrow().iter().any(|x| x.1 == path)
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        a == b
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::{MockFileSystem, RealFileSystem, RunType};
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write_files(dir: &Path, names: &[&str]) {
+        for name in names {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn batch_renames_with_a_swap() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_files(dir, &["a.jpg", "b.jpg", "c.jpg"]);
+        let log_path = dir.join(".batch.jsonl");
+        let batch = BatchRename::new(
+            "1",
+            &[
+                (dir.join("a.jpg"), dir.join("b.jpg")),
+                (dir.join("b.jpg"), dir.join("a.jpg")),
+                (dir.join("c.jpg"), dir.join("d.jpg")),
+            ],
+        );
+        let fs = RealFileSystem::new(&RunType::Exec);
+
+        let moves = batch
+            .entries
+            .iter()
+            .map(|x| (x.src.clone(), x.dst.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(conflicts(&fs, &moves, &CaseFolding::default()), vec![]);
+        assert_eq!(batch.run(&fs, &log_path), Ok(3));
+        assert_eq!(read(dir.join("a.jpg")), "b.jpg");
+        assert_eq!(read(dir.join("b.jpg")), "a.jpg");
+        assert_eq!(read(dir.join("d.jpg")), "c.jpg");
+        assert!(!log_path.exists());
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn conflicts_of_the_moves() {
+        let fs = MockFileSystem::new();
+        fs.existing_files
+            .borrow_mut()
+            .extend([PathBuf::from("/a/c.jpg"), PathBuf::from("/a/b.jpg")]);
+        let moves = moves(&[
+            ("/a/a.jpg", "/a/c.jpg"),
+            ("/a/b.jpg", "/a/d.jpg"),
+            ("/a/e.jpg", "/a/d.jpg"),
+            // Taken by a file that moves away.
+            ("/a/f.jpg", "/a/b.jpg"),
+        ]);
+
+        let conflicts = conflicts(&fs, &moves, &CaseFolding::default());
+        assert_eq!(
+            conflicts.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
+            vec![PathBuf::from("/a/a.jpg"), PathBuf::from("/a/e.jpg")]
+        );
+    }
+
+    // Y (B -> C) is left out for the file at C, so X (A -> B) can't have B.
+    #[test]
+    fn a_left_out_group_leaves_its_source_taken() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        let fs = RealFileSystem::new(&RunType::Exec);
+        let moves = vec![
+            (dir.join("a.jpg"), dir.join("b.jpg")),
+            (dir.join("b.jpg"), dir.join("c.jpg")),
+        ];
+
+        let found = conflicts_of_the_groups(
+            &fs,
+            &moves,
+            &[0, 1],
+            &HashSet::new(),
+            &CaseFolding::default(),
+        );
+        assert_eq!(found.iter().map(|x| x.0).collect::<Vec<_>>(), vec![1, 0]);
+
+        // Without the check, the batch refuses to replace B itself.
+        let log_path = dir.join(".batch.jsonl");
+        let result = BatchRename::new("1", &moves[..1]).run(&fs, &log_path);
+        assert!(matches!(result, Err(BatchError::Reverted(_))));
+        assert_eq!(read(dir.join("a.jpg")), "a.jpg");
+        assert_eq!(read(dir.join("b.jpg")), "b.jpg");
+        assert_eq!(read(dir.join("c.jpg")), "c.jpg");
+        assert!(!log_path.exists());
+    }
+
+    // The run stopped before `Prepared`, the moved files go back.
+    #[test]
+    fn recover_reverts_an_unprepared_batch() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_files(dir, &["a.jpg", "b.jpg"]);
+        let log_path = dir.join(".batch.jsonl");
+        let batch = BatchRename::new(
+            "1",
+            &[
+                (dir.join("a.jpg"), dir.join("b.jpg")),
+                (dir.join("b.jpg"), dir.join("a.jpg")),
+            ],
+        );
+        let intents = batch
+            .entries
+            .iter()
+            .map(|x| BatchStep::Intent(x.clone()))
+            .collect::<Vec<_>>();
+        BatchLog::create(&log_path)
+            .unwrap()
+            .append(&intents)
+            .unwrap();
+        std::fs::rename(&batch.entries[0].src, &batch.entries[0].tmp).unwrap();
+
+        let fs = RealFileSystem::new(&RunType::Exec);
+        assert_eq!(recover(&fs, &log_path), Ok(Some(Recovery::Reverted(1))));
+        assert_eq!(read(dir.join("a.jpg")), "a.jpg");
+        assert_eq!(read(dir.join("b.jpg")), "b.jpg");
+        assert!(!log_path.exists());
+        assert_eq!(recover(&fs, &log_path), Ok(None));
+    }
+
+    // The run stopped while `Prepared` was written, the batch was not
+    // prepared yet.
+    #[test]
+    fn recover_ignores_a_cut_line() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_files(dir, &["a.jpg", "b.jpg"]);
+        let log_path = dir.join(".batch.jsonl");
+        let batch = BatchRename::new("1", &[(dir.join("a.jpg"), dir.join("c.jpg"))]);
+        let intents = batch
+            .entries
+            .iter()
+            .map(|x| BatchStep::Intent(x.clone()))
+            .collect::<Vec<_>>();
+        BatchLog::create(&log_path)
+            .unwrap()
+            .append(&intents)
+            .unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .unwrap();
+        file.write_all(b"\"Prep").unwrap();
+        std::fs::rename(&batch.entries[0].src, &batch.entries[0].tmp).unwrap();
+
+        assert_eq!(BatchLog::read(&log_path), Ok(intents.clone()));
+        // The next step goes on a line of its own.
+        BatchLog::create(&log_path)
+            .unwrap()
+            .append(&[BatchStep::Reverting])
+            .unwrap();
+        let mut steps = intents;
+        steps.push(BatchStep::Reverting);
+        assert_eq!(BatchLog::read(&log_path), Ok(steps));
+
+        let fs = RealFileSystem::new(&RunType::Exec);
+        assert_eq!(recover(&fs, &log_path), Ok(Some(Recovery::Reverted(1))));
+        assert_eq!(read(dir.join("a.jpg")), "a.jpg");
+        assert!(!log_path.exists());
+    }
+
+    // The run stopped after `Prepared`, the rest of the renames are done.
+    #[test]
+    fn recover_completes_a_prepared_batch() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_files(dir, &["a.jpg", "b.jpg"]);
+        let log_path = dir.join(".batch.jsonl");
+        let batch = BatchRename::new(
+            "1",
+            &[
+                (dir.join("a.jpg"), dir.join("b.jpg")),
+                (dir.join("b.jpg"), dir.join("a.jpg")),
+            ],
+        );
+        let mut steps = batch
+            .entries
+            .iter()
+            .map(|x| BatchStep::Intent(x.clone()))
+            .collect::<Vec<_>>();
+        steps.push(BatchStep::Prepared);
+        BatchLog::create(&log_path).unwrap().append(&steps).unwrap();
+        for entry in batch.entries.iter() {
+            std::fs::rename(&entry.src, &entry.tmp).unwrap();
+        }
+        std::fs::rename(&batch.entries[0].tmp, &batch.entries[0].dst).unwrap();
+
+        let fs = RealFileSystem::new(&RunType::Exec);
+        assert_eq!(recover(&fs, &log_path), Ok(Some(Recovery::Completed(1))));
+        assert_eq!(read(dir.join("a.jpg")), "b.jpg");
+        assert_eq!(read(dir.join("b.jpg")), "a.jpg");
+        assert!(!log_path.exists());
+    }
+
+    fn moves(pairs: &[(&str, &str)]) -> Vec<(PathBuf, PathBuf)> {
+        pairs
+            .iter()
+            .map(|(a, b)| (PathBuf::from(a), PathBuf::from(b)))
+            .collect()
+    }
+
+    fn steps(ordered: &[OrderedMove]) -> Vec<(String, String)> {
+        ordered
+            .iter()
+            .map(|x| (x.from.display().to_string(), x.to.display().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn order_moves_renames_a_chain_from_its_end() {
+        let ordered = order_moves(
+            "1",
+            &moves(&[("/a/1", "/a/2"), ("/a/2", "/a/3"), ("/a/3", "/a/4")]),
+            &CaseFolding::default(),
+        );
+        assert_eq!(
+            steps(&ordered),
+            vec![
+                ("/a/3".to_string(), "/a/4".to_string()),
+                ("/a/2".to_string(), "/a/3".to_string()),
+                ("/a/1".to_string(), "/a/2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn order_moves_breaks_a_cycle_with_a_temporary_name() {
+        let ordered = order_moves(
+            "1",
+            &moves(&[
+                ("/a/1", "/a/2"),
+                ("/a/2", "/a/3"),
+                ("/a/3", "/a/1"),
+                ("/a/4", "/a/5"),
+            ]),
+            &CaseFolding::default(),
+        );
+        assert_eq!(
+            steps(&ordered),
+            vec![
+                ("/a/1".to_string(), "/a/.eximd-1-0.tmp".to_string()),
+                ("/a/3".to_string(), "/a/1".to_string()),
+                ("/a/2".to_string(), "/a/3".to_string()),
+                ("/a/.eximd-1-0.tmp".to_string(), "/a/2".to_string()),
+                ("/a/4".to_string(), "/a/5".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn order_moves_on_a_case_insensitive_directory() {
+        let moves = moves(&[
+            ("/sd/IMG_1.JPG", "/sd/img_1.jpg"),
+            ("/sd/B.JPG", "/sd/c.jpg"),
+            ("/sd/a.jpg", "/sd/b.jpg"),
+        ]);
+
+        // Byte exact, `b.jpg` is not `B.JPG`.
+        let ordered = order_moves("1", &moves, &CaseFolding::default());
+        assert_eq!(ordered.len(), 3);
+        assert_eq!(ordered[0].to, PathBuf::from("/sd/img_1.jpg"));
+
+        let case = CaseFolding::new([PathBuf::from("/sd")]);
+        assert_eq!(
+            steps(&order_moves("1", &moves, &case)),
+            vec![
+                (
+                    "/sd/IMG_1.JPG".to_string(),
+                    "/sd/.eximd-1-0.tmp".to_string()
+                ),
+                (
+                    "/sd/.eximd-1-0.tmp".to_string(),
+                    "/sd/img_1.jpg".to_string()
+                ),
+                ("/sd/B.JPG".to_string(), "/sd/c.jpg".to_string()),
+                ("/sd/a.jpg".to_string(), "/sd/b.jpg".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicts_on_a_case_insensitive_directory() {
+        let fs = MockFileSystem::new();
+        let moves = moves(&[("/sd/a.jpg", "/sd/C.JPG"), ("/sd/b.jpg", "/sd/c.jpg")]);
+
+        assert_eq!(conflicts(&fs, &moves, &CaseFolding::default()), vec![]);
+        let case = CaseFolding::new([PathBuf::from("/sd")]);
+        assert_eq!(
+            conflicts(&fs, &moves, &case)
+                .iter()
+                .map(|x| x.0.clone())
+                .collect::<Vec<_>>(),
+            vec![PathBuf::from("/sd/b.jpg")]
+        );
+    }
+
+    // The second group can't be renamed, its first file is put back. The
+    // swap of the other groups goes on.
+    #[test]
+    fn rename_in_order_rolls_back_the_failed_group() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_files(dir, &["a.jpg", "b.jpg", "c.jpg", "c.xmp", "taken.xmp"]);
+        let fs = RealFileSystem::new(&RunType::Exec);
+        let moves = vec![
+            (dir.join("a.jpg"), dir.join("b.jpg")),
+            (dir.join("b.jpg"), dir.join("a.jpg")),
+            (dir.join("c.jpg"), dir.join("taken.jpg")),
+            (dir.join("c.xmp"), dir.join("taken.xmp")),
+        ];
+
+        let outcomes = rename_in_order(
+            &fs,
+            "1",
+            &moves,
+            &[0, 1, 2, 2],
+            &CaseFolding::default(),
+            |_| Ok(()),
+        );
+        assert_eq!(outcomes[0], MoveOutcome::Renamed);
+        assert_eq!(outcomes[1], MoveOutcome::Renamed);
+        assert_eq!(outcomes[2], MoveOutcome::RolledBack);
+        assert!(matches!(outcomes[3], MoveOutcome::Failed(_)));
+        assert_eq!(read(dir.join("a.jpg")), "b.jpg");
+        assert_eq!(read(dir.join("b.jpg")), "a.jpg");
+        assert_eq!(read(dir.join("c.jpg")), "c.jpg");
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 5);
+    }
+
+    // The first group swaps its name with the second one through a temporary
+    // name, then fails. Its old name is taken by the second group already.
+    #[test]
+    fn rename_in_order_does_not_roll_back_over_an_other_group() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_files(dir, &["a.jpg", "a.xmp", "b.jpg", "taken.xmp"]);
+        let fs = RealFileSystem::new(&RunType::Exec);
+        let moves = vec![
+            (dir.join("a.jpg"), dir.join("b.jpg")),
+            (dir.join("b.jpg"), dir.join("a.jpg")),
+            (dir.join("a.xmp"), dir.join("taken.xmp")),
+        ];
+
+        let outcomes = rename_in_order(
+            &fs,
+            "1",
+            &moves,
+            &[0, 1, 0],
+            &CaseFolding::default(),
+            |_| Ok(()),
+        );
+        assert!(matches!(outcomes[0], MoveOutcome::RollbackFailed(_)));
+        assert_eq!(outcomes[1], MoveOutcome::Renamed);
+        assert!(matches!(outcomes[2], MoveOutcome::Failed(_)));
+        assert_eq!(read(dir.join("a.jpg")), "b.jpg");
+        // The file of the first group is left with the temporary name.
+        let names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|x| read(x.unwrap().path()))
+            .collect::<HashSet<_>>();
+        assert_eq!(names.len(), 4);
+        assert!(names.contains("a.jpg"));
+    }
+
+    // The folder of the second group can't be made, a file has its name.
+    #[test]
+    fn rename_in_order_makes_the_folders_of_the_groups() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        write_files(dir, &["a.jpg", "b.jpg", "c.jpg", "taken"]);
+        let fs = RealFileSystem::new(&RunType::Exec);
+        let moves = vec![
+            (dir.join("a.jpg"), dir.join("2024/a.jpg")),
+            (dir.join("b.jpg"), dir.join("taken/b.jpg")),
+            (dir.join("c.jpg"), dir.join("2025/c.jpg")),
+        ];
+
+        let outcomes = rename_in_order(
+            &fs,
+            "1",
+            &moves,
+            &[0, 1, 2],
+            &CaseFolding::default(),
+            |group| match group {
+                2 => Err("busy".to_string()),
+                _ => Ok(()),
+            },
+        );
+        assert_eq!(outcomes[0], MoveOutcome::Renamed);
+        assert!(matches!(outcomes[1], MoveOutcome::Failed(_)));
+        assert_eq!(read(dir.join("2024/a.jpg")), "a.jpg");
+        assert_eq!(read(dir.join("b.jpg")), "b.jpg");
+        // The busy group gets no folder.
+        assert!(!dir.join("2025").exists());
+    }
+
+    #[test]
+    fn rename_in_order_leaves_the_busy_groups_alone() {
+        let fs = MockFileSystem::new();
+        let moves = moves(&[("/a/1", "/a/2"), ("/a/3", "/a/4"), ("/a/3.xmp", "/a/4.xmp")]);
+
+        let outcomes = rename_in_order(
+            &fs,
+            "1",
+            &moves,
+            &[0, 1, 1],
+            &CaseFolding::default(),
+            |group| match group {
+                1 => Err("busy".to_string()),
+                _ => Ok(()),
+            },
+        );
+        assert_eq!(
+            outcomes,
+            vec![
+                MoveOutcome::Renamed,
+                MoveOutcome::Busy("busy".to_string()),
+                MoveOutcome::Busy("busy".to_string()),
+            ]
+        );
+        assert_eq!(fs.renamed_files.borrow().len(), 1);
+    }
+
+    #[test]
+    fn planner_collects_the_renames() {
+        let planner = BatchPlanner::new();
+        planner
+            .rename(Path::new("/a/a.jpg"), Path::new("/a/b.jpg"))
+            .unwrap();
+
+        assert!(planner.exists(Path::new("/a/b.jpg")));
+        assert!(!planner.exists(Path::new("/a/a.jpg")));
+        assert_eq!(
+            planner.moves(),
+            vec![(PathBuf::from("/a/a.jpg"), PathBuf::from("/a/b.jpg"))]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Why a file should not be renamed right now.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Busy {
+    // An other app holds a lock on it.
+    Locked,
+    // The processes that have it open.
+    Open(Vec<u32>),
+    // Changed a moment ago, a sync client may still be writing it.
+    Modified(Duration),
+}
+
+impl std::fmt::Display for Busy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Busy::Locked => write!(f, "locked by an other app"),
+            Busy::Open(pids) => {
+                let pids = pids.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                write!(f, "open in an other process (pid {})", pids.join(", "))
+            }
+            Busy::Modified(ago) => write!(
+                f,
+                "modified {}s ago, it may still be written",
+                ago.as_secs()
+            ),
+        }
+    }
+}
+
+// The lock is held as long as this lives, the file is renamed meanwhile.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: Option<std::fs::File>,
+}
+
+// Takes an advisory lock (`flock` on unix) on the file. Only the apps that
+// lock the files themselves see it. When the file can't be opened or the
+// file system has no locks, the rename goes on without one.
+pub fn lock(path: &Path) -> Result<FileLock, Busy> {
+    let Ok(file) = std::fs::File::open(path) else {
+        return Ok(FileLock { _file: None });
+    };
+    match file.try_lock() {
+        Ok(_) => Ok(FileLock { _file: Some(file) }),
+        Err(std::fs::TryLockError::WouldBlock) => Err(Busy::Locked),
+        Err(std::fs::TryLockError::Error(_)) => Ok(FileLock { _file: None }),
+    }
+}
+
+// How long ago the file was changed. A date in the future (a wrong clock)
+// is not counted as recent.
+pub fn modified_ago(path: &Path) -> Option<Duration> {
+    let modified = std::fs::metadata(path).and_then(|x| x.modified()).ok()?;
+    std::time::SystemTime::now().duration_since(modified).ok()
+}
+
+// The processes, other than this one, that have the files open. Only on
+// Linux and only the processes we can look into (usually our own user's).
+pub fn open_by(paths: &[PathBuf]) -> HashMap<PathBuf, Vec<u32>> {
+    let mut result: HashMap<PathBuf, Vec<u32>> = HashMap::new();
+    if !cfg!(target_os = "linux") || paths.is_empty() {
+        return result;
+    }
+    let wanted = paths
+        .iter()
+        .filter_map(|x| std::fs::canonicalize(x).ok().map(|c| (c, x.clone())))
+        .collect::<HashMap<_, _>>();
+    let Ok(procs) = std::fs::read_dir("/proc") else {
+        return result;
+    };
+
+    let own = std::process::id();
+    for entry in procs.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if pid == own {
+            continue;
+        }
+        let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            if let Some(path) = std::fs::read_link(fd.path())
+                .ok()
+                .and_then(|x| wanted.get(&x))
+            {
+                let pids = result.entry(path.clone()).or_default();
+                if !pids.contains(&pid) {
+                    pids.push(pid);
+                }
+            }
+        }
+    }
+    result
+}
+
+#[derive(Debug, Clone)]
+pub struct BusyCheck {
+    // The files changed more recently than this are left alone.
+    pub settle: Duration,
+    // From `open_by`, looked up once for all the files.
+    pub open: HashMap<PathBuf, Vec<u32>>,
+}
+
+impl BusyCheck {
+    pub fn new(settle: Duration, paths: &[PathBuf]) -> Self {
+        Self {
+            settle,
+            open: open_by(paths),
+        }
+    }
+
+    // Locks the files of a group, or tells why one of them is busy.
+    pub fn lock_all(&self, paths: &[PathBuf]) -> Result<Vec<FileLock>, (PathBuf, Busy)> {
+        let mut locks = vec![];
+        for path in paths {
+            if let Some(pids) = self.open.get(path) {
+                return Err((path.clone(), Busy::Open(pids.clone())));
+            }
+            if let Some(ago) = modified_ago(path).filter(|x| *x < self.settle) {
+                return Err((path.clone(), Busy::Modified(ago)));
+            }
+            locks.push(lock(path).map_err(|err| (path.clone(), err))?);
+        }
+        Ok(locks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn a_locked_file_is_busy() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("a.jpg");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let held = lock(&path).unwrap();
+        assert_eq!(lock(&path).unwrap_err(), Busy::Locked);
+        drop(held);
+        assert!(lock(&path).is_ok());
+    }
+
+    #[test]
+    fn a_file_modified_a_moment_ago_is_busy() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("a.jpg");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let check = BusyCheck {
+            settle: Duration::from_secs(60),
+            open: HashMap::new(),
+        };
+        let err = check.lock_all(std::slice::from_ref(&path)).unwrap_err();
+        assert!(matches!(err.1, Busy::Modified(_)));
+
+        let check = BusyCheck {
+            settle: Duration::ZERO,
+            ..check
+        };
+        assert_eq!(check.lock_all(&[path]).unwrap().len(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn open_by_finds_the_other_processes() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("a.jpg");
+        std::fs::write(&path, b"abc").unwrap();
+        let mut child = std::process::Command::new("sleep")
+            .arg("5")
+            .stdin(std::fs::File::open(&path).unwrap())
+            .spawn()
+            .unwrap();
+
+        let open = open_by(std::slice::from_ref(&path));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(open.get(&path), Some(&vec![child.id()]));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+// The directories on a file system that folds the case of the names (exFAT
+// cards, the macOS and Windows shares), where `IMG_1.JPG` and `img_1.jpg` are
+// the same file. Everywhere else the names are compared byte for byte.
+#[derive(Debug, Default, Clone)]
+pub struct CaseFolding {
+    dirs: HashSet<PathBuf>,
+}
+
+impl CaseFolding {
+    pub fn new(dirs: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            dirs: dirs.into_iter().collect(),
+        }
+    }
+
+    // Probes every directory once.
+    pub fn probe<'a>(dirs: impl IntoIterator<Item = &'a Path>) -> Self {
+        let dirs = dirs.into_iter().collect::<HashSet<_>>();
+        Self::new(
+            dirs.into_iter()
+                .filter(|x| folds_case(x))
+                .map(|x| x.to_path_buf()),
+        )
+    }
+
+    // For the dry runs, nothing is written. Only the names already in the
+    // directories are looked at.
+    pub fn inspect<'a>(dirs: impl IntoIterator<Item = &'a Path>) -> Self {
+        let dirs = dirs.into_iter().collect::<HashSet<_>>();
+        Self::new(
+            dirs.into_iter()
+                .filter(|x| folds_case_of_names(x))
+                .map(|x| x.to_path_buf()),
+        )
+    }
+
+    pub fn folds(&self, dir: &Path) -> bool {
+        self.dirs.contains(dir)
+    }
+
+    // The path as the file system compares it.
+    pub fn key(&self, path: &Path) -> PathBuf {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        match path.file_name().and_then(|x| x.to_str()) {
+            Some(name) if self.folds(dir) => path.with_file_name(name.to_lowercase()),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    // Only the case of the name changes, the file system sees the same name.
+    pub fn same_name(&self, a: &Path, b: &Path) -> bool {
+        a != b && self.key(a) == self.key(b)
+    }
+}
+
+// Creates a temporary file with a lower case name and looks for it with the
+// upper case one. A directory we can't write to is taken as case sensitive.
+pub fn folds_case(dir: &Path) -> bool {
+    let Ok(file) = tempfile::Builder::new()
+        .prefix(".eximd-case-")
+        .tempfile_in(dir)
+    else {
+        return false;
+    };
+    let Some(name) = file.path().file_name().and_then(|x| x.to_str()) else {
+        return false;
+    };
+    same_file(file.path(), &dir.join(name.to_uppercase()))
+}
+
+// Looks for the first name with a case in the directory under its other
+// case. An empty directory (or one that isn't there yet) is taken as case
+// sensitive.
+pub fn folds_case_of_names(dir: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    let other_case = entries
+        .filter_map(Result::ok)
+        .filter_map(|x| x.file_name().into_string().ok())
+        .find_map(|name| {
+            let swapped = name
+                .chars()
+                .map(|x| {
+                    if x.is_lowercase() {
+                        x.to_uppercase().to_string()
+                    } else {
+                        x.to_lowercase().to_string()
+                    }
+                })
+                .collect::<String>();
+            (swapped != name).then_some((name, swapped))
+        });
+    match other_case {
+        Some((name, swapped)) => same_file(&dir.join(name), &dir.join(swapped)),
+        None => false,
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        #[cfg(unix)]
+        (Ok(a), Ok(b)) => {
+            use std::os::unix::fs::MetadataExt;
+            a.dev() == b.dev() && a.ino() == b.ino()
+        }
+        #[cfg(not(unix))]
+        (Ok(_), Ok(_)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys_are_folded_only_in_the_folding_directories() {
+        let case = CaseFolding::new([PathBuf::from("/sd")]);
+
+        assert_eq!(
+            case.key(Path::new("/sd/IMG_1.JPG")),
+            PathBuf::from("/sd/img_1.jpg")
+        );
+        assert_eq!(
+            case.key(Path::new("/home/IMG_1.JPG")),
+            PathBuf::from("/home/IMG_1.JPG")
+        );
+        assert!(case.same_name(Path::new("/sd/a.JPG"), Path::new("/sd/a.jpg")));
+        assert!(!case.same_name(Path::new("/home/a.JPG"), Path::new("/home/a.jpg")));
+    }
+
+    // The temporary directories of the tests are case sensitive on Linux.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn probe_a_case_sensitive_directory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        assert!(!folds_case(temp_dir.path()));
+        assert!(!CaseFolding::probe([temp_dir.path()]).folds(temp_dir.path()));
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn inspect_a_case_sensitive_directory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+
+        assert!(!folds_case_of_names(dir));
+        std::fs::write(dir.join("IMG_1.jpg"), "").unwrap();
+        assert!(!CaseFolding::inspect([dir]).folds(dir));
+        // The other case is an other file.
+        std::fs::write(dir.join("img_1.JPG"), "").unwrap();
+        assert!(!folds_case_of_names(dir));
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+    }
+}
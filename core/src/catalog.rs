@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+// What we remember about a file to tell if it changed since the last scan
+// without reading it again. The size and the modification time catch the
+// edits, the inode catches a file that was replaced by another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    // Nanoseconds since the unix epoch.
+    pub mtime: i64,
+    pub inode: Option<u64>,
+}
+
+impl FileStamp {
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let meta =
+            std::fs::metadata(path).map_err(|err| format!("Error: {}: {}", path.display(), err))?;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |x| x.as_nanos() as i64);
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(meta.ino())
+        };
+        #[cfg(not(unix))]
+        let inode = None;
+
+        Ok(Self {
+            size: meta.len(),
+            mtime,
+            inode,
+        })
+    }
+
+    // The inode is only compared when both sides have one, the catalogs
+    // moved between systems don't.
+    pub fn same_as(&self, other: &FileStamp) -> bool {
+        self.size == other.size
+            && self.mtime == other.mtime
+            && match (self.inode, other.inode) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ScanDiff {
+    pub added: Vec<PathBuf>,
+    pub changed: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub unchanged: Vec<PathBuf>,
+}
+
+// Compares the files found on the disk with the ones in the catalog. Only
+// the known files under `root` can be removed, the catalog can hold other
+// directories as well. A file the scan did not see is only removed when it
+// is gone from the disk, narrower filters don't remove anything. A known
+// file without a stamp (collected before we kept them) is always changed.
+pub fn diff(
+    root: &Path,
+    known: &HashMap<PathBuf, Option<FileStamp>>,
+    scanned: &[(PathBuf, FileStamp)],
+    exists: impl Fn(&Path) -> bool,
+) -> ScanDiff {
+    let mut result = ScanDiff::default();
+    let mut seen = HashSet::new();
+
+    for (path, stamp) in scanned {
+        seen.insert(path);
+        match known.get(path) {
+            None => result.added.push(path.clone()),
+            Some(Some(known)) if known.same_as(stamp) => result.unchanged.push(path.clone()),
+            Some(_) => result.changed.push(path.clone()),
+        }
+    }
+
+    result.removed = known
+        .keys()
+        .filter(|x| x.starts_with(root) && !seen.contains(x) && !exists(x))
+        .cloned()
+        .collect();
+    result.removed.sort();
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stamp(size: u64, mtime: i64) -> FileStamp {
+        FileStamp {
+            size,
+            mtime,
+            inode: Some(1),
+        }
+    }
+
+    #[test]
+    fn diff_sorts_the_files_by_what_happened_to_them() {
+        let known = HashMap::from([
+            (PathBuf::from("/a/same.jpg"), Some(stamp(10, 1))),
+            (PathBuf::from("/a/edited.jpg"), Some(stamp(10, 1))),
+            (PathBuf::from("/a/old.jpg"), None),
+            (PathBuf::from("/a/gone.jpg"), Some(stamp(10, 1))),
+            (PathBuf::from("/b/other.jpg"), Some(stamp(10, 1))),
+            // Still on the disk, an `--exclude` left it out of the scan.
+            (PathBuf::from("/a/excluded.jpg"), Some(stamp(10, 1))),
+        ]);
+        let scanned = vec![
+            (PathBuf::from("/a/same.jpg"), stamp(10, 1)),
+            (PathBuf::from("/a/edited.jpg"), stamp(10, 2)),
+            (PathBuf::from("/a/old.jpg"), stamp(10, 1)),
+            (PathBuf::from("/a/new.jpg"), stamp(10, 1)),
+        ];
+
+        let result = diff(Path::new("/a"), &known, &scanned, |x| {
+            x == Path::new("/a/excluded.jpg")
+        });
+        assert_eq!(
+            result,
+            ScanDiff {
+                added: vec![PathBuf::from("/a/new.jpg")],
+                changed: vec![PathBuf::from("/a/edited.jpg"), PathBuf::from("/a/old.jpg")],
+                removed: vec![PathBuf::from("/a/gone.jpg")],
+                unchanged: vec![PathBuf::from("/a/same.jpg")],
+            }
+        );
+    }
+
+    #[test]
+    fn a_replaced_file_is_changed() {
+        let before = stamp(10, 1);
+        let replaced = FileStamp {
+            inode: Some(2),
+            ..before
+        };
+        let no_inode = FileStamp {
+            inode: None,
+            ..before
+        };
+
+        assert!(!before.same_as(&replaced));
+        assert!(before.same_as(&no_inode));
+    }
+
+    #[test]
+    fn stamp_of_a_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("a.jpg");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let stamp = FileStamp::from_path(&path).unwrap();
+        assert_eq!(stamp.size, 3);
+        assert!(stamp.same_as(&FileStamp::from_path(&path).unwrap()));
+        assert!(FileStamp::from_path(&temp_dir.path().join("b.jpg")).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 #[derive(PartialEq, Copy, Clone)]
@@ -6,8 +7,79 @@ pub enum RunType {
     Exec,
 }
 
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RenameOptions {
+    // Write the new file names in the NFC unicode form.
+    pub normalize_nfc: bool,
+    // Rename the groups named with an older template (see `NameTemplate`) too.
+    pub migrate_old_names: bool,
+    // The case of the extensions in the new names, the sidecars included.
+    pub ext_case: ExtCase,
+    // The extensions written with an other spelling, `jpeg` -> `jpg`. The
+    // lookup ignores the case.
+    pub ext_aliases: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtCase {
+    // As the file has it.
+    #[default]
+    Keep,
+    Lower,
+    Upper,
+}
+
+impl std::str::FromStr for ExtCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(ExtCase::Keep),
+            "lower" => Ok(ExtCase::Lower),
+            "upper" => Ok(ExtCase::Upper),
+            _ => Err(format!("'{}' is not keep, lower or upper", s)),
+        }
+    }
+}
+
 pub trait FileSystem {
     fn rename(&self, prev: &Path, next: &Path) -> std::io::Result<()>;
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()>;
+
+    fn copy(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()>;
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()>;
+
+    fn exists(&self, path: &Path) -> bool {
+        std::fs::symlink_metadata(path).is_ok()
+    }
+
+    // Some file systems (APFS, exFAT, ...) resolve the different spellings
+    // of a name to the same file. This tells us whether it is the file itself.
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            match (std::fs::symlink_metadata(a), std::fs::symlink_metadata(b)) {
+                (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+                _ => false,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            }
+        }
+    }
 }
 
 pub struct RealFileSystem {
@@ -16,7 +88,7 @@ pub struct RealFileSystem {
 
 impl RealFileSystem {
     pub fn new(mode: &RunType) -> Self {
-        Self { mode: mode.clone() }
+        Self { mode: *mode }
     }
 }
 
@@ -27,21 +99,76 @@ impl FileSystem for RealFileSystem {
         }
         Ok(())
     }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+        if self.mode == RunType::Exec {
+            std::fs::hard_link(original, link)?;
+        }
+        Ok(())
+    }
+
+    // The copy is on the disk before the caller removes the original.
+    fn copy(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        if self.mode == RunType::Exec {
+            std::fs::copy(from, to)?;
+            std::fs::File::open(to)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        if self.mode == RunType::Exec {
+            std::fs::create_dir_all(path)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        if self.mode == RunType::Exec {
+            std::fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        if self.mode == RunType::Exec {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct MockFileSystem {
     pub renamed_files: std::cell::RefCell<Vec<(PathBuf, PathBuf)>>,
+    pub linked_files: std::cell::RefCell<Vec<(PathBuf, PathBuf)>>,
+    pub copied_files: std::cell::RefCell<Vec<(PathBuf, PathBuf)>>,
+    pub created_dirs: std::cell::RefCell<Vec<PathBuf>>,
+    pub written_files: std::cell::RefCell<Vec<(PathBuf, Vec<u8>)>>,
+    pub removed_files: std::cell::RefCell<Vec<PathBuf>>,
+    pub existing_files: std::cell::RefCell<Vec<PathBuf>>,
 }
 
 impl MockFileSystem {
     pub fn new() -> Self {
         Self {
             renamed_files: std::cell::RefCell::new(vec![]),
+            linked_files: std::cell::RefCell::new(vec![]),
+            copied_files: std::cell::RefCell::new(vec![]),
+            created_dirs: std::cell::RefCell::new(vec![]),
+            written_files: std::cell::RefCell::new(vec![]),
+            removed_files: std::cell::RefCell::new(vec![]),
+            existing_files: std::cell::RefCell::new(vec![]),
         }
     }
 }
 
+impl Default for MockFileSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileSystem for MockFileSystem {
     fn rename(&self, prev: &Path, next: &Path) -> std::io::Result<()> {
         self.renamed_files
@@ -49,4 +176,43 @@ impl FileSystem for MockFileSystem {
             .push((prev.to_path_buf(), next.to_path_buf()));
         Ok(())
     }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+        self.linked_files
+            .borrow_mut()
+            .push((original.to_path_buf(), link.to_path_buf()));
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.copied_files
+            .borrow_mut()
+            .push((from.to_path_buf(), to.to_path_buf()));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        self.created_dirs.borrow_mut().push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        self.written_files
+            .borrow_mut()
+            .push((path.to_path_buf(), contents.to_vec()));
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        self.removed_files.borrow_mut().push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.existing_files.borrow().iter().any(|x| x == path)
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        a == b
+    }
 }
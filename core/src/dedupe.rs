@@ -0,0 +1,748 @@
+use super::config::FileSystem;
+use super::utils;
+use chrono::NaiveDateTime;
+use std::ffi::OsString;
+use std::io::{BufRead, Write};
+use std::path::{Component, Path, PathBuf};
+
+// One copy from a set of duplicates, with what we know about it
+// to decide which one stays.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub size: u64,
+    // When the media was taken, from the metadata.
+    pub taken: Option<NaiveDateTime>,
+    pub modified: Option<NaiveDateTime>,
+    // How many of the metadata fields are filled in. More is better.
+    pub metadata_fields: usize,
+}
+
+impl Candidate {
+    // For the callers without a catalog: only what the file system knows.
+    pub fn from_path(path: &Path) -> Self {
+        let meta = std::fs::metadata(path).ok();
+        Self {
+            path: path.to_path_buf(),
+            size: meta.as_ref().map_or(0, |x| x.len()),
+            taken: None,
+            modified: meta
+                .and_then(|x| x.modified().ok())
+                .map(|x| chrono::DateTime::<chrono::Local>::from(x).naive_local()),
+            metadata_fields: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeepPolicy {
+    Oldest,
+    Largest,
+    BestMetadata,
+    // The copy inside the folder stays. If there is none (or more),
+    // the oldest one wins.
+    Folder(PathBuf),
+}
+
+fn oldest_key(file: &Candidate) -> (bool, Option<NaiveDateTime>) {
+    let date = file.taken.or(file.modified);
+    (date.is_none(), date)
+}
+
+// Returns the index of the file to keep. The ties go to the shortest path,
+// so the same set always gives the same answer.
+pub fn choose_keeper(files: &[Candidate], policy: &KeepPolicy) -> Option<usize> {
+    let tie_break = |x: &Candidate| (x.path.components().count(), x.path.clone());
+    (0..files.len()).min_by(|a, b| {
+        let (a, b) = (&files[*a], &files[*b]);
+        let order = match policy {
+            KeepPolicy::Oldest => oldest_key(a).cmp(&oldest_key(b)),
+            KeepPolicy::Largest => b.size.cmp(&a.size),
+            KeepPolicy::BestMetadata => b.metadata_fields.cmp(&a.metadata_fields),
+            KeepPolicy::Folder(folder) => (!a.path.starts_with(folder), oldest_key(a))
+                .cmp(&(!b.path.starts_with(folder), oldest_key(b))),
+        };
+        order.then_with(|| tie_break(a).cmp(&tie_break(b)))
+    })
+}
+
+// The files next to the given one with the same name and a non-primary
+// extension (.xmp, .aae, ...). They are useless without the media file,
+// so they go wherever it goes.
+pub fn find_sidecars(path: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(stem)) = (path.parent(), path.file_stem()) else {
+        return vec![];
+    };
+    let stem = utils::nfc(&stem.to_string_lossy());
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut sidecars = entries
+        .filter_map(Result::ok)
+        .map(|x| x.path())
+        .filter(|x| x != path && x.is_file())
+        .filter(|x| {
+            let same_stem = x
+                .file_stem()
+                .is_some_and(|x| utils::nfc(&x.to_string_lossy()) == stem);
+            let ext = x.extension().and_then(|x| x.to_str()).unwrap_or_default();
+            same_stem && !utils::is_primary_ext(ext)
+        })
+        .collect::<Vec<_>>();
+    sidecars.sort();
+    sidecars
+}
+
+// Everything we did to a file, with enough details to put it back.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum UndoEntry {
+    // The file was moved to the trash or to the quarantine.
+    Moved {
+        from: PathBuf,
+        to: PathBuf,
+        trash_info: Option<PathBuf>,
+    },
+    // The file was replaced with a hardlink to the kept copy.
+    Linked {
+        path: PathBuf,
+        original: PathBuf,
+    },
+}
+
+// The freedesktop.org trash. The files go to `files/` and the `info/`
+// gets a `.trashinfo` file with the original path, so the file managers
+// can restore them too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trash {
+    dir: PathBuf,
+}
+
+impl Trash {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    // $XDG_DATA_HOME/Trash, which defaults to ~/.local/share/Trash.
+    pub fn from_env() -> Result<Self, String> {
+        let data_home = match std::env::var_os("XDG_DATA_HOME").filter(|x| !x.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::var_os("HOME")
+                .map(|x| PathBuf::from(x).join(".local/share"))
+                .ok_or("Error: neither XDG_DATA_HOME nor HOME is set")?,
+        };
+        Ok(Self::new(&data_home.join("Trash")))
+    }
+
+    fn put<F: FileSystem>(&self, fs: &F, path: &Path) -> Result<UndoEntry, String> {
+        let files_dir = self.dir.join("files");
+        let info_dir = self.dir.join("info");
+        fs.create_dir_all(&files_dir)
+            .and_then(|_| fs.create_dir_all(&info_dir))
+            .map_err(|err| format!("Error: creating the trash: {}", err))?;
+
+        let name = path.file_name().ok_or("Error: the path has no file name")?;
+        let (to, info) = (1..)
+            .map(|n| {
+                let name = numbered_name(name, n);
+                let mut info_name = name.clone();
+                info_name.push(".trashinfo");
+                (files_dir.join(name), info_dir.join(info_name))
+            })
+            .find(|(to, info)| !fs.exists(to) && !fs.exists(info))
+            .expect("to find a free name in the trash");
+
+        let contents = format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            percent_encode(&absolute(path)),
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        fs.write(&info, contents.as_bytes())
+            .map_err(|err| err.to_string())?;
+        if let Err(err) = move_file(fs, path, &to) {
+            let _ = fs.remove_file(&info);
+            return Err(err.to_string());
+        }
+        Ok(UndoEntry::Moved {
+            from: path.to_path_buf(),
+            to,
+            trash_info: Some(info),
+        })
+    }
+}
+
+// A rename can't move a file to another mount (the library on a NAS or a
+// card, the trash in the home), there the file is copied and removed. The
+// copy gets a temporary name next to the target until it is complete, so
+// the target is never half written.
+fn move_file<F: FileSystem>(fs: &F, from: &Path, to: &Path) -> std::io::Result<()> {
+    match fs.rename(from, to) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            let mut name = OsString::from(".");
+            name.push(to.file_name().unwrap_or_default());
+            name.push(".eximd-copy");
+            let tmp = to.with_file_name(name);
+            if let Err(err) = fs.copy(from, &tmp).and_then(|_| fs.rename(&tmp, to)) {
+                let _ = fs.remove_file(&tmp);
+                return Err(err);
+            }
+            // Both copies would stay, the move did not happen.
+            if let Err(err) = fs.remove_file(from) {
+                let _ = fs.remove_file(to);
+                return Err(err);
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+// "IMG_1.JPG", "IMG_1 (2).JPG", "IMG_1 (3).JPG", ...
+fn numbered_name(name: &std::ffi::OsStr, n: usize) -> OsString {
+    if n == 1 {
+        return name.to_owned();
+    }
+    let path = Path::new(name);
+    let mut next = path.file_stem().unwrap_or(name).to_owned();
+    next.push(format!(" ({})", n));
+    if let Some(ext) = path.extension() {
+        next.push(".");
+        next.push(ext);
+    }
+    next
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The trash spec wants the path escaped like in a URL.
+fn percent_encode(path: &Path) -> String {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().as_bytes().to_vec();
+
+    bytes
+        .iter()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (*x as char).to_string()
+            }
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DedupeAction {
+    Trash(Trash),
+    // The copies become hardlinks of the kept file. The paths stay,
+    // so the sidecars stay next to them too.
+    Hardlink,
+    // The copies are moved under the `dir`, keeping their path relative
+    // to the `root` (or the whole path when they are not under it).
+    Quarantine { dir: PathBuf, root: PathBuf },
+}
+
+impl DedupeAction {
+    fn moves_files(&self) -> bool {
+        !matches!(self, DedupeAction::Hardlink)
+    }
+
+    fn apply<F: FileSystem>(
+        &self,
+        fs: &F,
+        path: &Path,
+        keeper: &Path,
+    ) -> Result<UndoEntry, String> {
+        match self {
+            DedupeAction::Trash(trash) => trash.put(fs, path),
+            DedupeAction::Hardlink => {
+                let mut name = OsString::from(".");
+                name.push(path.file_name().ok_or("Error: the path has no file name")?);
+                name.push(".eximd-link");
+                let tmp = path.with_file_name(name);
+                // Linking next to the file and renaming it over the copy
+                // means the path is never missing, even if we crash.
+                fs.hard_link(keeper, &tmp).map_err(|err| err.to_string())?;
+                if let Err(err) = fs.rename(&tmp, path) {
+                    let _ = fs.remove_file(&tmp);
+                    return Err(err.to_string());
+                }
+                Ok(UndoEntry::Linked {
+                    path: path.to_path_buf(),
+                    original: keeper.to_path_buf(),
+                })
+            }
+            DedupeAction::Quarantine { dir, root } => {
+                let relative = match path.strip_prefix(root) {
+                    Ok(relative) => relative.to_path_buf(),
+                    Err(_) => path
+                        .components()
+                        .filter(|x| matches!(x, Component::Normal(_)))
+                        .collect(),
+                };
+                let to = dir.join(relative);
+                if fs.exists(&to) {
+                    return Err(format!("'{}' already exists", to.display()));
+                }
+                if let Some(parent) = to.parent() {
+                    fs.create_dir_all(parent).map_err(|err| err.to_string())?;
+                }
+                move_file(fs, path, &to).map_err(|err| err.to_string())?;
+                Ok(UndoEntry::Moved {
+                    from: path.to_path_buf(),
+                    to,
+                    trash_info: None,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DedupeOutcome {
+    pub path: PathBuf,
+    // What was done to the copy and its sidecars, even when one of them failed.
+    pub entries: Vec<UndoEntry>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetResolution {
+    pub kept: PathBuf,
+    pub outcomes: Vec<DedupeOutcome>,
+}
+
+// Keeps one file of the set and applies the action to the rest. Every
+// change goes to `record` as soon as it is done, so the undo log has it
+// even when a later step fails.
+pub fn resolve_set<F, R>(
+    fs: &F,
+    set: &[Candidate],
+    policy: &KeepPolicy,
+    action: &DedupeAction,
+    record: &mut R,
+) -> Option<SetResolution>
+where
+    F: FileSystem,
+    R: FnMut(&UndoEntry),
+{
+    let keeper = &set[choose_keeper(set, policy)?];
+    let mut outcomes = vec![];
+
+    for file in set.iter().filter(|x| x.path != keeper.path) {
+        let mut outcome = DedupeOutcome {
+            path: file.path.clone(),
+            entries: vec![],
+            error: None,
+        };
+        if fs.same_file(&file.path, &keeper.path) {
+            outcome.error = Some("already the same file as the kept one".to_string());
+            outcomes.push(outcome);
+            continue;
+        }
+
+        let mut paths = vec![file.path.clone()];
+        if action.moves_files() {
+            paths.extend(find_sidecars(&file.path));
+        }
+        for path in paths {
+            match action.apply(fs, &path, &keeper.path) {
+                Ok(entry) => {
+                    record(&entry);
+                    outcome.entries.push(entry);
+                }
+                Err(err) => {
+                    outcome.error = Some(format!("{}: {}", path.display(), err));
+                    break;
+                }
+            }
+        }
+        outcomes.push(outcome);
+    }
+
+    Some(SetResolution {
+        kept: keeper.path.clone(),
+        outcomes,
+    })
+}
+
+// A JSON line per change. It is appended and flushed right after every
+// change, so a crash in the middle still leaves a log we can undo.
+pub struct UndoLog {
+    file: std::fs::File,
+}
+
+impl UndoLog {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Error: opening the undo log: {}", err))?;
+        Ok(Self { file })
+    }
+
+    pub fn append(&mut self, entry: &UndoEntry) -> Result<(), String> {
+        let line = serde_json::to_string(entry).map_err(|err| err.to_string())?;
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| format!("Error: writing the undo log: {}", err))
+    }
+
+    pub fn read(path: &Path) -> Result<Vec<UndoEntry>, String> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| format!("Error: opening the undo log: {}", err))?;
+        std::io::BufReader::new(file)
+            .lines()
+            .map(|x| x.map_err(|err| err.to_string()))
+            .filter(|x| x.as_ref().map_or(true, |x| !x.trim().is_empty()))
+            .map(|x| x.and_then(|x| serde_json::from_str(&x).map_err(|err| err.to_string())))
+            .collect()
+    }
+
+    // Writes the log again with the entries given, they are what is left to
+    // undo. Without any, the log is removed.
+    pub fn keep_only(path: &Path, entries: &[UndoEntry]) -> Result<(), String> {
+        if entries.is_empty() {
+            return std::fs::remove_file(path)
+                .map_err(|err| format!("Error: removing the undo log: {}", err));
+        }
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry).map_err(|err| err.to_string())?);
+            lines.push('\n');
+        }
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let tmp = path.with_file_name(name);
+        std::fs::write(&tmp, lines)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|err| format!("Error: writing the undo log: {}", err))
+    }
+}
+
+pub type UndoResult = (UndoEntry, Result<(), String>);
+
+// Puts back the files of the log. Only the entries that failed stay in it,
+// so the next undo doesn't replay the ones already done. Not for the dry
+// runs, the log would be emptied without anything put back.
+pub fn undo_log<F: FileSystem>(fs: &F, path: &Path) -> Result<Vec<UndoResult>, String> {
+    let results = undo(fs, &UndoLog::read(path)?);
+    let left = results
+        .iter()
+        .rev()
+        .filter(|x| x.1.is_err())
+        .map(|x| x.0.clone())
+        .collect::<Vec<_>>();
+    UndoLog::keep_only(path, &left)?;
+    Ok(results)
+}
+
+// Puts the files back, the last change first.
+pub fn undo<F: FileSystem>(fs: &F, entries: &[UndoEntry]) -> Vec<UndoResult> {
+    entries
+        .iter()
+        .rev()
+        .map(|entry| (entry.clone(), undo_entry(fs, entry)))
+        .collect()
+}
+
+fn undo_entry<F: FileSystem>(fs: &F, entry: &UndoEntry) -> Result<(), String> {
+    match entry {
+        UndoEntry::Moved {
+            from,
+            to,
+            trash_info,
+        } => {
+            if fs.exists(from) {
+                return Err(format!("'{}' already exists", from.display()));
+            }
+            if let Some(parent) = from.parent() {
+                fs.create_dir_all(parent).map_err(|err| err.to_string())?;
+            }
+            move_file(fs, to, from).map_err(|err| err.to_string())?;
+            if let Some(info) = trash_info {
+                fs.remove_file(info).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        }
+        // The content is the same, we only need the file to be on its own again.
+        UndoEntry::Linked { path, .. } => {
+            let mut name = OsString::from(".");
+            name.push(path.file_name().ok_or("Error: the path has no file name")?);
+            name.push(".eximd-unlink");
+            let tmp = path.with_file_name(name);
+            fs.copy(path, &tmp).map_err(|err| err.to_string())?;
+            fs.rename(&tmp, path).map_err(|err| err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{MockFileSystem, RealFileSystem, RunType};
+    use std::fs::File;
+    use tempfile::tempdir;
+
+    fn candidate(path: &str, size: u64, taken: Option<&str>, fields: usize) -> Candidate {
+        Candidate {
+            path: PathBuf::from(path),
+            size,
+            taken: taken.map(|x| NaiveDateTime::parse_from_str(x, "%Y-%m-%d %H:%M:%S").unwrap()),
+            modified: None,
+            metadata_fields: fields,
+        }
+    }
+
+    #[test]
+    fn choose_keeper_by_policy() {
+        let set = vec![
+            candidate("/a/deep/IMG_1.JPG", 10, Some("2021-01-01 10:00:00"), 2),
+            candidate("/b/IMG_1.JPG", 20, None, 5),
+            candidate("/c/IMG_1.JPG", 10, Some("2020-01-01 10:00:00"), 3),
+        ];
+
+        assert_eq!(choose_keeper(&set, &KeepPolicy::Oldest), Some(2));
+        assert_eq!(choose_keeper(&set, &KeepPolicy::Largest), Some(1));
+        assert_eq!(choose_keeper(&set, &KeepPolicy::BestMetadata), Some(1));
+        assert_eq!(
+            choose_keeper(&set, &KeepPolicy::Folder(PathBuf::from("/a"))),
+            Some(0)
+        );
+        assert_eq!(
+            choose_keeper(&set, &KeepPolicy::Folder(PathBuf::from("/none"))),
+            Some(2)
+        );
+        assert_eq!(choose_keeper(&[], &KeepPolicy::Oldest), None);
+    }
+
+    #[test]
+    fn quarantine_moves_the_copy_and_its_sidecars() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("photos");
+        std::fs::create_dir_all(root.join("b")).unwrap();
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        for name in ["a/IMG_1.JPG", "b/IMG_1.JPG", "b/IMG_1.xmp", "b/IMG_2.JPG"] {
+            File::create(root.join(name)).unwrap();
+        }
+        let quarantine = temp_dir.path().join("quarantine");
+        let fs = RealFileSystem::new(&RunType::Exec);
+        let set = vec![
+            candidate(root.join("a/IMG_1.JPG").to_str().unwrap(), 1, None, 0),
+            candidate(root.join("b/IMG_1.JPG").to_str().unwrap(), 1, None, 0),
+        ];
+        let action = DedupeAction::Quarantine {
+            dir: quarantine.clone(),
+            root: root.clone(),
+        };
+        let mut log = vec![];
+
+        let resolution = resolve_set(&fs, &set, &KeepPolicy::Oldest, &action, &mut |x| {
+            log.push(x.clone())
+        })
+        .unwrap();
+
+        assert_eq!(resolution.kept, root.join("a/IMG_1.JPG"));
+        assert_eq!(resolution.outcomes[0].error, None);
+        assert_eq!(log.len(), 2);
+        assert!(quarantine.join("b/IMG_1.JPG").exists());
+        assert!(quarantine.join("b/IMG_1.xmp").exists());
+        assert!(root.join("b/IMG_2.JPG").exists());
+        assert!(!root.join("b/IMG_1.JPG").exists());
+
+        for (_, result) in undo(&fs, &log) {
+            assert_eq!(result, Ok(()));
+        }
+        assert!(root.join("b/IMG_1.JPG").exists());
+        assert!(root.join("b/IMG_1.xmp").exists());
+    }
+
+    // The sidecar can't go back the first time, its name is taken. The second
+    // undo only puts back the sidecar.
+    #[test]
+    fn undo_twice_only_replays_what_failed() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("photos");
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["IMG_1.JPG", "IMG_2.JPG", "IMG_2.xmp"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        let fs = RealFileSystem::new(&RunType::Exec);
+        let set = vec![
+            candidate(root.join("IMG_1.JPG").to_str().unwrap(), 1, None, 0),
+            candidate(root.join("IMG_2.JPG").to_str().unwrap(), 1, None, 0),
+        ];
+        let action = DedupeAction::Quarantine {
+            dir: temp_dir.path().join("quarantine"),
+            root: root.clone(),
+        };
+        let log_path = temp_dir.path().join("undo.jsonl");
+        let mut log = UndoLog::create(&log_path).unwrap();
+        resolve_set(&fs, &set, &KeepPolicy::Oldest, &action, &mut |x| {
+            log.append(x).unwrap()
+        })
+        .unwrap();
+        std::fs::write(root.join("IMG_2.xmp"), "new").unwrap();
+
+        let results = undo_log(&fs, &log_path).unwrap();
+        assert_eq!(results.iter().filter(|x| x.1.is_err()).count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(root.join("IMG_2.JPG")).unwrap(),
+            "IMG_2.JPG"
+        );
+        assert_eq!(UndoLog::read(&log_path).unwrap().len(), 1);
+
+        std::fs::remove_file(root.join("IMG_2.xmp")).unwrap();
+        let results = undo_log(&fs, &log_path).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1, Ok(()));
+        assert_eq!(
+            std::fs::read_to_string(root.join("IMG_2.xmp")).unwrap(),
+            "IMG_2.xmp"
+        );
+        assert!(!log_path.exists());
+        assert!(undo_log(&fs, &log_path).is_err());
+    }
+
+    // The trash is on another mount, the renames across it fail with EXDEV.
+    struct OtherMount {
+        fs: RealFileSystem,
+        mount: PathBuf,
+    }
+
+    impl FileSystem for OtherMount {
+        fn rename(&self, prev: &Path, next: &Path) -> std::io::Result<()> {
+            if prev.starts_with(&self.mount) != next.starts_with(&self.mount) {
+                return Err(std::io::ErrorKind::CrossesDevices.into());
+            }
+            self.fs.rename(prev, next)
+        }
+
+        fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+            self.fs.hard_link(original, link)
+        }
+
+        fn copy(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            self.fs.copy(from, to)
+        }
+
+        fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+            self.fs.create_dir_all(path)
+        }
+
+        fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+            self.fs.write(path, contents)
+        }
+
+        fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+            self.fs.remove_file(path)
+        }
+    }
+
+    #[test]
+    fn trash_on_another_mount_copies_the_file() {
+        let temp_dir = tempdir().unwrap();
+        let trash_dir = temp_dir.path().join("Trash");
+        let path = temp_dir.path().join("nas/IMG_1.JPG");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"photo").unwrap();
+        let fs = OtherMount {
+            fs: RealFileSystem::new(&RunType::Exec),
+            mount: trash_dir.clone(),
+        };
+
+        let entry = Trash::new(&trash_dir).put(&fs, &path).unwrap();
+
+        assert!(!path.exists());
+        let to = trash_dir.join("files/IMG_1.JPG");
+        assert_eq!(std::fs::read(&to).unwrap(), b"photo");
+        assert_eq!(
+            std::fs::read_dir(trash_dir.join("files")).unwrap().count(),
+            1
+        );
+
+        for (_, result) in undo(&fs, &[entry]) {
+            assert_eq!(result, Ok(()));
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"photo");
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn trash_writes_the_trash_info() {
+        let temp_dir = tempdir().unwrap();
+        let trash_dir = temp_dir.path().join("Trash");
+        let fs = MockFileSystem::new();
+        fs.existing_files
+            .borrow_mut()
+            .push(trash_dir.join("files/IMG 1.JPG"));
+        let trash = Trash::new(&trash_dir);
+
+        let entry = trash.put(&fs, Path::new("/photos/IMG 1.JPG")).unwrap();
+
+        assert_eq!(
+            entry,
+            UndoEntry::Moved {
+                from: PathBuf::from("/photos/IMG 1.JPG"),
+                to: trash_dir.join("files/IMG 1 (2).JPG"),
+                trash_info: Some(trash_dir.join("info/IMG 1 (2).JPG.trashinfo")),
+            }
+        );
+        let (path, contents) = fs.written_files.borrow()[0].clone();
+        assert_eq!(path, trash_dir.join("info/IMG 1 (2).JPG.trashinfo"));
+        let contents = String::from_utf8(contents).unwrap();
+        assert!(contents.starts_with("[Trash Info]\nPath=/photos/IMG%201.JPG\nDeletionDate="));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlink_replaces_the_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = tempdir().unwrap();
+        let a = temp_dir.path().join("a.jpg");
+        let b = temp_dir.path().join("b.jpg");
+        std::fs::write(&a, b"same").unwrap();
+        std::fs::write(&b, b"same").unwrap();
+        let fs = RealFileSystem::new(&RunType::Exec);
+        let set = vec![
+            candidate(a.to_str().unwrap(), 4, None, 0),
+            candidate(b.to_str().unwrap(), 4, None, 0),
+        ];
+        let log_path = temp_dir.path().join("undo.jsonl");
+        let mut log = UndoLog::create(&log_path).unwrap();
+
+        resolve_set(
+            &fs,
+            &set,
+            &KeepPolicy::Oldest,
+            &DedupeAction::Hardlink,
+            &mut |x| log.append(x).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::metadata(&a).unwrap().ino(),
+            std::fs::metadata(&b).unwrap().ino()
+        );
+        let entries = UndoLog::read(&log_path).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        undo(&fs, &entries);
+        assert_ne!(
+            std::fs::metadata(&a).unwrap().ino(),
+            std::fs::metadata(&b).unwrap().ino()
+        );
+        assert_eq!(std::fs::read(&b).unwrap(), b"same");
+    }
+}
@@ -1,41 +1,396 @@
+use super::exif::{self, FileNameGroup};
 use super::file::{FilePath, InputFile};
+use super::utils;
+use ignore::gitignore::Gitignore;
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::Match;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
 
-fn get_valid_walk_entry(entry: &walkdir::DirEntry) -> Option<PathBuf> {
-    let path = entry.path();
-    if path.is_file() {
-        Some(path.to_path_buf())
-    } else {
-        None
+// The name of the ignore file we look for in every directory we walk.
+// It follows the same rules as a `.gitignore` file.
+pub const IGNORE_FILE_NAME: &str = ".eximdignore";
+
+// How many events can wait for the consumer before the scan blocks.
+// It keeps the memory flat when the consumer is slower than the disk.
+const SCAN_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CollectOptions {
+    // Glob patterns (gitignore syntax) relative to the root of the walk.
+    // When not empty, only the files that match one of them are collected.
+    pub include: Vec<String>,
+    // Glob patterns (gitignore syntax) of the files and directories to skip.
+    pub exclude: Vec<String>,
+    // Skip the dot files and directories (.DS_Store, ._IMG_1234.JPG, .git, ...)
+    // and the OS metadata files like Thumbs.db.
+    pub skip_hidden: bool,
+    // Honour the `.eximdignore` files found in the walked directories.
+    pub use_ignore_files: bool,
+    // How deep we go from the root. The direct children are depth 1.
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+    // How many directories are read at the same time. With one thread the
+    // directories and the files come in a stable (sorted) order.
+    pub threads: usize,
+}
+
+impl Default for CollectOptions {
+    fn default() -> Self {
+        Self {
+            include: vec![],
+            exclude: vec![],
+            skip_hidden: true,
+            use_ignore_files: true,
+            max_depth: None,
+            follow_symlinks: false,
+            threads: 1,
+        }
     }
 }
 
-// Accept either a directory or a file path.
-// If it is a file, it will return a vector of just one file.
-// If it is a directory, it will walk the files and return
-// all the files recursivelly.
-pub fn collect_files(path: &Path) -> Result<Vec<InputFile>, String> {
+impl CollectOptions {
+    fn overrides(&self, root: &Path) -> Result<Override, String> {
+        let mut builder = OverrideBuilder::new(root);
+        for glob in self.include.iter() {
+            builder
+                .add(glob)
+                .map_err(|err| format!("Error: invalid include glob '{}': {}", glob, err))?;
+        }
+        // The override globs are whitelists by default. Prefixing them
+        // with "!" turns them into the ignore globs.
+        for glob in self.exclude.iter() {
+            builder
+                .add(&format!("!{}", glob))
+                .map_err(|err| format!("Error: invalid exclude glob '{}': {}", glob, err))?;
+        }
+        builder.build().map_err(|err| format!("Error: {}", err))
+    }
+}
+
+#[derive(Debug)]
+pub enum ScanEvent {
+    File(InputFile),
+    // All the files of a directory have been seen, so its groups are final.
+    Groups(Vec<FileNameGroup>),
+    // The directory (or the ignore file) could not be read. The scan goes on.
+    Error(String),
+}
+
+// The events of a scan, as they are found. The directories are read on
+// background threads, so the consumer can start working on the first
+// groups while the rest of the tree is still being walked.
+// Dropping the scanner stops the walk.
+pub struct Scanner {
+    events: mpsc::Receiver<ScanEvent>,
+    // One thread walks the tree in the sorted order.
+    ordered: bool,
+}
+
+impl Iterator for Scanner {
+    type Item = ScanEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.recv().ok()
+    }
+}
+
+impl Scanner {
+    // Only the groups, for the consumers that don't care about single files.
+    // The directories that could not be read go to `on_error`, the caller
+    // has to tell the user that some files are missing.
+    pub fn groups(self, mut on_error: impl FnMut(String)) -> impl Iterator<Item = FileNameGroup> {
+        self.filter_map(move |x| match x {
+            ScanEvent::Groups(groups) => Some(groups),
+            ScanEvent::Error(err) => {
+                on_error(err);
+                None
+            }
+            ScanEvent::File(_) => None,
+        })
+        .flatten()
+    }
+
+    // The groups ordered by the directory and then the stem. With one thread
+    // the scan already walks in this order and the groups still stream, with
+    // more we have to wait for the whole tree to sort them.
+    pub fn sorted_groups(
+        self,
+        on_error: impl FnMut(String) + Send + 'static,
+    ) -> Box<dyn Iterator<Item = FileNameGroup> + Send> {
+        if self.ordered {
+            return Box::new(self.groups(on_error));
+        }
+        let mut groups = self.groups(on_error).collect::<Vec<_>>();
+        exif::sort_groups_by_name(&mut groups);
+        Box::new(groups.into_iter())
+    }
+}
+
+// The walk of the `ignore` crate (`WalkBuilder`) can't be used here: its
+// parallel walk queues every entry on its own, so nobody knows when all the
+// files of a directory are seen, and the groups (the RAW with its JPEG) need
+// them all. So we read the directories ourselves, a directory at a time, and
+// only take the ignore file matching (`Gitignore`) from the crate.
+struct DirJob {
+    path: PathBuf,
+    depth: usize,
+    // The ignore files from the root down to this directory.
+    ignores: Arc<Vec<Gitignore>>,
+}
+
+#[derive(Default)]
+struct DirQueue {
+    jobs: Vec<DirJob>,
+    // The directories that are being read right now. They can still
+    // push new jobs, so the workers wait for them before they quit.
+    active: usize,
+    stopped: bool,
+}
+
+struct ScanContext {
+    root: PathBuf,
+    options: CollectOptions,
+    overrides: Override,
+    queue: Mutex<DirQueue>,
+    wakeup: Condvar,
+    // The canonical paths of the directories we have entered. It is only
+    // used with the symlinks, to not go around in circles.
+    visited: Mutex<HashSet<PathBuf>>,
+}
+
+impl ScanContext {
+    fn next_job(&self) -> Option<DirJob> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.stopped {
+                return None;
+            }
+            if let Some(job) = queue.jobs.pop() {
+                queue.active += 1;
+                return Some(job);
+            }
+            if queue.active == 0 {
+                return None;
+            }
+            queue = self.wakeup.wait(queue).unwrap();
+        }
+    }
+
+    fn finish_job(&self, mut next: Vec<DirJob>, stop: bool) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.active -= 1;
+        queue.stopped |= stop;
+        // The jobs are popped from the end, so the first directory
+        // in the sorted order is read first.
+        next.reverse();
+        queue.jobs.extend(next);
+        self.wakeup.notify_all();
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool, ignores: &[Gitignore]) -> bool {
+        // The user globs win over the ignore files.
+        match self.overrides.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+        // The closest ignore file has the last word.
+        for ignore in ignores.iter().rev() {
+            match ignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    fn is_new_dir(&self, path: &Path) -> bool {
+        if !self.options.follow_symlinks {
+            return true;
+        }
+        match path.canonicalize() {
+            Ok(path) => self.visited.lock().unwrap().insert(path),
+            Err(_) => false,
+        }
+    }
+
+    // Reads one directory. Sends its files and its groups and
+    // returns the subdirectories we need to go into.
+    fn read_dir(&self, job: &DirJob, tx: &mpsc::SyncSender<ScanEvent>) -> Result<Vec<DirJob>, ()> {
+        let send = |event| tx.send(event).map_err(|_| ());
+        let mut ignores = job.ignores.clone();
+        if self.options.use_ignore_files {
+            let ignore_file = job.path.join(IGNORE_FILE_NAME);
+            if ignore_file.is_file() {
+                let (ignore, err) = Gitignore::new(&ignore_file);
+                if let Some(err) = err {
+                    send(ScanEvent::Error(format!(
+                        "Error: reading {}: {}",
+                        ignore_file.display(),
+                        err
+                    )))?;
+                }
+                let mut next = job.ignores.to_vec();
+                next.push(ignore);
+                ignores = Arc::new(next);
+            }
+        }
+
+        let entries = match std::fs::read_dir(&job.path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                send(ScanEvent::Error(format!(
+                    "Error: reading {}: {}",
+                    job.path.display(),
+                    err
+                )))?;
+                return Ok(vec![]);
+            }
+        };
+        let mut entries = entries.filter_map(Result::ok).collect::<Vec<_>>();
+        entries.sort_by_key(|x| x.file_name());
+
+        let depth = job.depth + 1;
+        let mut files = vec![];
+        let mut dirs = vec![];
+        for entry in entries {
+            let path = entry.path();
+            if self.options.skip_hidden && utils::is_system_file(&path) {
+                continue;
+            }
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let (is_file, is_dir) = if file_type.is_symlink() {
+                // A link to a file is collected like before, a link
+                // to a directory only when we follow the links.
+                match std::fs::metadata(&path) {
+                    Ok(meta) => (
+                        meta.is_file(),
+                        meta.is_dir() && self.options.follow_symlinks,
+                    ),
+                    Err(_) => (false, false),
+                }
+            } else {
+                (file_type.is_file(), file_type.is_dir())
+            };
+            if !is_file && !is_dir {
+                continue;
+            }
+            if self.is_ignored(&path, is_dir, &ignores) {
+                continue;
+            }
+            if is_file {
+                files.push(InputFile::new(&FilePath::new(&path), &self.root));
+            } else if self.options.max_depth.is_none_or(|x| depth < x) && self.is_new_dir(&path) {
+                dirs.push(DirJob {
+                    path,
+                    depth,
+                    ignores: ignores.clone(),
+                });
+            }
+        }
+
+        if !files.is_empty() {
+            let groups = exif::group_same_name_files(&files);
+            for file in files {
+                send(ScanEvent::File(file))?;
+            }
+            send(ScanEvent::Groups(groups))?;
+        }
+        Ok(dirs)
+    }
+}
+
+// Walks the path on the background threads and streams what it finds.
+// The errors about the input (missing path, invalid globs) are returned
+// right away, the errors found on the way come as `ScanEvent::Error`.
+pub fn scan(path: &Path, options: &CollectOptions) -> Result<Scanner, String> {
+    let (tx, rx) = mpsc::sync_channel(SCAN_BUFFER);
+
     // We support direct path
     if path.is_file() {
-        let files = vec![InputFile::new(&FilePath::new(path), path)];
-        return Ok(files);
-        // We support a directory and we walk all the paths.
-    } else if path.is_dir() {
-        let files = WalkDir::new(path)
-            .into_iter()
-            .filter_map(Result::ok)
-            .filter_map(|x| get_valid_walk_entry(&x))
-            .map(|x| InputFile::new(&FilePath::new(&x), path))
-            .collect::<Vec<_>>();
-        return Ok(files);
-        // In case is a symlink or something, let's error
-    } else {
+        let file = InputFile::new(&FilePath::new(path), path);
+        let groups = exif::group_same_name_files(std::slice::from_ref(&file));
+        // The buffer is big enough, nobody waits on the other side yet.
+        let _ = tx.send(ScanEvent::File(file));
+        let _ = tx.send(ScanEvent::Groups(groups));
+        return Ok(Scanner {
+            events: rx,
+            ordered: true,
+        });
+    }
+    // In case is a symlink or something, let's error
+    if !path.is_dir() {
         return Err(format!(
             "Error: path is neither a file niether a dir: {}",
-            path.display().to_string()
+            path.display()
         ));
     }
+
+    let context = Arc::new(ScanContext {
+        root: path.to_path_buf(),
+        options: options.clone(),
+        overrides: options.overrides(path)?,
+        queue: Mutex::new(DirQueue::default()),
+        wakeup: Condvar::new(),
+        visited: Mutex::new(HashSet::new()),
+    });
+    if options.max_depth != Some(0) {
+        context.is_new_dir(path);
+        context.queue.lock().unwrap().jobs.push(DirJob {
+            path: path.to_path_buf(),
+            depth: 0,
+            ignores: Arc::new(vec![]),
+        });
+    }
+
+    for _ in 0..options.threads.max(1) {
+        let context = Arc::clone(&context);
+        let tx = tx.clone();
+        thread::spawn(move || {
+            while let Some(job) = context.next_job() {
+                match context.read_dir(&job, &tx) {
+                    Ok(next) => context.finish_job(next, false),
+                    // The scanner was dropped, nobody listens anymore.
+                    Err(_) => context.finish_job(vec![], true),
+                }
+            }
+        });
+    }
+
+    Ok(Scanner {
+        events: rx,
+        ordered: options.threads <= 1,
+    })
+}
+
+// Accept either a directory or a file path.
+// If it is a file, it will return a vector of just one file.
+// If it is a directory, it will walk the files and return
+// all the files recursivelly.
+pub fn collect_files(path: &Path) -> Result<Vec<InputFile>, String> {
+    collect_files_with_options(path, &CollectOptions::default())
+}
+
+// Same as the `collect_files` but the walk respects the provided options.
+// A direct file path is always returned as is, because the user asked for it.
+pub fn collect_files_with_options(
+    path: &Path,
+    options: &CollectOptions,
+) -> Result<Vec<InputFile>, String> {
+    let files = scan(path, options)?
+        .filter_map(|x| match x {
+            ScanEvent::File(file) => Some(file),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    Ok(files)
 }
 
 #[cfg(test)]
@@ -92,7 +447,7 @@ mod test {
         let invalid_path = Path::new("invalid/path/to/nothing");
 
         // Test: Call the function with an invalid path
-        let result = collect_files(&invalid_path);
+        let result = collect_files(invalid_path);
 
         // Assert: The result should be an error
         assert!(result.is_err());
@@ -129,4 +484,246 @@ mod test {
         assert!(paths.contains(&file2_path));
         assert!(paths.contains(&file3_path));
     }
+
+    fn collected_names(files: Vec<InputFile>) -> Vec<String> {
+        let mut names = files
+            .into_iter()
+            .map(|f| f.src_relative.to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_collect_files_skips_hidden_and_system_files() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join(".git")).unwrap();
+        File::create(temp_dir.path().join(".git/config")).unwrap();
+        File::create(temp_dir.path().join(".DS_Store")).unwrap();
+        File::create(temp_dir.path().join("._IMG_1234.JPG")).unwrap();
+        File::create(temp_dir.path().join("Thumbs.db")).unwrap();
+        File::create(temp_dir.path().join("IMG_1234.JPG")).unwrap();
+
+        let files = collect_files(temp_dir.path()).unwrap();
+        assert_eq!(collected_names(files), vec!["IMG_1234.JPG"]);
+
+        let options = CollectOptions {
+            skip_hidden: false,
+            ..Default::default()
+        };
+        let files = collect_files_with_options(temp_dir.path(), &options).unwrap();
+        assert_eq!(files.len(), 5);
+    }
+
+    #[test]
+    fn test_collect_files_with_include_and_exclude_globs() {
+        let temp_dir = tempdir().unwrap();
+        let subdir = temp_dir.path().join("export");
+        std::fs::create_dir_all(&subdir).unwrap();
+        File::create(temp_dir.path().join("a.jpg")).unwrap();
+        File::create(temp_dir.path().join("a.xmp")).unwrap();
+        File::create(temp_dir.path().join("b.mov")).unwrap();
+        File::create(subdir.join("c.jpg")).unwrap();
+
+        let options = CollectOptions {
+            include: vec!["*.jpg".into(), "*.xmp".into()],
+            exclude: vec!["export/".into()],
+            ..Default::default()
+        };
+        let files = collect_files_with_options(temp_dir.path(), &options).unwrap();
+        assert_eq!(collected_names(files), vec!["a.jpg", "a.xmp"]);
+    }
+
+    #[test]
+    fn test_collect_files_with_invalid_glob() {
+        let temp_dir = tempdir().unwrap();
+        let options = CollectOptions {
+            include: vec!["a{b".into()],
+            ..Default::default()
+        };
+        assert!(collect_files_with_options(temp_dir.path(), &options).is_err());
+    }
+
+    #[test]
+    fn test_collect_files_honours_ignore_file_per_directory() {
+        let temp_dir = tempdir().unwrap();
+        let subdir = temp_dir.path().join("subdir");
+        std::fs::create_dir_all(&subdir).unwrap();
+        File::create(temp_dir.path().join("a.jpg")).unwrap();
+        File::create(subdir.join("b.jpg")).unwrap();
+        File::create(subdir.join("b.xmp")).unwrap();
+        let mut ignore = File::create(subdir.join(IGNORE_FILE_NAME)).unwrap();
+        writeln!(ignore, "*.xmp").unwrap();
+
+        let files = collect_files(temp_dir.path()).unwrap();
+        assert_eq!(collected_names(files), vec!["a.jpg", "subdir/b.jpg"]);
+
+        let options = CollectOptions {
+            use_ignore_files: false,
+            skip_hidden: false,
+            ..Default::default()
+        };
+        let files = collect_files_with_options(temp_dir.path(), &options).unwrap();
+        assert_eq!(files.len(), 4);
+    }
+
+    #[test]
+    fn test_collect_files_with_max_depth() {
+        let temp_dir = tempdir().unwrap();
+        let subdir = temp_dir.path().join("subdir");
+        std::fs::create_dir_all(&subdir).unwrap();
+        File::create(temp_dir.path().join("a.jpg")).unwrap();
+        File::create(subdir.join("b.jpg")).unwrap();
+
+        let options = CollectOptions {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        let files = collect_files_with_options(temp_dir.path(), &options).unwrap();
+        assert_eq!(collected_names(files), vec!["a.jpg"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_files_with_symlinks() {
+        let temp_dir = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        File::create(outside.path().join("b.jpg")).unwrap();
+        File::create(temp_dir.path().join("a.jpg")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), temp_dir.path().join("linked")).unwrap();
+
+        let files = collect_files(temp_dir.path()).unwrap();
+        assert_eq!(collected_names(files), vec!["a.jpg"]);
+
+        let options = CollectOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let files = collect_files_with_options(temp_dir.path(), &options).unwrap();
+        assert_eq!(collected_names(files), vec!["a.jpg", "linked/b.jpg"]);
+    }
+
+    #[test]
+    fn test_scan_emits_the_groups_of_every_directory() {
+        let temp_dir = tempdir().unwrap();
+        let subdir = temp_dir.path().join("subdir");
+        std::fs::create_dir_all(&subdir).unwrap();
+        File::create(temp_dir.path().join("IMG_1.JPG")).unwrap();
+        File::create(temp_dir.path().join("IMG_1.xmp")).unwrap();
+        File::create(subdir.join("IMG_1.JPG")).unwrap();
+
+        let events = scan(temp_dir.path(), &CollectOptions::default())
+            .unwrap()
+            .collect::<Vec<_>>();
+        let names = events
+            .iter()
+            .map(|x| match x {
+                ScanEvent::File(file) => file.src_relative.to_string(),
+                ScanEvent::Groups(groups) => format!("groups: {}", groups[0].group_key()),
+                ScanEvent::Error(err) => err.to_owned(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "IMG_1.JPG",
+                "IMG_1.xmp",
+                "groups: IMG_1",
+                "subdir/IMG_1.JPG",
+                "groups: subdir/IMG_1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_with_many_threads() {
+        let temp_dir = tempdir().unwrap();
+        let mut expected = vec![];
+        for i in 0..8 {
+            let subdir = temp_dir.path().join(format!("dir{}", i));
+            std::fs::create_dir_all(subdir.join("nested")).unwrap();
+            File::create(subdir.join("a.jpg")).unwrap();
+            File::create(subdir.join("nested/b.mov")).unwrap();
+            expected.push(format!("dir{}/a.jpg", i));
+            expected.push(format!("dir{}/nested/b.mov", i));
+        }
+        expected.sort();
+
+        let options = CollectOptions {
+            threads: 4,
+            ..Default::default()
+        };
+        let groups = scan(temp_dir.path(), &options)
+            .unwrap()
+            .groups(|err| panic!("{}", err))
+            .count();
+        assert_eq!(groups, 16);
+        let files = collect_files_with_options(temp_dir.path(), &options).unwrap();
+        assert_eq!(collected_names(files), expected);
+    }
+
+    #[test]
+    fn test_scan_sorts_the_groups_by_directory_and_stem() {
+        let temp_dir = tempdir().unwrap();
+        for dir in ["a/x", "a-b", "b"] {
+            std::fs::create_dir_all(temp_dir.path().join(dir)).unwrap();
+        }
+        for name in [
+            "b.jpg",
+            "a/b.jpg",
+            "a/x/a.jpg",
+            "a-b/a.jpg",
+            "b/a.jpg",
+            "a.jpg",
+        ] {
+            File::create(temp_dir.path().join(name)).unwrap();
+        }
+        let expected = vec!["a", "b", "a/b", "a/x/a", "a-b/a", "b/a"];
+
+        for threads in [1, 4] {
+            let options = CollectOptions {
+                threads,
+                ..Default::default()
+            };
+            let keys = scan(temp_dir.path(), &options)
+                .unwrap()
+                .sorted_groups(|err| panic!("{}", err))
+                .map(|x| x.group_key().to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(keys, expected);
+        }
+    }
+
+    #[test]
+    fn test_scan_reports_the_errors_with_the_groups() {
+        let temp_dir = tempdir().unwrap();
+        File::create(temp_dir.path().join("a.jpg")).unwrap();
+        std::fs::write(temp_dir.path().join(IGNORE_FILE_NAME), "b[.jpg\n").unwrap();
+
+        let mut errors = vec![];
+        let groups = scan(temp_dir.path(), &CollectOptions::default())
+            .unwrap()
+            .groups(|err| errors.push(err))
+            .count();
+        assert_eq!(groups, 1);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(IGNORE_FILE_NAME));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_does_not_loop_on_symlinks() {
+        let temp_dir = tempdir().unwrap();
+        let subdir = temp_dir.path().join("subdir");
+        std::fs::create_dir_all(&subdir).unwrap();
+        File::create(subdir.join("a.jpg")).unwrap();
+        std::os::unix::fs::symlink(temp_dir.path(), subdir.join("loop")).unwrap();
+
+        let options = CollectOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let files = collect_files_with_options(temp_dir.path(), &options).unwrap();
+        assert_eq!(collected_names(files), vec!["subdir/a.jpg"]);
+    }
 }
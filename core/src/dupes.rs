@@ -0,0 +1,517 @@
+use super::catalog::FileStamp;
+use super::hash;
+use super::pipeline::CancelToken;
+use super::similar;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+
+// What we know about the content of a file. Everything is optional,
+// the finder only fills in what it needs.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FileHashes {
+    pub size: Option<u64>,
+    pub partial: Option<String>,
+    pub full: Option<String>,
+    pub perceptual: Option<u64>,
+}
+
+// Where the finder keeps the hashes, so the next run does not read
+// the same files again. The CLI loads it from the catalog, the app
+// keeps it in memory for as long as it runs.
+pub trait HashStore {
+    fn get(&self, path: &Path) -> Option<FileHashes>;
+    fn set(&mut self, path: &Path, hashes: FileHashes);
+}
+
+// The hashes go with the stamp of the file when it was hashed. A file that
+// changed since, or an entry without a stamp, is read again.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    hashes: HashMap<PathBuf, (Option<FileStamp>, FileHashes)>,
+    changed: HashSet<PathBuf>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Fills the store without marking anything as changed.
+    pub fn load(&mut self, path: &Path, stamp: Option<FileStamp>, hashes: FileHashes) {
+        self.hashes.insert(path.to_path_buf(), (stamp, hashes));
+    }
+
+    // Takes in the entries of an other store, a copy that was used while
+    // this one was not locked.
+    pub fn merge(&mut self, other: MemoryStore) {
+        self.changed.extend(other.changed);
+        self.hashes.extend(other.hashes);
+    }
+
+    // The entries the finder computed since the store was loaded,
+    // for the callers that need to save them somewhere.
+    pub fn changed(&self) -> impl Iterator<Item = (&PathBuf, Option<FileStamp>, &FileHashes)> {
+        self.changed
+            .iter()
+            .filter_map(|x| self.hashes.get_key_value(x))
+            .map(|(path, x)| (path, x.0, &x.1))
+    }
+}
+
+impl HashStore for MemoryStore {
+    fn get(&self, path: &Path) -> Option<FileHashes> {
+        self.hashes
+            .get(path)
+            .filter(|x| {
+                let now = FileStamp::from_path(path).ok();
+                x.0.zip(now).is_some_and(|(a, b)| a.same_as(&b))
+            })
+            .map(|x| x.1.clone())
+    }
+
+    fn set(&mut self, path: &Path, hashes: FileHashes) {
+        self.changed.insert(path.to_path_buf());
+        self.hashes.insert(
+            path.to_path_buf(),
+            (FileStamp::from_path(path).ok(), hashes),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Confidence {
+    // The same bytes.
+    Exact,
+    // The images look the same (resized, recompressed, ...).
+    Similar,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DuplicateCluster {
+    pub confidence: Confidence,
+    // Sorted, so the same cluster always looks the same.
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DupesStage {
+    Size,
+    PartialHash,
+    FullHash,
+    PerceptualHash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct DupesProgress {
+    pub stage: DupesStage,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DupesSummary {
+    pub clusters: usize,
+    // The files that could not be read, with the reason.
+    pub skipped: Vec<(PathBuf, String)>,
+    pub cancelled: bool,
+}
+
+// Only the groups with more than one file can hold duplicates.
+fn keep_groups<K, F>(files: Vec<PathBuf>, key: F) -> Vec<Vec<PathBuf>>
+where
+    K: Hash + Eq,
+    F: Fn(&Path) -> Option<K>,
+{
+    let mut groups: HashMap<K, Vec<PathBuf>> = HashMap::new();
+    for file in files {
+        if let Some(k) = key(&file) {
+            groups.entry(k).or_default().push(file);
+        }
+    }
+    groups.into_values().filter(|x| x.len() > 1).collect()
+}
+
+fn into_cluster(confidence: Confidence, mut files: Vec<PathBuf>) -> DuplicateCluster {
+    files.sort();
+    DuplicateCluster { confidence, files }
+}
+
+pub struct DuplicateFinder {
+    similar_threshold: Option<u32>,
+    cancel: CancelToken,
+}
+
+impl Default for DuplicateFinder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DuplicateFinder {
+    pub fn new() -> Self {
+        Self {
+            similar_threshold: None,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    // Also look for the images within the threshold (in bits of the dHash).
+    pub fn similar(mut self, threshold: u32) -> Self {
+        self.similar_threshold = Some(threshold);
+        self
+    }
+
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    // Runs one stage over the files: reads the value from the store or
+    // computes and stores it. The files we can't read are left out.
+    fn stage<S, T, G, C, P>(
+        &self,
+        store: &mut S,
+        files: Vec<PathBuf>,
+        stage: DupesStage,
+        (get, compute): (G, C),
+        on_progress: &mut P,
+        skipped: &mut Vec<(PathBuf, String)>,
+    ) -> Vec<(PathBuf, T)>
+    where
+        S: HashStore,
+        G: Fn(&FileHashes) -> Option<T>,
+        C: Fn(&Path, &mut FileHashes) -> Result<T, String>,
+        P: FnMut(DupesProgress),
+    {
+        let total = files.len();
+        let mut done = vec![];
+        for (i, file) in files.into_iter().enumerate() {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let mut hashes = store.get(&file).unwrap_or_default();
+            let value = match get(&hashes) {
+                Some(value) => Ok(value),
+                None => compute(&file, &mut hashes).inspect(|_| store.set(&file, hashes)),
+            };
+            match value {
+                Ok(value) => done.push((file, value)),
+                Err(err) => skipped.push((file, err)),
+            }
+            on_progress(DupesProgress {
+                stage,
+                done: i + 1,
+                total,
+            });
+        }
+        done
+    }
+
+    // The exact duplicates are found in three steps, every step only looks
+    // at what is left from the one before: the same size, the same partial
+    // hash and finally the same full BLAKE3 hash. The clusters are handed
+    // over as soon as they are known, the exact ones first.
+    pub fn find<S, P, C>(
+        &self,
+        store: &mut S,
+        files: &[PathBuf],
+        mut on_progress: P,
+        mut on_cluster: C,
+    ) -> DupesSummary
+    where
+        S: HashStore,
+        P: FnMut(DupesProgress),
+        C: FnMut(DuplicateCluster),
+    {
+        // The same file listed twice is not a duplicate of itself.
+        let mut seen = HashSet::new();
+        let files = files
+            .iter()
+            .filter(|x| seen.insert(*x))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut skipped = vec![];
+        let mut clusters = 0;
+
+        let sized = self.stage(
+            store,
+            files.clone(),
+            DupesStage::Size,
+            (
+                |x: &FileHashes| x.size,
+                |path: &Path, hashes: &mut FileHashes| {
+                    let size = std::fs::metadata(path).map_err(|x| x.to_string())?.len();
+                    hashes.size = Some(size);
+                    Ok(size)
+                },
+            ),
+            &mut on_progress,
+            &mut skipped,
+        );
+        let sizes = sized.into_iter().collect::<HashMap<_, _>>();
+        // All the empty files have the same content, but they are not what
+        // anybody is looking for.
+        let candidates = keep_groups(sizes.keys().cloned().collect(), |x| {
+            sizes.get(x).copied().filter(|x| *x > 0)
+        })
+        .concat();
+
+        let partial = self.stage(
+            store,
+            candidates,
+            DupesStage::PartialHash,
+            (
+                |x: &FileHashes| x.partial.clone(),
+                |path: &Path, hashes: &mut FileHashes| {
+                    let value = hash::partial_hash(path).map_err(|x| x.to_string())?;
+                    hashes.partial = Some(value.clone());
+                    Ok(value)
+                },
+            ),
+            &mut on_progress,
+            &mut skipped,
+        );
+        let partial = partial.into_iter().collect::<HashMap<_, _>>();
+        let candidates = keep_groups(partial.keys().cloned().collect(), |x| {
+            Some((sizes.get(x)?, partial.get(x)?))
+        })
+        .concat();
+
+        let full = self.stage(
+            store,
+            candidates,
+            DupesStage::FullHash,
+            (
+                |x: &FileHashes| x.full.clone(),
+                |path: &Path, hashes: &mut FileHashes| {
+                    let value = hash::full_hash(path).map_err(|x| x.to_string())?;
+                    hashes.full = Some(value.clone());
+                    Ok(value)
+                },
+            ),
+            &mut on_progress,
+            &mut skipped,
+        );
+        let full = full.into_iter().collect::<HashMap<_, _>>();
+        let mut exact = keep_groups(full.keys().cloned().collect(), |x| full.get(x).cloned())
+            .into_iter()
+            .map(|x| into_cluster(Confidence::Exact, x))
+            .collect::<Vec<_>>();
+        exact.sort_by(|a, b| a.files.cmp(&b.files));
+        let exact_sets = exact
+            .iter()
+            .enumerate()
+            .flat_map(|(i, x)| x.files.iter().map(move |path| (path.clone(), i)))
+            .collect::<HashMap<_, _>>();
+        for cluster in exact {
+            clusters += 1;
+            on_cluster(cluster);
+        }
+
+        if let Some(threshold) = self.similar_threshold {
+            let images = files
+                .into_iter()
+                .filter(|x| similar::is_supported(x))
+                .collect::<Vec<_>>();
+            let hashed = self.stage(
+                store,
+                images,
+                DupesStage::PerceptualHash,
+                (
+                    |x: &FileHashes| x.perceptual,
+                    |path: &Path, hashes: &mut FileHashes| {
+                        let value = similar::dhash(path)?;
+                        hashes.perceptual = Some(value);
+                        Ok(value)
+                    },
+                ),
+                &mut on_progress,
+                &mut skipped,
+            );
+            let hashes = hashed.iter().map(|x| x.1).collect::<Vec<_>>();
+            let mut similar = similar::cluster(&hashes, threshold)
+                .into_iter()
+                .map(|x| {
+                    let files = x.into_iter().map(|i| hashed[i].0.clone()).collect();
+                    into_cluster(Confidence::Similar, files)
+                })
+                // The copies of one exact set look the same too, they are reported above.
+                .filter(|x| {
+                    let first = exact_sets.get(&x.files[0]);
+                    first.is_none() || !x.files.iter().all(|path| exact_sets.get(path) == first)
+                })
+                .collect::<Vec<_>>();
+            similar.sort_by(|a, b| a.files.cmp(&b.files));
+            for cluster in similar {
+                clusters += 1;
+                on_cluster(cluster);
+            }
+        }
+
+        DupesSummary {
+            clusters,
+            skipped,
+            cancelled: self.cancel.is_cancelled(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use tempfile::tempdir;
+
+    fn write_files(dir: &Path, files: &[(&str, &[u8])]) -> Vec<PathBuf> {
+        files
+            .iter()
+            .map(|(name, content)| {
+                let path = dir.join(name);
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_exact_duplicates_and_remembers_the_hashes() {
+        let temp_dir = tempdir().unwrap();
+        let files = write_files(
+            temp_dir.path(),
+            &[
+                ("a.jpg", b"one"),
+                ("b.jpg", b"one"),
+                ("c.jpg", b"two"),
+                ("d.jpg", b"three"),
+            ],
+        );
+        File::create(temp_dir.path().join("e.jpg")).unwrap();
+        File::create(temp_dir.path().join("f.jpg")).unwrap();
+        let mut store = MemoryStore::new();
+        let mut clusters = vec![];
+
+        let summary = DuplicateFinder::new().find(&mut store, &files, |_| {}, |x| clusters.push(x));
+
+        assert_eq!(summary.clusters, 1);
+        assert_eq!(
+            clusters,
+            vec![DuplicateCluster {
+                confidence: Confidence::Exact,
+                files: vec![files[0].clone(), files[1].clone()],
+            }]
+        );
+        // "c.jpg" has the same size, so it got the partial hash too.
+        assert!(store.get(&files[2]).unwrap().partial.is_some());
+        assert_eq!(store.get(&files[2]).unwrap().full, None);
+        assert_eq!(store.get(&files[3]).unwrap().partial, None);
+        assert_eq!(store.changed().count(), 4);
+    }
+
+    #[test]
+    fn a_changed_file_is_hashed_again() {
+        let temp_dir = tempdir().unwrap();
+        let files = write_files(temp_dir.path(), &[("a.jpg", b"one")]);
+        let mut store = MemoryStore::new();
+
+        store.set(&files[0], FileHashes::default());
+        assert!(store.get(&files[0]).is_some());
+        std::fs::write(&files[0], b"other").unwrap();
+        assert_eq!(store.get(&files[0]), None);
+    }
+
+    #[test]
+    fn uses_the_stored_hashes() {
+        let temp_dir = tempdir().unwrap();
+        let files = write_files(temp_dir.path(), &[("a.jpg", b"one"), ("b.jpg", b"two")]);
+        let mut store = MemoryStore::new();
+        // The stored hashes say they are the same, so we trust them.
+        for file in files.iter() {
+            store.load(
+                file,
+                FileStamp::from_path(file).ok(),
+                FileHashes {
+                    size: Some(3),
+                    partial: Some("p".into()),
+                    full: Some("f".into()),
+                    perceptual: None,
+                },
+            );
+        }
+        let mut progress = vec![];
+
+        let summary =
+            DuplicateFinder::new().find(&mut store, &files, |x| progress.push(x.stage), |_| {});
+
+        assert_eq!(summary.clusters, 1);
+        assert_eq!(store.changed().count(), 0);
+        assert_eq!(
+            progress,
+            vec![
+                DupesStage::Size,
+                DupesStage::Size,
+                DupesStage::PartialHash,
+                DupesStage::PartialHash,
+                DupesStage::FullHash,
+                DupesStage::FullHash,
+            ]
+        );
+    }
+
+    #[test]
+    fn the_loaded_hashes_of_a_changed_file_are_not_used() {
+        let temp_dir = tempdir().unwrap();
+        let files = write_files(temp_dir.path(), &[("a.jpg", b"one"), ("b.jpg", b"one")]);
+        let mut store = MemoryStore::new();
+        let same = FileHashes {
+            size: Some(3),
+            partial: Some("p".into()),
+            full: Some("f".into()),
+            perceptual: None,
+        };
+        // "a.jpg" was edited since it was hashed, "b.jpg" has no stamp.
+        let old = FileStamp::from_path(&files[0]).ok().map(|x| FileStamp {
+            mtime: x.mtime - 1,
+            ..x
+        });
+        store.load(&files[0], old, same.clone());
+        store.load(&files[1], None, same);
+        std::fs::write(&files[0], b"two").unwrap();
+
+        let summary = DuplicateFinder::new().find(&mut store, &files, |_| {}, |_| {});
+
+        assert_eq!(summary.clusters, 0);
+        assert_eq!(store.changed().count(), 2);
+    }
+
+    #[test]
+    fn skips_the_missing_files() {
+        let temp_dir = tempdir().unwrap();
+        let files = vec![temp_dir.path().join("missing.jpg")];
+
+        let summary = DuplicateFinder::new().find(&mut MemoryStore::new(), &files, |_| {}, |_| {});
+
+        assert_eq!(summary.clusters, 0);
+        assert_eq!(summary.skipped.len(), 1);
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let temp_dir = tempdir().unwrap();
+        let files = write_files(temp_dir.path(), &[("a.jpg", b"one"), ("b.jpg", b"one")]);
+        let token = CancelToken::new();
+        token.cancel();
+
+        let summary = DuplicateFinder::new().cancel_token(token).find(
+            &mut MemoryStore::new(),
+            &files,
+            |_| {},
+            |_| {},
+        );
+
+        assert!(summary.cancelled);
+        assert_eq!(summary.clusters, 0);
+    }
+}
@@ -0,0 +1,247 @@
+use super::exif::FileNameGroup;
+use chrono::NaiveDateTime;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct EventOptions {
+    // A longer gap between two groups starts a new event.
+    pub gap_minutes: i64,
+    // A group taken further away (in km) from the one before starts a new
+    // event too. Only the groups with a GPS position are compared.
+    pub distance_km: Option<f64>,
+}
+
+impl Default for EventOptions {
+    fn default() -> Self {
+        Self {
+            gap_minutes: 3 * 60,
+            distance_km: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Event {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    // The indexes of the groups given to `cluster`, in the order of the dates.
+    pub groups: Vec<usize>,
+    // The first GPS position of the event, for a reverse geocoder.
+    pub position: Option<(f64, f64)>,
+    // The start time, only when more events start on the same day, so they
+    // get a folder each, `10.00` (`10.00 2` for the same minute).
+    pub time: Option<String>,
+    pub label: Option<String>,
+}
+
+impl Event {
+    // The first date, the time and the label, `2024-08-16 Wedding` or
+    // `2024-08-16 10.00 Wedding`.
+    pub fn name(&self) -> String {
+        [
+            Some(self.start.format("%Y-%m-%d").to_string()),
+            self.time.clone(),
+            self.label.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
+    // Relative to the root of the files, `2024/2024-08-16 Wedding`.
+    pub fn folder(&self) -> PathBuf {
+        PathBuf::from(self.start.format("%Y").to_string()).join(self.name())
+    }
+}
+
+fn position(group: &FileNameGroup) -> Option<(f64, f64)> {
+    let metadata = group.primary()?.metadata.as_ref()?;
+    Some((metadata.gps_latitude?, metadata.gps_longitude?))
+}
+
+// The great circle distance between two positions (latitude, longitude).
+pub fn distance_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat_a, lat_b) = (a.0.to_radians(), b.0.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.1 - a.1).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * 6371.0 * h.sqrt().asin()
+}
+
+// Clusters the groups with a date into events, in the order of the dates.
+// The groups without a date are in none of them.
+pub fn cluster(groups: &[FileNameGroup], options: &EventOptions) -> Vec<Event> {
+    let mut dated = groups
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| x.date().map(|date| (date, x.group_key().sort_key(), i)))
+        .collect::<Vec<_>>();
+    dated.sort();
+
+    let gap = chrono::Duration::minutes(options.gap_minutes);
+    let mut events: Vec<Event> = vec![];
+    // The last position seen in the current event.
+    let mut last_position = None;
+    for (date, _, i) in dated {
+        let position = position(&groups[i]);
+        let split = match events.last() {
+            None => true,
+            Some(event) => {
+                date - event.end > gap
+                    || options
+                        .distance_km
+                        .zip(last_position.zip(position))
+                        .is_some_and(|(max, (a, b))| distance_km(a, b) > max)
+            }
+        };
+        if split {
+            last_position = None;
+            events.push(Event {
+                start: date,
+                end: date,
+                groups: vec![],
+                position,
+                time: None,
+                label: None,
+            });
+        }
+        let event = events.last_mut().expect("an event was just pushed");
+        event.end = date;
+        event.groups.push(i);
+        event.position = event.position.or(position);
+        last_position = position.or(last_position);
+    }
+    set_times(&mut events);
+    events
+}
+
+// The events of a day would share the folder, the ones of a busy day get
+// their start time in the name.
+fn set_times(events: &mut [Event]) {
+    let mut per_day = HashMap::new();
+    for event in events.iter() {
+        *per_day.entry(event.start.date()).or_insert(0) += 1;
+    }
+    let mut taken = HashSet::new();
+    for event in events.iter_mut() {
+        if per_day[&event.start.date()] < 2 {
+            continue;
+        }
+        let time = event.start.format("%H.%M").to_string();
+        let mut next = time.clone();
+        let mut n = 1;
+        while !taken.insert((event.start.date(), next.clone())) {
+            n += 1;
+            next = format!("{} {}", time, n);
+        }
+        event.time = Some(next);
+    }
+}
+
+// Gives the events their labels. No reverse geocoder comes with eximd (it
+// would need a place database or an online service), the labels are given
+// by the user, the `position` is kept for one. The label is a part of a
+// folder name, the path separators are taken out.
+pub fn label_events(events: &mut [Event], label: impl Fn(&Event) -> Option<String>) {
+    for event in events.iter_mut() {
+        event.label = label(event)
+            .map(|x| x.replace(['/', '\\'], "-").trim().to_string())
+            .filter(|x| !x.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exif::{ExifFile, ExifMetadata, FileNameGroupKey};
+    use crate::file::{FilePath, InputFile};
+    use std::path::Path;
+
+    fn group(name: &str, date: &str, position: Option<(f64, f64)>) -> FileNameGroup {
+        let mut file = ExifFile::from(&InputFile::new(
+            &FilePath::new(&Path::new("root").join(name)),
+            Path::new("root"),
+        ));
+        file.metadata = Some(ExifMetadata {
+            date_time_original: NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").ok(),
+            gps_latitude: position.map(|x| x.0),
+            gps_longitude: position.map(|x| x.1),
+            ..Default::default()
+        });
+        FileNameGroup::Image {
+            key: FileNameGroupKey::from(file.group_key.as_str()),
+            image: file,
+            config: vec![],
+        }
+    }
+
+    #[test]
+    fn cluster_on_the_time_gaps() {
+        let groups = vec![
+            group("a.jpg", "2024-08-16 18:00", None),
+            group("b.jpg", "2024-08-16 10:00", None),
+            group("c.jpg", "2024-08-16 12:30", None),
+            group("d.jpg", "2024-08-17 09:00", None),
+            group("e.jpg", "", None),
+        ];
+
+        let mut events = cluster(&groups, &EventOptions::default());
+        assert_eq!(
+            events.iter().map(|x| x.groups.clone()).collect::<Vec<_>>(),
+            vec![vec![1, 2], vec![0], vec![3]]
+        );
+
+        label_events(&mut events, |x| {
+            (x.start.format("%H").to_string() == "10").then(|| "Wedding/Party ".to_string())
+        });
+        assert_eq!(events[0].name(), "2024-08-16 10.00 Wedding-Party");
+        assert_eq!(
+            events[0].folder(),
+            PathBuf::from("2024/2024-08-16 10.00 Wedding-Party")
+        );
+        // Two events on the 16th, each has its own folder.
+        assert_eq!(events[1].folder(), PathBuf::from("2024/2024-08-16 18.00"));
+        assert_eq!(events[2].folder(), PathBuf::from("2024/2024-08-17"));
+    }
+
+    #[test]
+    fn cluster_on_the_distance() {
+        let amsterdam = (52.37, 4.90);
+        let utrecht = (52.09, 5.12);
+        let groups = vec![
+            group("a.jpg", "2024-08-16 10:00", Some(amsterdam)),
+            // Without a position, it stays with the one before.
+            group("b.jpg", "2024-08-16 10:10", None),
+            group("c.jpg", "2024-08-16 10:20", Some(utrecht)),
+        ];
+        assert!((distance_km(amsterdam, utrecht) - 34.0).abs() < 2.0);
+
+        let options = EventOptions::default();
+        assert_eq!(cluster(&groups, &options).len(), 1);
+
+        let options = EventOptions {
+            distance_km: Some(10.0),
+            ..options
+        };
+        let events = cluster(&groups, &options);
+        assert_eq!(
+            events.iter().map(|x| x.groups.clone()).collect::<Vec<_>>(),
+            vec![vec![0, 1], vec![2]]
+        );
+        assert_eq!(events[1].position, Some(utrecht));
+        // Split in the same minute, the folders still differ.
+        assert_eq!(events[0].name(), "2024-08-16 10.00");
+        assert_eq!(events[1].name(), "2024-08-16 10.20");
+
+        let groups = vec![
+            group("a.jpg", "2024-08-16 10:00", Some(amsterdam)),
+            group("b.jpg", "2024-08-16 10:00", Some(utrecht)),
+        ];
+        let events = cluster(&groups, &options);
+        assert_eq!(events[0].name(), "2024-08-16 10.00");
+        assert_eq!(events[1].name(), "2024-08-16 10.00 2");
+    }
+}
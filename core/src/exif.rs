@@ -1,10 +1,11 @@
-use super::config::FileSystem;
+use super::config::{ExtCase, FileSystem, RenameOptions};
 use super::file::{FileExt, FilePath, FileStem, FileType, InputFile};
 use super::utils;
 use chrono::NaiveDateTime;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
@@ -42,6 +43,15 @@ pub struct ExifMetadata {
     pub date_time_original: Option<NaiveDateTime>,
     #[serde(default, deserialize_with = "parse_date")]
     pub creation_date: Option<NaiveDateTime>,
+    // Where the events are split, see `events::cluster`.
+    #[serde(default, rename = "GPSLatitude", deserialize_with = "parse_gps")]
+    pub gps_latitude: Option<f64>,
+    #[serde(default, rename = "GPSLongitude", deserialize_with = "parse_gps")]
+    pub gps_longitude: Option<f64>,
+    // The date taken from the file name instead of exiftool, see
+    // `FileNameGroup::date_from_name`.
+    #[serde(skip)]
+    pub name_date: Option<NaiveDateTime>,
 }
 
 impl std::hash::Hash for ExifMetadata {
@@ -56,14 +66,103 @@ impl std::hash::Hash for ExifMetadata {
     }
 }
 
+// It has to compare the same fields as the `Hash` above, otherwise the
+// hash sets would treat the same metadata as different and the other way around.
+// This is only a heuristic, the content hash is what tells the real duplicates.
 impl PartialEq for ExifMetadata {
     fn eq(&self, other: &Self) -> bool {
-        self.file_name == other.file_name
+        self.file_size == other.file_size
+            && self.file_type == other.file_type
+            && self.file_type_extension == other.file_type_extension
+            && self.date_time_original == other.date_time_original
+            && self.image_width == other.image_width
     }
 }
 
 impl Eq for ExifMetadata {}
 
+// The fields we query often, taken from the full exiftool JSON. The rest of
+// the JSON is kept as it is in the catalog.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExifDetails {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    // In seconds.
+    pub duration: Option<f64>,
+    pub orientation: Option<String>,
+    // Apple's id that pairs the image and the video of a live photo.
+    pub content_identifier: Option<String>,
+    // With the fraction of a second and the time zone, as exiftool prints it.
+    pub sub_sec_date_time_original: Option<String>,
+}
+
+// The same tag can be a string or a number, depending on the file.
+fn json_str(value: &serde_json::Value, key: &str) -> Option<String> {
+    match value.get(key)? {
+        serde_json::Value::String(x) if !x.trim().is_empty() => Some(x.trim().to_string()),
+        serde_json::Value::Number(x) => Some(x.to_string()),
+        _ => None,
+    }
+}
+
+// "53 deg 12' 16.92\" N" or a plain number. South and west are negative.
+fn parse_gps_coordinate(value: &str) -> Option<f64> {
+    if let Ok(value) = value.parse::<f64>() {
+        return Some(value);
+    }
+    let numbers = value
+        .split(|x: char| !(x.is_ascii_digit() || x == '.'))
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let (degrees, rest) = numbers.split_first()?;
+    let minutes = rest.first().unwrap_or(&0.0);
+    let seconds = rest.get(1).unwrap_or(&0.0);
+    let coordinate = degrees + minutes / 60.0 + seconds / 3600.0;
+    match value.trim_end().chars().last() {
+        Some('S') | Some('W') => Some(-coordinate),
+        _ => Some(coordinate),
+    }
+}
+
+// "12.35 s", "0:01:02" or "0:01:02 (approx)".
+fn parse_duration(value: &str) -> Option<f64> {
+    let value = value.trim_end_matches("(approx)").trim();
+    if let Some(seconds) = value.strip_suffix('s') {
+        return seconds.trim().parse().ok();
+    }
+    value.split(':').try_fold(0.0, |total, x| {
+        x.parse::<f64>().ok().map(|x| total * 60.0 + x)
+    })
+}
+
+impl ExifDetails {
+    pub fn from_json(value: &serde_json::Value) -> Self {
+        Self {
+            make: json_str(value, "Make"),
+            model: json_str(value, "Model"),
+            lens: json_str(value, "LensModel")
+                .or_else(|| json_str(value, "LensID"))
+                .or_else(|| json_str(value, "Lens")),
+            gps_latitude: json_str(value, "GPSLatitude")
+                .as_deref()
+                .and_then(parse_gps_coordinate),
+            gps_longitude: json_str(value, "GPSLongitude")
+                .as_deref()
+                .and_then(parse_gps_coordinate),
+            duration: json_str(value, "Duration")
+                .as_deref()
+                .and_then(parse_duration),
+            orientation: json_str(value, "Orientation"),
+            content_identifier: json_str(value, "ContentIdentifier"),
+            sub_sec_date_time_original: json_str(value, "SubSecDateTimeOriginal"),
+        }
+    }
+}
+
 // We always want to take only the date and time from the string
 // and ignore the miliseconds and the timezone information.
 // When the exif data is created, the
@@ -83,7 +182,7 @@ where
     let s: Option<String> = Option::deserialize(deserializer)?;
     if let Some(s) = s {
         let s = if s.len() >= 18 { &s[..19] } else { &s };
-        match chrono::NaiveDateTime::parse_from_str(&s, "%Y:%m:%d %H:%M:%S") {
+        match chrono::NaiveDateTime::parse_from_str(s, "%Y:%m:%d %H:%M:%S") {
             Ok(dt) => Ok(Some(dt)),
             Err(_) => Ok(None),
         }
@@ -92,6 +191,18 @@ where
     }
 }
 
+fn parse_gps<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<serde_json::Value> = Option::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::Number(x)) => x.as_f64(),
+        Some(serde_json::Value::String(x)) => parse_gps_coordinate(&x),
+        _ => None,
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct ExifFile {
     pub group_key: String,
@@ -120,19 +231,32 @@ impl ExifFile {
     // again and see if we can clean it up in a more logical form.
     pub fn next_file_stem_from_exif(&self) -> Option<String> {
         // TODO: Parametize the format of the date
-        self.metadata
-            .as_ref()
-            .map(|x| {
-                x.date_time_original
-                    .or(x.creation_date)
-                    .map(|date| date.format("%Y-%m-%d_%H.%M.%S").to_string())
-            })
-            .flatten()
+        self.metadata.as_ref().and_then(|x| {
+            x.date_time_original
+                .or(x.creation_date)
+                .or(x.name_date)
+                .map(|date| NameTemplate::Current.stem(&date))
+        })
     }
 
-    pub fn next_file_name(&self) -> Option<String> {
+    // The tag the new name is taken from, the same order as above.
+    pub fn date_source(&self) -> Option<&'static str> {
+        self.metadata.as_ref().and_then(|x| {
+            if x.date_time_original.is_some() {
+                Some("DateTimeOriginal")
+            } else if x.creation_date.is_some() {
+                Some("CreationDate")
+            } else if x.name_date.is_some() {
+                Some("FileName")
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn next_file_name(&self) -> Option<OsString> {
         self.next_file_stem_from_exif()
-            .map(|x| format!("{}.{}", x, self.ext.value()))
+            .map(|x| self.file_name_with_stem(&x))
     }
 
     pub fn next_file_src_from_exif(&self) -> Option<PathBuf> {
@@ -141,9 +265,16 @@ impl ExifFile {
     }
 
     pub fn next_file_src_with_stem_name(&self, next_stem: &str) -> PathBuf {
-        self.src
-            .value()
-            .with_file_name(format!("{}.{}", next_stem, self.ext.value()))
+        self.src.with_file_name(self.file_name_with_stem(next_stem))
+    }
+
+    pub fn next_file_src_with_stem_and_ext(&self, next_stem: &str, ext: &OsStr) -> PathBuf {
+        self.src.with_file_name(file_name(next_stem, ext))
+    }
+
+    // We build the name from the OS string so the extension is kept byte for byte.
+    fn file_name_with_stem(&self, stem: &str) -> OsString {
+        file_name(stem, self.ext.value())
     }
 
     pub fn fetch_and_set_metadata(&mut self, cmd_path: &str) -> &Self {
@@ -152,7 +283,7 @@ impl ExifFile {
     }
 
     pub fn get_key(&self) -> String {
-        self.stem.value().into()
+        self.stem.to_string()
     }
 }
 
@@ -184,6 +315,15 @@ impl From<&InputFile> for ExifFile {
     }
 }
 
+fn file_name(stem: &str, ext: &OsStr) -> OsString {
+    let mut name = OsString::from(stem);
+    if !ext.is_empty() {
+        name.push(".");
+        name.push(ext);
+    }
+    name
+}
+
 // When getting the data for each item from the exiftool and stdout
 // it is passed as an array of objects serde does not automatically pares it.
 // We take away all the wrapper stuff and return a valid object that can be
@@ -207,38 +347,45 @@ fn obj_str_from_array_of_one(data: &str) -> Result<String, Box<dyn Error>> {
             line = &line[0..(line.len() - 1)];
         }
 
-        buffer.push_str(&line);
+        buffer.push_str(line);
 
         if line.len() == 1 && line.starts_with("}") {
             return Ok(buffer);
         }
     }
 
-    panic!("Should never get here.");
+    // Nothing to parse, the exiftool could not read the file.
+    Err("no metadata in the exiftool output".into())
 }
 
 // This function runs the exiftool command which's path is passed
 // as the cmd_path argument. And it will get the exif data
 // and return it as a JSON object in a string.
-// TODO: See if we need to return an error or doing these expects are ok
-fn get_exif_metadata_from_cmd(cmd_path: &str, path: &FilePath) -> Option<ExifMetadata> {
+// The path is passed as the raw OS string after the "--" so that neither
+// the names with invalid UTF-8 nor the names starting with "-" get mangled.
+pub fn read_exif_json(cmd_path: &str, path: &FilePath) -> Result<String, String> {
     let cmd = Command::new(cmd_path)
-        .args(["-j", path.as_str()])
+        .args(["-j", "--"])
+        .arg(path.value())
         .output()
-        .expect("tu run exiftool command");
+        .map_err(|err| format!("Error: running {}: {}", cmd_path, err))?;
 
-    let data = String::from_utf8(cmd.stdout).expect("convert the utf8 into a string");
-    let value = match obj_str_from_array_of_one(&data) {
-        Ok(value) => value,
-        Err(err) => {
-            eprintln!("Error: {}", err);
-            return None;
-        }
-    };
-    match serde_json::from_str::<ExifMetadata>(&value) {
+    // The exiftool echoes the file name back in the JSON as is. For the names
+    // that are not valid UTF-8 we only lose the "SourceFile" and "FileName" fields.
+    let data = String::from_utf8_lossy(&cmd.stdout);
+    obj_str_from_array_of_one(&data).map_err(|err| format!("Error: {}", err))
+}
+
+pub fn read_exif_metadata(cmd_path: &str, path: &FilePath) -> Result<ExifMetadata, String> {
+    let value = read_exif_json(cmd_path, path)?;
+    serde_json::from_str::<ExifMetadata>(&value).map_err(|err| format!("Error: {}", err))
+}
+
+pub(crate) fn get_exif_metadata_from_cmd(cmd_path: &str, path: &FilePath) -> Option<ExifMetadata> {
+    match read_exif_metadata(cmd_path, path) {
         Ok(value) => Some(value),
         Err(err) => {
-            eprintln!("Error: {}", err);
+            eprintln!("{}", err);
             None
         }
     }
@@ -263,6 +410,16 @@ impl FileNameGroupKey {
     pub fn value(&self) -> &str {
         &self.0
     }
+
+    // The directory and the stem. The directories compare by their
+    // components, so a directory comes right before its subdirectories,
+    // the same order the directory scan walks them in.
+    pub fn sort_key(&self) -> (&Path, &str) {
+        match self.0.rsplit_once('/') {
+            Some((dir, stem)) => (Path::new(dir), stem),
+            None => (Path::new(""), &self.0),
+        }
+    }
 }
 
 impl From<&str> for FileNameGroupKey {
@@ -277,9 +434,9 @@ impl From<&InputFile> for FileNameGroupKey {
     }
 }
 
-impl ToString for FileNameGroupKey {
-    fn to_string(&self) -> String {
-        self.value().to_owned()
+impl std::fmt::Display for FileNameGroupKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.value())
     }
 }
 
@@ -291,6 +448,7 @@ impl std::hash::Hash for FileNameGroupKey {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum FileNameGroup {
     Image {
@@ -321,6 +479,35 @@ pub enum FileNameGroup {
 }
 
 impl FileNameGroup {
+    // The date the group is named after.
+    pub fn date(&self) -> Option<NaiveDateTime> {
+        self.primary()
+            .and_then(|x| x.metadata.as_ref())
+            .and_then(|x| x.date_time_original.or(x.creation_date).or(x.name_date))
+    }
+
+    // A group named with the current template was renamed before, its date
+    // is in the name. Taking it from there saves the exiftool call when the
+    // folder is renamed again. False when the name is not a date.
+    pub fn date_from_name(&mut self) -> bool {
+        let Some(primary) = self.primary_mut() else {
+            return false;
+        };
+        let Some(date) = primary
+            .stem
+            .to_str()
+            .and_then(|x| NaiveDateTime::parse_from_str(x, NameTemplate::Current.format()).ok())
+        else {
+            return false;
+        };
+        primary.metadata = Some(ExifMetadata {
+            source_file: primary.src.value().to_string_lossy().to_string(),
+            name_date: Some(date),
+            ..Default::default()
+        });
+        true
+    }
+
     // This method is used to get all the file paths
     // so that we can do the renaming of each file.
     pub fn merge_into_rename_refs(&self) -> Vec<&ExifFile> {
@@ -351,6 +538,26 @@ impl FileNameGroup {
         merged
     }
 
+    // The file we read the metadata from to name the whole group.
+    // For the live images it is the image, because it has the richer exif.
+    pub fn primary_mut(&mut self) -> Option<&mut ExifFile> {
+        match self {
+            Self::Image { image, .. } => Some(image),
+            Self::LiveImage { image, .. } => Some(image),
+            Self::Video { video, .. } => Some(video),
+            _ => None,
+        }
+    }
+
+    pub fn primary(&self) -> Option<&ExifFile> {
+        match self {
+            Self::Image { image, .. } => Some(image),
+            Self::LiveImage { image, .. } => Some(image),
+            Self::Video { video, .. } => Some(video),
+            _ => None,
+        }
+    }
+
     pub fn group_key(&self) -> &FileNameGroupKey {
         match self {
             FileNameGroup::Image { key, .. } => key,
@@ -369,7 +576,7 @@ pub fn group_same_name_files(files: &[InputFile]) -> Vec<FileNameGroup> {
         let g = groups
             .entry(FileNameGroupKey::from(item))
             .or_insert((Vec::new(), Vec::new()));
-        if utils::is_primary_ext(item.ext.value()) {
+        if utils::is_primary_ext(item.ext.to_str().unwrap_or_default()) {
             g.0.push(ExifFile::from(item));
         } else {
             g.1.push(ExifFile::from(item));
@@ -448,7 +655,7 @@ pub fn group_same_name_files(files: &[InputFile]) -> Vec<FileNameGroup> {
             }
             _ => {
                 // 3. if we have a media vector length anything else:
-                if primary_files.len() > 0 {
+                if !primary_files.is_empty() {
                     file_name_groups.push(FileNameGroup::Uncertain {
                         key,
                         primary: primary_files,
@@ -464,9 +671,139 @@ pub fn group_same_name_files(files: &[InputFile]) -> Vec<FileNameGroup> {
         }
     }
 
+    sort_groups_by_name(&mut file_name_groups);
     file_name_groups
 }
 
+pub fn sort_groups_by_name(groups: &mut [FileNameGroup]) {
+    groups.sort_by(|a, b| a.group_key().sort_key().cmp(&b.group_key().sort_key()));
+}
+
+// The groups without a date go last, in the name order.
+pub fn sort_groups_by_date(groups: &mut [FileNameGroup]) {
+    groups.sort_by(|a, b| {
+        let (date_a, date_b) = (a.date(), b.date());
+        (date_a.is_none(), date_a, a.group_key().sort_key()).cmp(&(
+            date_b.is_none(),
+            date_b,
+            b.group_key().sort_key(),
+        ))
+    });
+}
+
+// The date names we know about. The files are named with the current one,
+// the others are left behind by older tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameTemplate {
+    Current,
+    // The camera uploads of Dropbox.
+    Dropbox,
+}
+
+impl NameTemplate {
+    pub const ALL: [NameTemplate; 2] = [NameTemplate::Current, NameTemplate::Dropbox];
+
+    pub fn format(&self) -> &'static str {
+        match self {
+            NameTemplate::Current => "%Y-%m-%d_%H.%M.%S",
+            NameTemplate::Dropbox => "%Y-%m-%d %H.%M.%S",
+        }
+    }
+
+    // How the template reads for the people.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            NameTemplate::Current => "YYYY-MM-DD_HH.MM.SS",
+            NameTemplate::Dropbox => "YYYY-MM-DD HH.MM.SS",
+        }
+    }
+
+    pub fn stem(&self, date: &NaiveDateTime) -> String {
+        date.format(self.format()).to_string()
+    }
+
+    pub fn detect(stem: &str) -> Option<NameTemplate> {
+        NameTemplate::ALL
+            .into_iter()
+            .find(|x| NaiveDateTime::parse_from_str(stem, x.format()).is_ok())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameState {
+    // Every file of the group already has the next name.
+    Unchanged,
+    // The group is named with an older template.
+    OldTemplate(NameTemplate),
+    Rename,
+}
+
+// The extension with the alias and the case of the options. An extension
+// that is not UTF-8 is kept as it is. With `Keep` an upper case extension
+// gets its alias in upper case too.
+pub fn next_ext(ext: &OsStr, options: &RenameOptions) -> OsString {
+    let Some(ext) = ext.to_str() else {
+        return ext.to_owned();
+    };
+    let alias = options
+        .ext_aliases
+        .iter()
+        .find(|x| x.0.eq_ignore_ascii_case(ext))
+        .map(|x| x.1.as_str());
+    let next = match (options.ext_case, alias) {
+        (ExtCase::Keep, None) => ext.to_string(),
+        (ExtCase::Keep, Some(alias)) if ext != ext.to_lowercase() => alias.to_uppercase(),
+        (ExtCase::Keep, Some(alias)) => alias.to_string(),
+        (ExtCase::Lower, x) => x.unwrap_or(ext).to_lowercase(),
+        (ExtCase::Upper, x) => x.unwrap_or(ext).to_uppercase(),
+    };
+    OsString::from(next)
+}
+
+// `dir` moves the file into an other folder (the folder of its event).
+fn next_src_with_options(
+    file: &ExifFile,
+    dir: Option<&Path>,
+    next_stem: &str,
+    options: &RenameOptions,
+) -> PathBuf {
+    let mut next_src =
+        file.next_file_src_with_stem_and_ext(next_stem, &next_ext(file.ext.value(), options));
+    if let (Some(dir), Some(name)) = (dir, next_src.file_name()) {
+        next_src = dir.join(name);
+    }
+    if options.normalize_nfc {
+        utils::file_name_to_nfc(&next_src)
+    } else {
+        next_src
+    }
+}
+
+// Tells if the group has to be renamed to `next_stem` at all. Running the
+// rename again on a folder that was done before leaves it as it is.
+pub fn name_state(
+    group: &FileNameGroup,
+    dir: Option<&Path>,
+    next_stem: &str,
+    options: &RenameOptions,
+) -> NameState {
+    let items = group.merge_into_rename_refs();
+    if items
+        .iter()
+        .all(|x| *x.src.value() == next_src_with_options(x, dir, next_stem, options))
+    {
+        return NameState::Unchanged;
+    }
+    match group
+        .primary()
+        .and_then(|x| x.stem.to_str())
+        .and_then(NameTemplate::detect)
+    {
+        Some(NameTemplate::Current) | None => NameState::Rename,
+        Some(template) => NameState::OldTemplate(template),
+    }
+}
+
 pub trait ExifNotifier {
     fn rename_success(&self, prev: &FilePath, next: &Path) -> ();
     fn rename_error(&self, prev: &FilePath, err: String) -> ();
@@ -474,6 +811,8 @@ pub trait ExifNotifier {
     fn rollback_error(&self, next: &Path, err: String) -> ();
     fn uncertain(&self, src: &FilePath) -> ();
     fn unsupported(&self, src: &FilePath) -> ();
+    // The file already has its next name, it is not touched.
+    fn unchanged(&self, _src: &FilePath) {}
 }
 
 pub fn rename_with_rollback<F: FileSystem, N: ExifNotifier>(
@@ -481,13 +820,56 @@ pub fn rename_with_rollback<F: FileSystem, N: ExifNotifier>(
     nf: &N,
     items: Vec<&ExifFile>,
     next_stem: &str,
+) -> usize {
+    rename_with_rollback_with_options(fs, nf, items, next_stem, &RenameOptions::default())
+}
+
+// Finds an other file that already uses the next name. The NFC and NFD
+// spellings of the name are considered to be the same name.
+fn find_name_collision<F: FileSystem>(fs: &F, src: &Path, next_src: &Path) -> Option<PathBuf> {
+    utils::file_name_variants(next_src)
+        .into_iter()
+        .filter(|x| x != src && fs.exists(x))
+        .find(|x| !fs.same_file(x, src))
+}
+
+pub fn rename_with_rollback_with_options<F: FileSystem, N: ExifNotifier>(
+    fs: &F,
+    nf: &N,
+    items: Vec<&ExifFile>,
+    next_stem: &str,
+    options: &RenameOptions,
+) -> usize {
+    rename_into_with_rollback(fs, nf, items, None, next_stem, options)
+}
+
+// Like the above, `dir` is where the files go instead of their own folder.
+pub fn rename_into_with_rollback<F: FileSystem, N: ExifNotifier>(
+    fs: &F,
+    nf: &N,
+    items: Vec<&ExifFile>,
+    dir: Option<&Path>,
+    next_stem: &str,
+    options: &RenameOptions,
 ) -> usize {
     let mut processed = vec![];
     let mut needs_rollback = false;
     for file in items {
         if !needs_rollback {
-            let next_src = file.next_file_src_with_stem_name(next_stem);
-            match fs.rename(&file.src.value(), &next_src) {
+            let next_src = next_src_with_options(file, dir, next_stem, options);
+            if next_src == *file.src.value() {
+                nf.unchanged(&file.src);
+                continue;
+            }
+            if let Some(existing) = find_name_collision(fs, file.src.value(), &next_src) {
+                nf.rename_error(
+                    &file.src,
+                    format!("'{}' already exists", existing.display()),
+                );
+                needs_rollback = true;
+                continue;
+            }
+            match fs.rename(file.src.value(), &next_src) {
                 Ok(_) => {
                     nf.rename_success(&file.src, &next_src);
                     processed.push((&file.src, next_src));
@@ -502,12 +884,20 @@ pub fn rename_with_rollback<F: FileSystem, N: ExifNotifier>(
 
     if needs_rollback {
         for file in processed.iter() {
-            match fs.rename(&file.1, file.0.value()) {
+            // An other file can have taken the old name since, it is not
+            // replaced.
+            let prev = file.0.value();
+            let result = if fs.exists(prev) && !fs.same_file(prev, &file.1) {
+                Err(format!("'{}' already exists", prev.display()))
+            } else {
+                fs.rename(&file.1, prev).map_err(|err| err.to_string())
+            };
+            match result {
                 Ok(_) => {
                     nf.rollback_success(&file.1, file.0);
                 }
                 Err(err) => {
-                    nf.rollback_error(&file.1, err.to_string());
+                    nf.rollback_error(&file.1, err);
                 }
             }
         }
@@ -524,7 +914,59 @@ mod test {
     use serde_json;
     use std::path::Path;
 
-    const DATE_FORMAT: &'static str = "%Y:%m:%d %H:%M:%S";
+    const DATE_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+    #[test]
+    fn exif_details_from_the_json() {
+        let value = serde_json::json!({
+            "Make": "Apple",
+            "Model": "iPhone 12",
+            "LensID": "iPhone 12 back camera",
+            "GPSLatitude": "53 deg 12' 16.92\" N",
+            "GPSLongitude": "6 deg 32' 13.56\" W",
+            "Duration": "0:01:02",
+            "Orientation": "Rotate 90 CW",
+            "ContentIdentifier": "A1B2",
+            "SubSecDateTimeOriginal": "2021:02:08 15:56:06.123+01:00",
+        });
+
+        let details = ExifDetails::from_json(&value);
+        assert_eq!(details.make.as_deref(), Some("Apple"));
+        assert_eq!(details.lens.as_deref(), Some("iPhone 12 back camera"));
+        assert!((details.gps_latitude.unwrap() - 53.2047).abs() < 0.0001);
+        assert!((details.gps_longitude.unwrap() + 6.5371).abs() < 0.0001);
+        assert_eq!(details.duration, Some(62.0));
+        assert_eq!(
+            details.sub_sec_date_time_original.as_deref(),
+            Some("2021:02:08 15:56:06.123+01:00")
+        );
+        assert_eq!(
+            ExifDetails::from_json(&serde_json::json!({})),
+            ExifDetails::default()
+        );
+    }
+
+    #[test]
+    fn exif_metadata_with_the_gps_position() {
+        let metadata = serde_json::from_value::<ExifMetadata>(serde_json::json!({
+            "SourceFile": "a.jpg",
+            "FileName": "a.jpg",
+            "FileSize": "1 kB",
+            "GPSLatitude": "53 deg 12' 16.92\" N",
+            "GPSLongitude": -6.5,
+        }))
+        .unwrap();
+
+        assert!((metadata.gps_latitude.unwrap() - 53.2047).abs() < 0.0001);
+        assert_eq!(metadata.gps_longitude, Some(-6.5));
+    }
+
+    #[test]
+    fn parse_the_durations() {
+        assert_eq!(parse_duration("12.35 s"), Some(12.35));
+        assert_eq!(parse_duration("1:00:01 (approx)"), Some(3601.0));
+        assert_eq!(parse_duration("soon"), None);
+    }
 
     #[test]
     fn test_parse_date_with_date_and_time() {
@@ -625,6 +1067,7 @@ mod test {
             exif_file.next_file_stem_from_exif(),
             Some("2021-10-10_12.34.56".to_string())
         );
+        assert_eq!(exif_file.date_source(), Some("DateTimeOriginal"));
     }
 
     #[test]
@@ -648,6 +1091,7 @@ mod test {
             exif_file.next_file_stem_from_exif(),
             Some("2021-10-10_12.34.56".to_string())
         );
+        assert_eq!(exif_file.date_source(), Some("CreationDate"));
     }
 
     #[test]
@@ -670,7 +1114,7 @@ mod test {
 
         assert_eq!(
             exif_file.next_file_name(),
-            Some("2021-10-10_12.34.56.jpg".to_string())
+            Some(OsString::from("2021-10-10_12.34.56.jpg"))
         );
     }
 
@@ -687,7 +1131,7 @@ mod test {
 
         match &groups[0] {
             FileNameGroup::Image { key, image, config } => {
-                assert_eq!(key.value(), "file");
+                assert_eq!(key.value(), "to/file");
                 assert_eq!(image.ext.value(), "jpg");
                 assert_eq!(config.len(), 0);
             }
@@ -718,7 +1162,7 @@ mod test {
 
         match &groups[0] {
             FileNameGroup::Image { key, image, config } => {
-                assert_eq!(key.value(), "file");
+                assert_eq!(key.value(), "to/file");
                 assert_eq!(image.ext.value(), "jpg");
                 assert_eq!(config.len(), 2);
             }
@@ -739,7 +1183,7 @@ mod test {
 
         match &groups[0] {
             FileNameGroup::Video { key, video, config } => {
-                assert_eq!(key.value(), "file");
+                assert_eq!(key.value(), "to/file");
                 assert_eq!(video.ext.value(), "mov");
                 assert_eq!(config.len(), 0);
             }
@@ -766,7 +1210,7 @@ mod test {
 
         match &groups[0] {
             FileNameGroup::Video { key, video, config } => {
-                assert_eq!(key.value(), "file");
+                assert_eq!(key.value(), "to/file");
                 assert_eq!(video.ext.value(), "mov");
                 assert_eq!(config.len(), 1);
             }
@@ -793,7 +1237,7 @@ mod test {
 
         match &groups[0] {
             FileNameGroup::Unsupported { key, config } => {
-                assert_eq!(key.value(), "file");
+                assert_eq!(key.value(), "to/file");
                 assert_eq!(config.len(), 2);
             }
             _ => panic!("Unexpected group type"),
@@ -824,7 +1268,7 @@ mod test {
                 video,
                 config,
             } => {
-                assert_eq!(key.value(), "file");
+                assert_eq!(key.value(), "to/file");
                 assert_eq!(image.ext.value(), "jpg");
                 assert_eq!(video.ext.value(), "mov");
                 assert_eq!(config.len(), 0);
@@ -840,12 +1284,12 @@ mod test {
         }
     }
     impl ExifNotifier for MockExifNotifer {
-        fn rename_success(&self, _prev: &FilePath, _next: &Path) -> () {}
-        fn rename_error(&self, _prev: &FilePath, _err: String) -> () {}
-        fn rollback_success(&self, _next: &Path, _prev: &FilePath) -> () {}
-        fn rollback_error(&self, _next: &Path, _err: String) -> () {}
-        fn uncertain(&self, _src: &FilePath) -> () {}
-        fn unsupported(&self, _src: &FilePath) -> () {}
+        fn rename_success(&self, _prev: &FilePath, _next: &Path) {}
+        fn rename_error(&self, _prev: &FilePath, _err: String) {}
+        fn rollback_success(&self, _next: &Path, _prev: &FilePath) {}
+        fn rollback_error(&self, _next: &Path, _err: String) {}
+        fn uncertain(&self, _src: &FilePath) {}
+        fn unsupported(&self, _src: &FilePath) {}
     }
 
     #[test]
@@ -864,7 +1308,7 @@ mod test {
         let files = vec![&image];
         let next_stem = "2021-10-10_12.34.56";
 
-        rename_with_rollback(&fs, &nf, files, &next_stem);
+        rename_with_rollback(&fs, &nf, files, next_stem);
         let renamed_files = fs.renamed_files.borrow();
         let first = renamed_files.first().unwrap();
 
@@ -873,6 +1317,107 @@ mod test {
         assert_eq!(first.1, PathBuf::from("path/to/2021-10-10_12.34.56.jpg"));
     }
 
+    #[test]
+    fn rename_with_rollback_leaves_the_named_files_alone() {
+        let fs = MockFileSystem::new();
+        let nf = MockExifNotifer::new();
+        let image = ExifFile::new(
+            &InputFile::new(
+                &FilePath::new(Path::new("path/to/2021-10-10_12.34.56.jpg")),
+                Path::new("path"),
+            ),
+            ExifMetadata {
+                ..Default::default()
+            },
+        );
+        let config = ExifFile::new(
+            &InputFile::new(
+                &FilePath::new(Path::new("path/to/file.xml")),
+                Path::new("path"),
+            ),
+            ExifMetadata {
+                ..Default::default()
+            },
+        );
+
+        rename_with_rollback(&fs, &nf, vec![&image, &config], "2021-10-10_12.34.56");
+        let renamed_files = fs.renamed_files.borrow();
+
+        assert_eq!(renamed_files.len(), 1);
+        assert_eq!(renamed_files[0].0, PathBuf::from("path/to/file.xml"));
+    }
+
+    #[test]
+    fn rename_with_rollback_does_not_replace_a_taken_name() {
+        let fs = MockFileSystem::new();
+        let nf = MockExifNotifer::new();
+        let file = |name: &str| {
+            ExifFile::new(
+                &InputFile::new(
+                    &FilePath::new(&Path::new("path/to").join(name)),
+                    Path::new("path"),
+                ),
+                ExifMetadata {
+                    ..Default::default()
+                },
+            )
+        };
+        // The sidecar can't be renamed and the old name of the image is
+        // taken by the time it goes back.
+        fs.existing_files.borrow_mut().extend([
+            PathBuf::from("path/to/2021-10-10_12.34.56.xmp"),
+            PathBuf::from("path/to/file.jpg"),
+        ]);
+
+        let (image, config) = (file("file.jpg"), file("file.xmp"));
+        rename_with_rollback(&fs, &nf, vec![&image, &config], "2021-10-10_12.34.56");
+        let renamed_files = fs.renamed_files.borrow();
+
+        assert_eq!(renamed_files.len(), 1);
+        assert_eq!(renamed_files[0].0, PathBuf::from("path/to/file.jpg"));
+    }
+
+    #[test]
+    fn name_state_of_the_groups() {
+        let group = |name: &str| {
+            let file = ExifFile::new(
+                &InputFile::new(
+                    &FilePath::new(&Path::new("path").join(name)),
+                    Path::new("path"),
+                ),
+                ExifMetadata {
+                    ..Default::default()
+                },
+            );
+            FileNameGroup::Image {
+                key: FileNameGroupKey::from(file.group_key.as_str()),
+                image: file,
+                config: vec![],
+            }
+        };
+        let options = RenameOptions::default();
+        let next_stem = "2021-10-10_12.34.56";
+
+        assert_eq!(
+            name_state(&group("2021-10-10_12.34.56.jpg"), None, next_stem, &options),
+            NameState::Unchanged
+        );
+        assert_eq!(
+            name_state(&group("2021-10-10 12.34.56.jpg"), None, next_stem, &options),
+            NameState::OldTemplate(NameTemplate::Dropbox)
+        );
+        // Named after an other date, the exif is right.
+        assert_eq!(
+            name_state(&group("2020-01-01_00.00.00.jpg"), None, next_stem, &options),
+            NameState::Rename
+        );
+        assert_eq!(
+            name_state(&group("IMG_1234.jpg"), None, next_stem, &options),
+            NameState::Rename
+        );
+        assert_eq!(NameTemplate::detect("2021-10-10 12.34"), None);
+    }
+
     #[test]
     fn rename_with_rollback_one_image_with_config_file() {
         let fs = MockFileSystem::new();
@@ -899,9 +1444,9 @@ mod test {
         let files = vec![&image, &config];
         let next_stem = "2021-10-10_12.34.56";
 
-        rename_with_rollback(&fs, &nf, files, &next_stem);
+        rename_with_rollback(&fs, &nf, files, next_stem);
         let renamed_files = fs.renamed_files.borrow();
-        let first = renamed_files.get(0).unwrap();
+        let first = renamed_files.first().unwrap();
         let second = renamed_files.get(1).unwrap();
 
         assert_eq!(renamed_files.len(), 2);
@@ -946,9 +1491,9 @@ mod test {
         let files = vec![&image, &config1, &config2];
         let next_stem = "2021-10-10_12.34.56";
 
-        rename_with_rollback(&fs, &nf, files, &next_stem);
+        rename_with_rollback(&fs, &nf, files, next_stem);
         let renamed_files = fs.renamed_files.borrow();
-        let first = renamed_files.get(0).unwrap();
+        let first = renamed_files.first().unwrap();
         let second = renamed_files.get(1).unwrap();
         let third = renamed_files.get(2).unwrap();
 
@@ -960,4 +1505,270 @@ mod test {
         assert_eq!(third.0, PathBuf::from("path/to/file.aae"));
         assert_eq!(third.1, PathBuf::from("path/to/2021-10-10_12.34.56.aae"));
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn rename_with_rollback_keeps_non_utf8_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let fs = MockFileSystem::new();
+        let nf = MockExifNotifer::new();
+        let image = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new(OsStr::from_bytes(b"path/Z\xfcrich/IMG.J\xd6G"))),
+            Path::new("path"),
+        ));
+
+        rename_with_rollback(&fs, &nf, vec![&image], "2021-10-10_12.34.56");
+        let renamed_files = fs.renamed_files.borrow();
+        let first = renamed_files.first().unwrap();
+
+        assert_eq!(
+            first.1.as_os_str().as_bytes(),
+            b"path/Z\xfcrich/2021-10-10_12.34.56.J\xd6G"
+        );
+    }
+
+    #[test]
+    fn group_same_name_files_in_different_directories() {
+        let input_files = vec![
+            InputFile::new(
+                &FilePath::new(Path::new("path/a/IMG_1.jpg")),
+                Path::new("path"),
+            ),
+            InputFile::new(
+                &FilePath::new(Path::new("path/b/IMG_1.jpg")),
+                Path::new("path"),
+            ),
+        ];
+
+        let groups = group_same_name_files(&input_files);
+
+        assert_eq!(groups.len(), 2);
+        assert!(groups
+            .iter()
+            .all(|x| matches!(x, FileNameGroup::Image { .. })));
+    }
+
+    #[test]
+    fn group_same_name_files_in_a_stable_order() {
+        let names = [
+            "a-b/IMG_1.jpg",
+            "a/x/IMG_1.jpg",
+            "IMG_2.jpg",
+            "a/IMG_2.jpg",
+            "IMG_1.jpg",
+        ];
+        let input_files = names
+            .iter()
+            .map(|x| {
+                InputFile::new(
+                    &FilePath::new(&Path::new("path").join(x)),
+                    Path::new("path"),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let keys = group_same_name_files(&input_files)
+            .iter()
+            .map(|x| x.group_key().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            keys,
+            vec!["IMG_1", "IMG_2", "a/IMG_2", "a/x/IMG_1", "a-b/IMG_1"]
+        );
+    }
+
+    #[test]
+    fn sort_groups_by_their_date() {
+        let group = |name: &str, date: Option<&str>| {
+            let file = InputFile::new(
+                &FilePath::new(&Path::new("path").join(name)),
+                Path::new("path"),
+            );
+            let metadata = ExifMetadata {
+                date_time_original: date
+                    .map(|x| NaiveDateTime::parse_from_str(x, DATE_FORMAT).unwrap()),
+                ..Default::default()
+            };
+            FileNameGroup::Image {
+                key: FileNameGroupKey::from(&file),
+                image: ExifFile::new(&file, metadata),
+                config: vec![],
+            }
+        };
+        let mut groups = vec![
+            group("b.jpg", None),
+            group("c.jpg", Some("2021:10:10 12:34:56")),
+            group("a.jpg", None),
+            group("d.jpg", Some("2020:10:10 12:34:56")),
+        ];
+
+        sort_groups_by_date(&mut groups);
+        let keys = groups
+            .iter()
+            .map(|x| x.group_key().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["d", "c", "a", "b"]);
+    }
+
+    #[test]
+    fn group_same_name_files_with_nfc_and_nfd_names() {
+        let input_files = vec![
+            InputFile::new(
+                &FilePath::new(Path::new("path/to/IMG_Z\u{fc}rich.jpg")),
+                Path::new("path"),
+            ),
+            InputFile::new(
+                &FilePath::new(Path::new("path/to/IMG_Zu\u{308}rich.xmp")),
+                Path::new("path"),
+            ),
+        ];
+
+        let groups = group_same_name_files(&input_files);
+
+        assert_eq!(groups.len(), 1);
+        match &groups[0] {
+            FileNameGroup::Image { key, config, .. } => {
+                assert_eq!(key.value(), "to/IMG_Z\u{fc}rich");
+                assert_eq!(config.len(), 1);
+            }
+            _ => panic!("Unexpected group type"),
+        }
+    }
+
+    #[test]
+    fn rename_with_rollback_normalizes_to_nfc() {
+        let fs = MockFileSystem::new();
+        let nf = MockExifNotifer::new();
+        let image = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new("path/to/file.jpg")),
+            Path::new("path"),
+        ));
+        let options = RenameOptions {
+            normalize_nfc: true,
+            ..Default::default()
+        };
+
+        rename_with_rollback_with_options(&fs, &nf, vec![&image], "Zu\u{308}rich", &options);
+        let renamed_files = fs.renamed_files.borrow();
+
+        assert_eq!(renamed_files[0].1, PathBuf::from("path/to/Z\u{fc}rich.jpg"));
+    }
+
+    #[test]
+    fn next_ext_with_the_case_and_the_aliases() {
+        let mut options = RenameOptions {
+            ext_aliases: HashMap::from([
+                ("jpeg".to_string(), "jpg".to_string()),
+                ("tiff".to_string(), "tif".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let ext = |x: &str, options: &RenameOptions| next_ext(OsStr::new(x), options);
+
+        assert_eq!(ext("JPG", &options), "JPG");
+        assert_eq!(ext("JPEG", &options), "JPG");
+        assert_eq!(ext("jpeg", &options), "jpg");
+        options.ext_case = ExtCase::Lower;
+        assert_eq!(ext("JPEG", &options), "jpg");
+        assert_eq!(ext("Tiff", &options), "tif");
+        assert_eq!(ext("XMP", &options), "xmp");
+        options.ext_case = ExtCase::Upper;
+        assert_eq!(ext("jpeg", &options), "JPG");
+        assert_eq!(ext("aae", &options), "AAE");
+    }
+
+    #[test]
+    fn rename_with_rollback_lowers_the_extensions_of_the_group() {
+        let fs = MockFileSystem::new();
+        let nf = MockExifNotifer::new();
+        let image = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new("path/to/IMG_1.JPEG")),
+            Path::new("path"),
+        ));
+        let config = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new("path/to/IMG_1.XMP")),
+            Path::new("path"),
+        ));
+        let options = RenameOptions {
+            ext_case: ExtCase::Lower,
+            ext_aliases: HashMap::from([("jpeg".to_string(), "jpg".to_string())]),
+            ..Default::default()
+        };
+
+        rename_with_rollback_with_options(
+            &fs,
+            &nf,
+            vec![&image, &config],
+            "2021-10-10_12.34.56",
+            &options,
+        );
+        let renamed_files = fs.renamed_files.borrow();
+
+        assert_eq!(
+            renamed_files[0].1,
+            PathBuf::from("path/to/2021-10-10_12.34.56.jpg")
+        );
+        assert_eq!(
+            renamed_files[1].1,
+            PathBuf::from("path/to/2021-10-10_12.34.56.xmp")
+        );
+    }
+
+    #[test]
+    fn rename_into_with_rollback_moves_the_group() {
+        let fs = MockFileSystem::new();
+        let nf = MockExifNotifer::new();
+        let image = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new("path/to/IMG_1.jpg")),
+            Path::new("path"),
+        ));
+        let config = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new("path/to/IMG_1.xmp")),
+            Path::new("path"),
+        ));
+        let dir = Path::new("path/2021/2021-10-10 Party");
+
+        let count = rename_into_with_rollback(
+            &fs,
+            &nf,
+            vec![&image, &config],
+            Some(dir),
+            "2021-10-10_12.34.56",
+            &RenameOptions::default(),
+        );
+        let renamed_files = fs.renamed_files.borrow();
+
+        assert_eq!(count, 2);
+        assert_eq!(renamed_files[0].1, dir.join("2021-10-10_12.34.56.jpg"));
+        assert_eq!(renamed_files[1].1, dir.join("2021-10-10_12.34.56.xmp"));
+    }
+
+    #[test]
+    fn rename_with_rollback_detects_nfd_collision() {
+        let fs = MockFileSystem::new();
+        fs.existing_files
+            .borrow_mut()
+            .push(PathBuf::from("path/to/Zu\u{308}rich.xmp"));
+        let nf = MockExifNotifer::new();
+        let image = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new("path/to/file.jpg")),
+            Path::new("path"),
+        ));
+        let config = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new("path/to/file.xmp")),
+            Path::new("path"),
+        ));
+
+        let count = rename_with_rollback(&fs, &nf, vec![&image, &config], "Z\u{fc}rich");
+        let renamed_files = fs.renamed_files.borrow();
+
+        // The image is renamed, the config collides and the image is rolled back.
+        assert_eq!(count, 1);
+        assert_eq!(renamed_files.len(), 2);
+        assert_eq!(renamed_files[1].0, PathBuf::from("path/to/Z\u{fc}rich.jpg"));
+        assert_eq!(renamed_files[1].1, PathBuf::from("path/to/file.jpg"));
+    }
 }
@@ -1,5 +1,5 @@
 use super::utils;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -14,8 +14,15 @@ impl FilePath {
         &self.0
     }
 
-    pub fn as_str(&self) -> &str {
-        &self.value().to_str().unwrap_or_default()
+    // The path can contain bytes that are not valid UTF-8 (Latin-1 names from
+    // older Windows machines). We never want to mangle such names, so the callers
+    // need to decide what to do when the path can't be represented as a string.
+    pub fn to_str(&self) -> Option<&str> {
+        self.value().to_str()
+    }
+
+    pub fn is_utf8(&self) -> bool {
+        self.to_str().is_some()
     }
 
     pub fn with_file_name<S: AsRef<OsStr>>(&self, file_name: S) -> PathBuf {
@@ -23,60 +30,59 @@ impl FilePath {
     }
 }
 
-impl ToString for FilePath {
-    fn to_string(&self) -> String {
-        self.value().to_string_lossy().into()
+impl std::fmt::Display for FilePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.value().display())
     }
 }
 
+// The stem and the extension keep the raw OS bytes so that renaming a file
+// puts back exactly what was there. Use the `Display` impl only for output.
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct FileStem(String);
+pub struct FileStem(OsString);
 
 impl FileStem {
-    // TODO: maybe it needs to reutrn an option or an error in case
-    // we can not convert it to string?
     pub fn new(path: &Path) -> Self {
-        let stem = path
-            .file_stem()
-            .expect("To have a file stem")
-            .to_string_lossy()
-            .into();
+        let stem = path.file_stem().expect("To have a file stem").to_owned();
         Self(stem)
     }
 
-    pub fn value(&self) -> &str {
+    pub fn value(&self) -> &OsStr {
         &self.0
     }
+
+    pub fn to_str(&self) -> Option<&str> {
+        self.value().to_str()
+    }
 }
 
-impl ToString for FileStem {
-    fn to_string(&self) -> String {
-        self.value().to_owned().into()
+impl std::fmt::Display for FileStem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.value().to_string_lossy())
     }
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct FileExt(String);
+pub struct FileExt(OsString);
 
 impl FileExt {
-    // TODO: maybe it needs to reutrn an option or an error in case
-    // we can not convert it to string?
     pub fn new(path: &Path) -> Self {
-        let ext = path
-            .extension()
-            .map(|i| i.to_string_lossy().into())
-            .unwrap_or_default();
+        let ext = path.extension().map(|i| i.to_owned()).unwrap_or_default();
         Self(ext)
     }
 
-    pub fn value(&self) -> &str {
+    pub fn value(&self) -> &OsStr {
         &self.0
     }
+
+    pub fn to_str(&self) -> Option<&str> {
+        self.value().to_str()
+    }
 }
 
-impl ToString for FileExt {
-    fn to_string(&self) -> String {
-        self.value().to_owned().into()
+impl std::fmt::Display for FileExt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.value().to_string_lossy())
     }
 }
 
@@ -89,9 +95,10 @@ pub enum FileType {
 
 impl From<&FileExt> for FileType {
     fn from(ext: &FileExt) -> Self {
-        if utils::is_img(ext.value()) {
+        let ext = ext.to_str().unwrap_or_default();
+        if utils::is_img(ext) {
             FileType::IMG
-        } else if utils::is_video(ext.value()) {
+        } else if utils::is_video(ext) {
             FileType::VIDEO
         } else {
             FileType::OTHER
@@ -128,8 +135,29 @@ impl InputFile {
         }
     }
 
+    // The lossy conversion would map different invalid names onto the same
+    // key, so the names that are not valid UTF-8 use the escaped form instead.
+    // The valid names are normalized to NFC so the macOS (NFD) copies of
+    // a file end up in the same group as the rest.
+    // The key starts with the relative directory, because only the files
+    // sitting next to each other belong together.
     pub fn hash_key(&self) -> String {
-        self.stem.to_string()
+        let stem = match self.stem.to_str() {
+            Some(stem) => utils::nfc(stem),
+            None => format!("{:?}", self.stem.value()),
+        };
+        match self
+            .src_relative
+            .value()
+            .parent()
+            .filter(|x| !x.as_os_str().is_empty())
+        {
+            Some(dir) => match dir.to_str() {
+                Some(dir) => format!("{}/{}", utils::nfc(dir), stem),
+                None => format!("{:?}/{}", dir.as_os_str(), stem),
+            },
+            None => stem,
+        }
     }
 }
 
@@ -150,5 +178,31 @@ mod test {
         assert_eq!(input_file.stem.value(), "file");
         assert_eq!(input_file.ext.value(), "jpg");
         assert_eq!(input_file.file_type, FileType::IMG);
+        assert_eq!(input_file.hash_key(), "to/file");
+    }
+
+    #[test]
+    fn hash_key_of_a_file_in_the_root() {
+        let input_file = InputFile::new(
+            &FilePath::new(Path::new("path/file.jpg")),
+            Path::new("path"),
+        );
+        assert_eq!(input_file.hash_key(), "file");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_an_input_file_from_non_utf8_name() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // "Zürich" encoded as Latin-1
+        let name = OsStr::from_bytes(b"path/to/Z\xfcrich.jpg");
+        let input_file = InputFile::new(&FilePath::new(Path::new(name)), Path::new("path"));
+
+        assert!(!input_file.src.is_utf8());
+        assert_eq!(input_file.stem.value().as_bytes(), b"Z\xfcrich");
+        assert_eq!(input_file.stem.to_str(), None);
+        assert_eq!(input_file.ext.value(), "jpg");
+        assert_eq!(input_file.file_type, FileType::IMG);
     }
 }
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// How much of the start and of the end of a file goes into the partial hash.
+// The files that only look alike usually differ in the headers already,
+// and the end catches the copies that were cut short.
+pub const PARTIAL_BLOCK_SIZE: u64 = 64 * 1024;
+
+fn read_block(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+// A cheap BLAKE3 hash of the size, the first and the last block of the file.
+// Two files with a different partial hash are never the same, two files with
+// the same partial hash still need the full hash to be sure.
+pub fn partial_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&size.to_le_bytes());
+
+    let mut buf = vec![0; PARTIAL_BLOCK_SIZE as usize];
+    let read = read_block(&mut file, &mut buf)?;
+    hasher.update(&buf[..read]);
+    if size > PARTIAL_BLOCK_SIZE {
+        // The blocks can overlap for the small files, that is fine.
+        file.seek(SeekFrom::Start(size.saturating_sub(PARTIAL_BLOCK_SIZE)))?;
+        let read = read_block(&mut file, &mut buf)?;
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+// The BLAKE3 hash of the whole content.
+pub fn full_hash(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn write_file(path: &Path, content: &[u8]) {
+        File::create(path).unwrap().write_all(content).unwrap();
+    }
+
+    #[test]
+    fn same_content_has_the_same_hashes() {
+        let temp_dir = tempdir().unwrap();
+        let a = temp_dir.path().join("a.jpg");
+        let b = temp_dir.path().join("b.jpg");
+        write_file(&a, b"the same bytes");
+        write_file(&b, b"the same bytes");
+
+        assert_eq!(partial_hash(&a).unwrap(), partial_hash(&b).unwrap());
+        assert_eq!(full_hash(&a).unwrap(), full_hash(&b).unwrap());
+    }
+
+    #[test]
+    fn partial_hash_misses_the_middle_but_full_hash_does_not() {
+        let temp_dir = tempdir().unwrap();
+        let a = temp_dir.path().join("a.jpg");
+        let b = temp_dir.path().join("b.jpg");
+        let mut content = vec![0u8; (PARTIAL_BLOCK_SIZE * 3) as usize];
+        write_file(&a, &content);
+        content[(PARTIAL_BLOCK_SIZE + 10) as usize] = 1;
+        write_file(&b, &content);
+
+        assert_eq!(partial_hash(&a).unwrap(), partial_hash(&b).unwrap());
+        assert_ne!(full_hash(&a).unwrap(), full_hash(&b).unwrap());
+    }
+
+    #[test]
+    fn partial_hash_sees_the_end_of_the_file() {
+        let temp_dir = tempdir().unwrap();
+        let a = temp_dir.path().join("a.jpg");
+        let b = temp_dir.path().join("b.jpg");
+        let mut content = vec![0u8; (PARTIAL_BLOCK_SIZE * 3) as usize];
+        write_file(&a, &content);
+        *content.last_mut().unwrap() = 1;
+        write_file(&b, &content);
+
+        assert_ne!(partial_hash(&a).unwrap(), partial_hash(&b).unwrap());
+    }
+}
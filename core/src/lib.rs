@@ -1,5 +1,16 @@
 pub mod dir;
+pub mod dupes;
 pub mod exif;
 pub mod file;
+pub mod hash;
+pub mod pipeline;
+pub mod similar;
 pub mod utils;
 pub mod config;
+pub mod dedupe;
+pub mod catalog;
+pub mod batch;
+pub mod busy;
+pub mod case;
+pub mod name;
+pub mod events;
@@ -0,0 +1,246 @@
+use super::exif::FileNameGroup;
+use chrono::format::{Item, StrftimeItems};
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+pub const SEQ_TOKEN: &str = "{seq}";
+
+// The names the groups get instead of `NameTemplate::Current`. The pattern
+// is a chrono format (`%Y-%m-%d`) with an optional `{seq}` for the number
+// of the group, e.g. `%Y-%m-%d_{seq}` or `Wedding_{seq}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamePattern {
+    pattern: String,
+}
+
+impl NamePattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if pattern.contains(['/', '\\']) {
+            return Err(format!("The name '{}' can't have a path in it", pattern));
+        }
+        let mut has_date = false;
+        for part in pattern.split(SEQ_TOKEN) {
+            for item in StrftimeItems::new(part) {
+                match item {
+                    Item::Error => {
+                        return Err(format!("The name '{}' is not a valid date format", pattern))
+                    }
+                    Item::Numeric(..) | Item::Fixed(_) => has_date = true,
+                    _ => {}
+                }
+            }
+            // The dates have no time zone, `%z` and `%Z` can't be written.
+            let mut sample = String::new();
+            if write!(sample, "{}", NaiveDateTime::default().format(part)).is_err() {
+                return Err(format!(
+                    "The name '{}' can't have a time zone (%z, %Z) in it",
+                    pattern
+                ));
+            }
+        }
+        // Every group would get the same name.
+        if !has_date && !pattern.contains(SEQ_TOKEN) {
+            return Err(format!(
+                "The name '{}' has no date or {}",
+                pattern, SEQ_TOKEN
+            ));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+        })
+    }
+
+    pub fn has_seq(&self) -> bool {
+        self.pattern.contains(SEQ_TOKEN)
+    }
+
+    // A pattern with `{seq}` needs the number, a group without one is not
+    // named.
+    pub fn stem(&self, date: &NaiveDateTime, seq: Option<&str>) -> Option<String> {
+        let parts = self
+            .pattern
+            .split(SEQ_TOKEN)
+            .map(|x| date.format(x).to_string())
+            .collect::<Vec<_>>();
+        match seq {
+            Some(seq) => Some(parts.join(seq)),
+            None if parts.len() == 1 => parts.into_iter().next(),
+            None => None,
+        }
+    }
+}
+
+// What the numbers of `{seq}` start over for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeqScope {
+    // One numbering for all the files, a whole shoot.
+    #[default]
+    Global,
+    Day,
+    Folder,
+}
+
+impl std::str::FromStr for SeqScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(SeqScope::Global),
+            "day" => Ok(SeqScope::Day),
+            "folder" => Ok(SeqScope::Folder),
+            _ => Err(format!("'{}' is not global, day or folder", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequence {
+    pub scope: SeqScope,
+    pub start: u64,
+    // Padded with zeros to this many digits.
+    pub width: usize,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self {
+            scope: SeqScope::Global,
+            start: 1,
+            width: 4,
+        }
+    }
+}
+
+impl Sequence {
+    pub fn format(&self, number: u64) -> String {
+        format!("{:0width$}", number, width = self.width)
+    }
+
+    // The numbers of the groups, in the order of `groups`. They are given
+    // in the order of the dates, a group (the RAW and the JPEG, the live
+    // photo) shares one number. The groups without a date get none.
+    pub fn number_groups(&self, groups: &[FileNameGroup]) -> Vec<Option<u64>> {
+        let mut dated = groups
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| x.date().map(|date| (date, x.group_key().sort_key(), i)))
+            .collect::<Vec<_>>();
+        dated.sort();
+
+        let mut next = HashMap::new();
+        let mut result = vec![None; groups.len()];
+        for (date, _, i) in dated {
+            let scope = match self.scope {
+                SeqScope::Global => String::new(),
+                SeqScope::Day => date.date().to_string(),
+                SeqScope::Folder => groups[i]
+                    .primary()
+                    .and_then(|x| x.src.value().parent())
+                    .map(|x| x.display().to_string())
+                    .unwrap_or_default(),
+            };
+            let number = next.entry(scope).or_insert(self.start);
+            result[i] = Some(*number);
+            *number += 1;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exif::{ExifFile, ExifMetadata, FileNameGroupKey};
+    use crate::file::{FilePath, InputFile};
+    use std::path::Path;
+
+    fn date(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn group(path: &str, value: &str) -> FileNameGroup {
+        let mut file = ExifFile::from(&InputFile::new(
+            &FilePath::new(Path::new(path)),
+            Path::new("root"),
+        ));
+        file.metadata = Some(ExifMetadata {
+            date_time_original: Some(date(value)),
+            ..Default::default()
+        });
+        FileNameGroup::Image {
+            key: FileNameGroupKey::from(file.group_key.as_str()),
+            image: file,
+            config: vec![],
+        }
+    }
+
+    #[test]
+    fn stems_of_a_pattern() {
+        let date = date("2024-08-16 10:20:30");
+
+        let pattern = NamePattern::parse("%Y-%m-%d_{seq}").unwrap();
+        assert!(pattern.has_seq());
+        assert_eq!(
+            pattern.stem(&date, Some("0001")),
+            Some("2024-08-16_0001".to_string())
+        );
+        assert_eq!(pattern.stem(&date, None), None);
+
+        let pattern = NamePattern::parse("Wedding_{seq}").unwrap();
+        assert_eq!(
+            pattern.stem(&date, Some("0123")),
+            Some("Wedding_0123".to_string())
+        );
+
+        let pattern = NamePattern::parse("%Y%m%d-%H%M%S").unwrap();
+        assert_eq!(
+            pattern.stem(&date, None),
+            Some("20240816-102030".to_string())
+        );
+
+        assert!(NamePattern::parse("Wedding").is_err());
+        assert!(NamePattern::parse("%Y/%m/{seq}").is_err());
+        assert!(NamePattern::parse("%Q_{seq}").is_err());
+        assert!(NamePattern::parse("%Y_%z_{seq}").is_err());
+        assert!(NamePattern::parse("%Y_%:z").is_err());
+        assert!(NamePattern::parse("%Y_%Z").is_err());
+    }
+
+    #[test]
+    fn number_groups_in_the_order_of_the_dates() {
+        let groups = vec![
+            group("root/a/IMG_1.jpg", "2024-08-16 12:00:00"),
+            group("root/a/IMG_2.jpg", "2024-08-16 09:00:00"),
+            group("root/b/IMG_3.jpg", "2024-08-17 08:00:00"),
+            group("root/b/IMG_4.jpg", "2024-08-15 08:00:00"),
+        ];
+
+        let seq = Sequence::default();
+        assert_eq!(
+            seq.number_groups(&groups),
+            vec![Some(3), Some(2), Some(4), Some(1)]
+        );
+        assert_eq!(seq.format(3), "0003");
+
+        let seq = Sequence {
+            scope: SeqScope::Day,
+            start: 10,
+            width: 2,
+        };
+        assert_eq!(
+            seq.number_groups(&groups),
+            vec![Some(11), Some(10), Some(10), Some(10)]
+        );
+
+        let seq = Sequence {
+            scope: SeqScope::Folder,
+            ..Default::default()
+        };
+        assert_eq!(
+            seq.number_groups(&groups),
+            vec![Some(2), Some(1), Some(2), Some(1)]
+        );
+    }
+}
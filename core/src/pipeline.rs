@@ -0,0 +1,384 @@
+use super::exif::{self, ExifMetadata, FileNameGroup};
+use super::file::FilePath;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+// Anything that can read the metadata of a single file. The pipeline calls
+// it from many threads at once.
+pub trait MetadataSource: Sync {
+    fn fetch(&self, path: &FilePath) -> Option<ExifMetadata>;
+}
+
+pub struct Exiftool {
+    cmd_path: String,
+}
+
+impl Exiftool {
+    pub fn new(cmd_path: &str) -> Self {
+        Self {
+            cmd_path: cmd_path.to_string(),
+        }
+    }
+}
+
+impl MetadataSource for Exiftool {
+    fn fetch(&self, path: &FilePath) -> Option<ExifMetadata> {
+        exif::get_exif_metadata_from_cmd(&self.cmd_path, path)
+    }
+}
+
+// Cloning the token shares the flag, so the FE can keep a clone
+// and cancel the pipeline that runs in an other thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultOrder {
+    // The results come in the same order as the groups were passed in.
+    Input,
+    // The results come as soon as they are ready.
+    Completion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct PipelineProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineSummary {
+    pub processed: usize,
+    pub cancelled: bool,
+}
+
+pub struct MetadataPipeline {
+    workers: usize,
+    order: ResultOrder,
+    cancel: CancelToken,
+}
+
+impl Default for MetadataPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetadataPipeline {
+    pub fn new() -> Self {
+        let workers = thread::available_parallelism()
+            .map(|x| x.get())
+            .unwrap_or(1);
+        Self {
+            workers,
+            order: ResultOrder::Input,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    pub fn order(mut self, order: ResultOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    // Fetches the metadata of the primary file of every group on the worker
+    // threads. The callbacks run on the calling thread, so they don't need to be
+    // `Send`. The groups without a primary file are passed through untouched.
+    // When the token gets cancelled, the groups already being processed
+    // are still delivered and the rest is dropped.
+    pub fn run<S, P, R>(
+        &self,
+        source: &S,
+        groups: Vec<FileNameGroup>,
+        on_progress: P,
+        on_result: R,
+    ) -> PipelineSummary
+    where
+        S: MetadataSource,
+        P: FnMut(PipelineProgress),
+        R: FnMut(usize, FileNameGroup),
+    {
+        self.run_stream(source, groups.into_iter(), on_progress, on_result)
+    }
+
+    // Same as `run`, but the groups are pulled while the workers are already
+    // busy, e.g. straight from the `dir::scan`. When the iterator can't tell
+    // its length, the progress total is the number of groups seen so far.
+    pub fn run_stream<S, I, P, R>(
+        &self,
+        source: &S,
+        groups: I,
+        mut on_progress: P,
+        mut on_result: R,
+    ) -> PipelineSummary
+    where
+        S: MetadataSource,
+        I: Iterator<Item = FileNameGroup> + Send,
+        P: FnMut(PipelineProgress),
+        R: FnMut(usize, FileNameGroup),
+    {
+        let known_total = match groups.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(lower),
+            _ => None,
+        };
+        let jobs = Mutex::new(groups.enumerate());
+        let seen = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel::<(usize, FileNameGroup)>();
+        let mut processed = 0;
+        let workers = known_total.map_or(self.workers, |x| self.workers.min(x));
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+                let jobs = &jobs;
+                let seen = &seen;
+                scope.spawn(move || loop {
+                    if self.cancel.is_cancelled() {
+                        break;
+                    }
+                    let Some((i, mut group)) = jobs.lock().unwrap().next() else {
+                        break;
+                    };
+                    seen.fetch_max(i + 1, Ordering::Relaxed);
+                    // The metadata can be known already, see `date_from_name`.
+                    if let Some(primary) = group.primary_mut().filter(|x| x.metadata.is_none()) {
+                        primary.metadata = source.fetch(&primary.src);
+                    }
+                    if tx.send((i, group)).is_err() {
+                        break;
+                    }
+                });
+            }
+            // Once the workers are done, the channel closes and the loop below ends.
+            drop(tx);
+
+            let mut pending = BTreeMap::new();
+            let mut next_index = 0;
+            for (i, group) in rx {
+                processed += 1;
+                on_progress(PipelineProgress {
+                    done: processed,
+                    total: known_total.unwrap_or_else(|| seen.load(Ordering::Relaxed)),
+                });
+                match self.order {
+                    ResultOrder::Completion => on_result(i, group),
+                    ResultOrder::Input => {
+                        pending.insert(i, group);
+                        while let Some(group) = pending.remove(&next_index) {
+                            on_result(next_index, group);
+                            next_index += 1;
+                        }
+                    }
+                }
+            }
+            // After a cancel there can be gaps in the indexes.
+            // We still hand over what we have, in order.
+            for (i, group) in pending {
+                on_result(i, group);
+            }
+        });
+
+        PipelineSummary {
+            processed,
+            cancelled: self.cancel.is_cancelled(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exif::group_same_name_files;
+    use crate::file::InputFile;
+    use std::path::Path;
+
+    struct MockSource {
+        calls: AtomicUsize,
+    }
+
+    impl MetadataSource for MockSource {
+        fn fetch(&self, path: &FilePath) -> Option<ExifMetadata> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Some(ExifMetadata {
+                source_file: path.to_string(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn groups(count: usize) -> Vec<FileNameGroup> {
+        let files = (0..count)
+            .map(|i| {
+                InputFile::new(
+                    &FilePath::new(Path::new(&format!("path/IMG_{:04}.jpg", i))),
+                    Path::new("path"),
+                )
+            })
+            .collect::<Vec<_>>();
+        group_same_name_files(&files)
+    }
+
+    #[test]
+    fn pipeline_fetches_metadata_in_input_order() {
+        let source = MockSource {
+            calls: AtomicUsize::new(0),
+        };
+        let input = groups(50);
+        let expected = input
+            .iter()
+            .map(|x| x.group_key().clone())
+            .collect::<Vec<_>>();
+        let mut progress = vec![];
+        let mut results = vec![];
+
+        let summary = MetadataPipeline::new().workers(4).run(
+            &source,
+            input,
+            |x| progress.push(x.done),
+            |i, group| results.push((i, group)),
+        );
+
+        assert_eq!(summary.processed, 50);
+        assert!(!summary.cancelled);
+        assert_eq!(source.calls.load(Ordering::Relaxed), 50);
+        assert_eq!(progress, (1..=50).collect::<Vec<_>>());
+        for (pos, (i, group)) in results.iter().enumerate() {
+            assert_eq!(pos, *i);
+            assert_eq!(group.group_key(), &expected[pos]);
+            assert!(group.primary().unwrap().metadata.is_some());
+        }
+    }
+
+    #[test]
+    fn pipeline_passes_through_groups_without_primary() {
+        let source = MockSource {
+            calls: AtomicUsize::new(0),
+        };
+        let files = vec![InputFile::new(
+            &FilePath::new(Path::new("path/file.xmp")),
+            Path::new("path"),
+        )];
+        let mut results = vec![];
+
+        MetadataPipeline::new().order(ResultOrder::Completion).run(
+            &source,
+            group_same_name_files(&files),
+            |_| {},
+            |_, x| results.push(x),
+        );
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(source.calls.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn pipeline_skips_the_groups_dated_by_their_name() {
+        let source = MockSource {
+            calls: AtomicUsize::new(0),
+        };
+        let files = ["path/2024-08-16_10.20.30.jpg", "path/IMG_0001.jpg"]
+            .map(|x| InputFile::new(&FilePath::new(Path::new(x)), Path::new("path")));
+        let mut input = group_same_name_files(&files);
+        let named = input
+            .iter_mut()
+            .map(|x| x.date_from_name())
+            .collect::<Vec<_>>();
+        assert_eq!(named, vec![true, false]);
+        let mut results = vec![];
+
+        MetadataPipeline::new().run(&source, input, |_| {}, |_, x| results.push(x));
+
+        assert_eq!(source.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            results[0].date().map(|x| x.to_string()),
+            Some("2024-08-16 10:20:30".to_string())
+        );
+        assert_eq!(
+            results[0].primary().unwrap().date_source(),
+            Some("FileName")
+        );
+    }
+
+    #[test]
+    fn pipeline_runs_on_a_stream_of_groups() {
+        let source = MockSource {
+            calls: AtomicUsize::new(0),
+        };
+        // The filter hides the length, like the groups coming from a scan.
+        let stream = groups(20).into_iter().filter(|_| true);
+        let mut totals = vec![];
+        let mut results = vec![];
+
+        let summary = MetadataPipeline::new().workers(3).run_stream(
+            &source,
+            stream,
+            |x| totals.push(x.total),
+            |i, _| results.push(i),
+        );
+
+        assert_eq!(summary.processed, 20);
+        assert_eq!(results, (0..20).collect::<Vec<_>>());
+        assert!(totals.iter().all(|x| *x > 0 && *x <= 20));
+    }
+
+    struct CancellingSource {
+        token: CancelToken,
+        calls: AtomicUsize,
+    }
+
+    impl MetadataSource for CancellingSource {
+        fn fetch(&self, _path: &FilePath) -> Option<ExifMetadata> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) == 4 {
+                self.token.cancel();
+            }
+            None
+        }
+    }
+
+    #[test]
+    fn pipeline_stops_when_cancelled() {
+        let token = CancelToken::new();
+        let source = CancellingSource {
+            token: token.clone(),
+            calls: AtomicUsize::new(0),
+        };
+        let mut results = 0;
+
+        let summary = MetadataPipeline::new().workers(1).cancel_token(token).run(
+            &source,
+            groups(50),
+            |_| {},
+            |_, _| results += 1,
+        );
+
+        assert!(summary.cancelled);
+        assert_eq!(summary.processed, 5);
+        assert_eq!(results, 5);
+    }
+}
@@ -0,0 +1,206 @@
+use image::imageops::FilterType;
+use image::ImageFormat;
+use std::path::Path;
+
+// The images we can decode without the system libraries. HEIC and the RAW
+// formats need a native decoder, so they are left out of the similar search.
+pub fn is_supported(path: &Path) -> bool {
+    ImageFormat::from_path(path)
+        .map(|x| x.reading_enabled())
+        .unwrap_or(false)
+}
+
+// The difference hash (dHash) of an image. The image is shrunk to 9x8 gray
+// pixels and every bit says if a pixel is brighter than its right neighbour.
+// A re-export or a recompression changes only a couple of bits, so the
+// Hamming distance between two hashes tells how alike the images look.
+pub fn dhash(path: &Path) -> Result<u64, String> {
+    let img = image::open(path).map_err(|err| format!("Error: {}: {}", path.display(), err))?;
+    Ok(dhash_from_image(&img))
+}
+
+fn dhash_from_image(img: &image::DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+struct BkNode {
+    hash: u64,
+    id: usize,
+    // The children by their distance from this node.
+    children: Vec<(u32, BkNode)>,
+}
+
+// A BK-tree over the Hamming distance. A search only walks the children
+// whose distance is within the threshold from the query distance, which
+// skips most of the tree instead of comparing against every image.
+#[derive(Default)]
+pub struct BkTree {
+    root: Option<BkNode>,
+}
+
+impl BkTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, hash: u64, id: usize) {
+        let Some(mut node) = self.root.as_mut() else {
+            self.root = Some(BkNode {
+                hash,
+                id,
+                children: vec![],
+            });
+            return;
+        };
+        loop {
+            let distance = hamming(node.hash, hash);
+            match node.children.iter().position(|(d, _)| *d == distance) {
+                Some(i) => node = &mut node.children[i].1,
+                None => {
+                    node.children.push((
+                        distance,
+                        BkNode {
+                            hash,
+                            id,
+                            children: vec![],
+                        },
+                    ));
+                    return;
+                }
+            }
+        }
+    }
+
+    // The ids of all the hashes within the threshold (inclusive).
+    pub fn find(&self, hash: u64, threshold: u32) -> Vec<usize> {
+        let mut found = vec![];
+        let mut stack = self.root.iter().collect::<Vec<_>>();
+        while let Some(node) = stack.pop() {
+            let distance = hamming(node.hash, hash);
+            if distance <= threshold {
+                found.push(node.id);
+            }
+            for (d, child) in node.children.iter() {
+                if d.abs_diff(distance) <= threshold {
+                    stack.push(child);
+                }
+            }
+        }
+        found
+    }
+}
+
+fn find_root(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    // Point everything on the way straight to the root.
+    let mut i = i;
+    while parents[i] != root {
+        let next = parents[i];
+        parents[i] = root;
+        i = next;
+    }
+    root
+}
+
+// Groups the hashes that are within the threshold of each other. The groups
+// are transitive: when A is close to B and B to C, all three end up together.
+// Returns the indexes into `hashes`, only the groups with more than one item.
+pub fn cluster(hashes: &[u64], threshold: u32) -> Vec<Vec<usize>> {
+    let mut tree = BkTree::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        tree.insert(*hash, i);
+    }
+
+    let mut parents = (0..hashes.len()).collect::<Vec<_>>();
+    for (i, hash) in hashes.iter().enumerate() {
+        for j in tree.find(*hash, threshold) {
+            let (a, b) = (find_root(&mut parents, i), find_root(&mut parents, j));
+            if a != b {
+                parents[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut clusters = std::collections::BTreeMap::<usize, Vec<usize>>::new();
+    for i in 0..hashes.len() {
+        let root = find_root(&mut parents, i);
+        clusters.entry(root).or_default().push(i);
+    }
+    clusters.into_values().filter(|x| x.len() > 1).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{DynamicImage, GrayImage, Luma};
+
+    fn gradient(width: u32, height: u32, flip: bool) -> DynamicImage {
+        let img = GrayImage::from_fn(width, height, |x, y| {
+            let value = ((x * 255 / width) + (y * 50 / height)) as u8;
+            Luma([if flip { 255 - value } else { value }])
+        });
+        DynamicImage::ImageLuma8(img)
+    }
+
+    #[test]
+    fn dhash_ignores_the_size_of_the_image() {
+        let big = dhash_from_image(&gradient(640, 480, false));
+        let small = dhash_from_image(&gradient(160, 120, false));
+        let other = dhash_from_image(&gradient(640, 480, true));
+
+        assert!(hamming(big, small) <= 4);
+        assert!(hamming(big, other) > 32);
+    }
+
+    #[test]
+    fn dhash_reads_a_png_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("a.png");
+        gradient(64, 64, false).save(&path).unwrap();
+
+        assert!(is_supported(&path));
+        assert!(!is_supported(Path::new("a.heic")));
+        assert_eq!(
+            dhash(&path).unwrap(),
+            dhash_from_image(&gradient(64, 64, false))
+        );
+    }
+
+    #[test]
+    fn bk_tree_finds_the_hashes_within_the_threshold() {
+        let hashes = [0b0000u64, 0b0001, 0b0011, 0b1111_0000, u64::MAX];
+        let mut tree = BkTree::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            tree.insert(*hash, i);
+        }
+
+        let mut found = tree.find(0, 2);
+        found.sort();
+        assert_eq!(found, vec![0, 1, 2]);
+        assert_eq!(tree.find(u64::MAX, 0), vec![4]);
+    }
+
+    #[test]
+    fn cluster_joins_the_chains_of_close_hashes() {
+        let hashes = [0b0000u64, 0b0011, 0b1111, u64::MAX, u64::MAX - 1];
+        assert_eq!(cluster(&hashes, 2), vec![vec![0, 1, 2], vec![3, 4]]);
+        assert_eq!(cluster(&hashes, 0), Vec::<Vec<usize>>::new());
+    }
+}
@@ -1,12 +1,20 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
 
 // this is the list of all available and image extensions that are allowed to check
-pub const IMGS: &'static [&'static str] = &[
+pub const IMGS: &[&str] = &[
     "cr3", "bmp", "cr2", "dng", "heic", "jpeg", "jpg", "nef", "png", "raf", "raw", "rw2", "svg",
     "tif", "tiff", "webp",
 ];
 
-pub const VIDEOS: &'static [&'static str] = &["avi", "m4v", "mov", "mp4", "mpg"];
+pub const VIDEOS: &[&str] = &["avi", "m4v", "mov", "mp4", "mpg"];
+
+// The files the cameras and the editors write next to a photo or a video,
+// with the same name: the edits (.xmp, .aae, .dop, .pp3) and the thumbnails.
+pub const SIDECARS: &[&str] = &["aae", "dop", "pp3", "thm", "xmp"];
+
+// The files the operating systems drop into the folders on their own.
+pub const SYSTEM_FILES: &[&str] = &["thumbs.db", "desktop.ini", "ehthumbs.db"];
 
 fn get_ext(path: &Path) -> String {
     let ext = path.extension().unwrap_or_default().to_ascii_lowercase();
@@ -29,6 +37,12 @@ pub fn is_img_ext(path: &Path) -> bool {
     IMGS.contains(&ext.as_str())
 }
 
+pub fn is_system_file(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_ascii_lowercase();
+    let name = name.to_str().unwrap_or_default();
+    name.starts_with('.') || SYSTEM_FILES.contains(&name)
+}
+
 pub fn is_video(ext: &str) -> bool {
     let ext = ext.to_lowercase();
     let ext = ext.as_str();
@@ -48,3 +62,60 @@ pub fn is_primary_ext(ext: &str) -> bool {
 pub fn path_to_string(path: &Path) -> String {
     path.to_string_lossy().to_string()
 }
+
+// Files copied from macOS often have the NFD (decomposed) names while the
+// other systems produce NFC. Two names are the same name for us when their
+// NFC forms are equal.
+pub fn nfc(value: &str) -> String {
+    value.nfc().collect()
+}
+
+pub fn nfd(value: &str) -> String {
+    value.nfd().collect()
+}
+
+// The names that are not valid UTF-8 are returned as they are.
+pub fn file_name_to_nfc(path: &Path) -> PathBuf {
+    match path.file_name().and_then(|x| x.to_str()) {
+        Some(name) => path.with_file_name(nfc(name)),
+        None => path.to_path_buf(),
+    }
+}
+
+// All the spellings of the file name that a user would consider the same name.
+pub fn file_name_variants(path: &Path) -> Vec<PathBuf> {
+    let mut variants = vec![path.to_path_buf()];
+    if let Some(name) = path.file_name().and_then(|x| x.to_str()) {
+        for next in [nfc(name), nfd(name)] {
+            let next = path.with_file_name(next);
+            if !variants.contains(&next) {
+                variants.push(next);
+            }
+        }
+    }
+    variants
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nfc_and_nfd_names_are_the_same_name() {
+        let composed = "IMG_Z\u{fc}rich";
+        let decomposed = "IMG_Zu\u{308}rich";
+
+        assert_ne!(composed, decomposed);
+        assert_eq!(nfc(composed), nfc(decomposed));
+        assert_eq!(
+            file_name_to_nfc(Path::new("path/IMG_Zu\u{308}rich.jpg")),
+            PathBuf::from("path/IMG_Z\u{fc}rich.jpg")
+        );
+    }
+
+    #[test]
+    fn file_name_variants_of_ascii_name() {
+        let variants = file_name_variants(Path::new("path/IMG_1234.jpg"));
+        assert_eq!(variants, vec![PathBuf::from("path/IMG_1234.jpg")]);
+    }
+}
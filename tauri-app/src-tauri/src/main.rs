@@ -1,12 +1,17 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use eximd::config::FileSystem;
+use eximd::config::{FileSystem, RenameOptions};
+use eximd::dedupe::{Candidate, DedupeAction, KeepPolicy, Trash, UndoLog};
+use eximd::dir::{scan, CollectOptions, ScanEvent};
+use eximd::dupes::{Confidence, DuplicateCluster, DuplicateFinder, MemoryStore};
+use eximd::events::EventOptions;
 use eximd::exif::{ExifFile, FileNameGroup, FileNameGroupKey};
 use eximd::file::FilePath;
+use eximd::pipeline::{CancelToken, Exiftool, MetadataPipeline, ResultOrder};
 use serde::ser::SerializeStruct;
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use tauri::{AppHandle, Manager, Window};
@@ -17,19 +22,26 @@ use tauri::{AppHandle, Manager, Window};
 #[derive(Default, Debug)]
 struct AppState {
     source: Mutex<PathBuf>,
+    collect_options: Mutex<CollectOptions>,
     file_group: Arc<Mutex<Vec<FileNameGroup>>>,
-    exiffing_handles: Arc<Mutex<Vec<(JoinHandle<()>, Arc<AtomicBool>)>>>,
+    exiffing_handles: Arc<Mutex<Vec<(JoinHandle<()>, CancelToken)>>>,
+    // Kept for as long as the app runs, so a second search is quick.
+    hash_store: Arc<Mutex<MemoryStore>>,
+    dupes_handles: Arc<Mutex<Vec<(JoinHandle<()>, CancelToken)>>>,
 }
 
 #[derive(Debug, Clone)]
 struct FileNameGroupV(FileNameGroup);
 
+// The names that are not valid UTF-8 can only be shown lossy in the FE.
+// We flag them so the FE can report them instead of pretending they are fine.
 fn exif_file_to_json(file: &ExifFile) -> serde_json::Value {
     serde_json::json!({
-        "src": file.src.value(),
-        "src_relative": file.src_relative.value(),
-        "stem": file.stem.value(),
-        "ext": file.ext.value(),
+        "src": file.src.to_string(),
+        "src_relative": file.src_relative.to_string(),
+        "stem": file.stem.to_string(),
+        "ext": file.ext.to_string(),
+        "lossy": !file.src.is_utf8(),
     })
 }
 
@@ -118,22 +130,22 @@ struct DropInputPayload {
 #[derive(Debug, serde::Serialize, Clone)]
 struct ExifFileData {
     key: String,
-    src: PathBuf,
-    src_next: PathBuf,
+    src: String,
+    src_next: String,
     file_name_next: String,
     ext: String,
 }
 
 impl ExifFileData {
-    fn new(item: &ExifFile, next_src: &std::path::PathBuf) -> Self {
+    fn new(item: &ExifFile, next_src: &std::path::Path) -> Self {
         Self {
             key: item.group_key.to_owned(),
-            src: item.src.value().to_owned(),
-            src_next: next_src.clone(),
+            src: item.src.to_string(),
+            src_next: next_src.to_string_lossy().to_string(),
             file_name_next: item
                 .next_file_stem_from_exif()
                 .unwrap_or("ERROR".to_string()),
-            ext: item.ext.value().into(),
+            ext: item.ext.to_string(),
         }
     }
 }
@@ -142,8 +154,8 @@ impl ExifFileData {
 fn cancel_exif_collection_cmd(state: tauri::State<'_, Arc<AppState>>) -> Result<(), String> {
     let mut handles = state.exiffing_handles.lock().unwrap();
 
-    for (_, flag) in handles.iter() {
-        flag.store(true, Ordering::Relaxed);
+    for (_, token) in handles.iter() {
+        token.cancel();
     }
 
     while let Some((handle, _)) = handles.pop() {
@@ -160,73 +172,51 @@ fn start_exif_collection_cmd(
     state: tauri::State<'_, Arc<AppState>>,
     window: Window,
 ) -> Result<(), String> {
-    let mut file_group = { state.file_group.lock().unwrap().clone() };
+    let file_group = { state.file_group.lock().unwrap().clone() };
     let resource_path = app_handle
         .path_resolver()
         .resolve_resource("../binaries")
         .ok_or_else(|| "Failed to resolve resource dir for exiftool")?;
     let state_clone = std::sync::Arc::clone(&state);
-    let cancel_flag = Arc::new(AtomicBool::new(false));
-    let cancle_flag_clone = Arc::clone(&cancel_flag);
+    let cancel_token = CancelToken::new();
+    let pipeline = MetadataPipeline::new()
+        .order(ResultOrder::Completion)
+        .cancel_token(cancel_token.clone());
 
     let handle = thread::spawn(move || {
         let cmd_path = resource_path
             .join("exiftool/exiftool")
             .to_string_lossy()
             .to_string();
-
-        for (i, mut group) in file_group.iter_mut().enumerate() {
-            if cancle_flag_clone.load(Ordering::Relaxed) {
-                println!("Exif collection thread cancelling");
-                break;
-            }
-
-            match &mut group {
-                FileNameGroup::Image { image, .. } => {
-                    image.fetch_and_set_metadata(&cmd_path); // this is blocking.....
-                    if let Some(next_src) = image.next_file_src_from_exif() {
-                        let next_metadata = &image.metadata;
-                        let mut file_group = state_clone.file_group.lock().unwrap();
-                        if let FileNameGroup::Image { ref mut image, .. } = file_group[i] {
-                            image.metadata = next_metadata.clone();
-                        }
-                        window
-                            .emit("EXIF_FILE_DATA", ExifFileData::new(&image, &next_src))
-                            .expect("send message to the FE");
-                    } else {
-                    }
-                }
-                FileNameGroup::Video { video, .. } => {
-                    video.fetch_and_set_metadata(&cmd_path);
-                    if let Some(next_src) = video.next_file_src_from_exif() {
-                        let next_metadata = &video.metadata;
-                        let mut file_group = state_clone.file_group.lock().unwrap();
-                        if let FileNameGroup::Video { ref mut video, .. } = file_group[i] {
-                            video.metadata = next_metadata.clone();
-                        }
-                        window
-                            .emit("EXIF_FILE_DATA", ExifFileData::new(&video, &next_src))
-                            .expect("send message to the FE");
+        let source = Exiftool::new(&cmd_path);
+
+        let summary = pipeline.run(
+            &source,
+            file_group,
+            |progress| {
+                window
+                    .emit("EXIF_COLLECTION_PROGRESS", progress)
+                    .expect("send message to the FE");
+            },
+            |i, group| {
+                // Maybe create a new event that would notify the FE
+                // with the list of all the items we want to ignore?
+                let Some(primary) = group.primary() else {
+                    return;
+                };
+                if let Some(next_src) = primary.next_file_src_from_exif() {
+                    let mut file_group = state_clone.file_group.lock().unwrap();
+                    if let Some(item) = file_group.get_mut(i).and_then(|x| x.primary_mut()) {
+                        item.metadata = primary.metadata.clone();
                     }
+                    window
+                        .emit("EXIF_FILE_DATA", ExifFileData::new(primary, &next_src))
+                        .expect("send message to the FE");
                 }
-                FileNameGroup::LiveImage { image, .. } => {
-                    image.fetch_and_set_metadata(&cmd_path);
-                    if let Some(next_src) = image.next_file_src_from_exif() {
-                        let next_metadata = &image.metadata;
-                        let mut file_group = state_clone.file_group.lock().unwrap();
-                        if let FileNameGroup::LiveImage { ref mut image, .. } = file_group[i] {
-                            image.metadata = next_metadata.clone();
-                        }
-                        window
-                            .emit("EXIF_FILE_DATA", ExifFileData::new(&image, &next_src))
-                            .expect("send message to the FE");
-                    }
-                }
-                _ => {
-                    // Maybe create a new event that would notify the FE
-                    // with the list of all the items we want to ignore?
-                }
-            }
+            },
+        );
+        if summary.cancelled {
+            println!("Exif collection thread cancelling");
         }
 
         window
@@ -238,7 +228,7 @@ fn start_exif_collection_cmd(
         .exiffing_handles
         .lock()
         .unwrap()
-        .push((handle, cancel_flag));
+        .push((handle, cancel_token));
 
     Ok(())
 }
@@ -294,6 +284,8 @@ impl eximd::exif::ExifNotifier for TauriCommitNotifier<'_> {
 #[derive(Debug, serde::Deserialize)]
 struct CommitRenamePayload {
     items: Vec<FileNameGroupKey>,
+    #[serde(default)]
+    options: RenameOptions,
 }
 
 pub struct TempFileSystem {}
@@ -309,6 +301,31 @@ impl FileSystem for TempFileSystem {
         println!("renaming {:?}", prev);
         Ok(())
     }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+        println!("linking {:?} -> {:?}", link, original);
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, _to: &Path) -> std::io::Result<()> {
+        println!("copying {:?}", from);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        println!("creating {:?}", path);
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, _contents: &[u8]) -> std::io::Result<()> {
+        println!("writing {:?}", path);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("removing {:?}", path);
+        Ok(())
+    }
 }
 
 #[derive(Debug, serde::Serialize, Clone)]
@@ -326,6 +343,7 @@ async fn commit_rename_groups_cmd(
     let fs = eximd::config::RealFileSystem::new(&eximd::config::RunType::Exec);
     // let fs = TempFileSystem::new();
     let items = payload.items;
+    let options = payload.options;
     let groups = {
         let file_groups = state.file_group.lock().unwrap();
 
@@ -346,11 +364,12 @@ async fn commit_rename_groups_cmd(
             match group {
                 FileNameGroup::Image { ref image, .. } => {
                     if let Some(next_stem) = image.next_file_stem_from_exif() {
-                        let file_count = eximd::exif::rename_with_rollback(
+                        let file_count = eximd::exif::rename_with_rollback_with_options(
                             &fs,
                             &nf,
                             group.merge_into_rename_refs(),
                             &next_stem,
+                            &options,
                         );
                         rename_group_count += 1;
                         rename_file_count += file_count;
@@ -358,11 +377,12 @@ async fn commit_rename_groups_cmd(
                 }
                 FileNameGroup::Video { ref video, .. } => {
                     if let Some(next_stem) = video.next_file_stem_from_exif() {
-                        let file_count = eximd::exif::rename_with_rollback(
+                        let file_count = eximd::exif::rename_with_rollback_with_options(
                             &fs,
                             &nf,
                             group.merge_into_rename_refs(),
                             &next_stem,
+                            &options,
                         );
                         rename_group_count += 1;
                         rename_file_count += file_count;
@@ -370,11 +390,12 @@ async fn commit_rename_groups_cmd(
                 }
                 FileNameGroup::LiveImage { ref image, .. } => {
                     if let Some(next_stem) = image.next_file_stem_from_exif() {
-                        let file_count = eximd::exif::rename_with_rollback(
+                        let file_count = eximd::exif::rename_with_rollback_with_options(
                             &fs,
                             &nf,
                             group.merge_into_rename_refs(),
                             &next_stem,
+                            &options,
                         );
                         rename_group_count += 1;
                         rename_file_count += file_count;
@@ -414,40 +435,323 @@ async fn drop_input_cmd(
     }
 }
 
+#[tauri::command]
+fn get_collect_options_cmd(
+    state: tauri::State<'_, Arc<AppState>>,
+) -> Result<CollectOptions, String> {
+    Ok(state.collect_options.lock().unwrap().clone())
+}
+
+#[tauri::command]
+fn set_collect_options_cmd(
+    state: tauri::State<'_, Arc<AppState>>,
+    payload: CollectOptions,
+) -> Result<(), String> {
+    let mut options = state.collect_options.lock().unwrap();
+    *options = payload;
+    Ok(())
+}
+
 #[tauri::command]
 fn collect_rename_files_cmd(
     state: tauri::State<'_, Arc<AppState>>,
     window: Window,
 ) -> Result<(), String> {
     let input_path = { state.source.lock().unwrap().clone() };
+    let options = { state.collect_options.lock().unwrap().clone() };
     let state = std::sync::Arc::clone(&state);
 
-    thread::spawn(move || match eximd::dir::collect_files(&input_path) {
-        Ok(files) => {
-            let file_count = files.len();
-            let file_groups = eximd::exif::group_same_name_files(&files);
+    // Drop the groups of the previous source, the new ones come in per directory.
+    state.file_group.lock().unwrap().clear();
+    let scanner = scan(&input_path, &options)?;
 
-            let mut group = state.file_group.lock().unwrap();
-            *group = file_groups;
+    thread::spawn(move || {
+        let mut file_count = 0;
+        for event in scanner {
+            match event {
+                ScanEvent::File(_) => file_count += 1,
+                ScanEvent::Groups(groups) => {
+                    let files = groups
+                        .iter()
+                        .map(|x| FileNameGroupV(x.clone()))
+                        .collect::<Vec<_>>();
+                    state.file_group.lock().unwrap().extend(groups);
+                    window
+                        .emit("COLLECTION_GROUPS", DropView { files, file_count })
+                        .expect("send message to FE to work");
+                }
+                ScanEvent::Error(err) => {
+                    eprintln!("ERROR: we could not collect files {:?}", err);
+                }
+            }
+        }
 
-            let files = group
+        let files = state
+            .file_group
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|x| FileNameGroupV(x.clone()))
+            .collect::<Vec<_>>();
+        let res = DropView { files, file_count };
+        window
+            .emit("COLLECTION_SUCCESS", res)
+            .expect("send message to FE to work");
+    });
+
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+struct DuplicateClusterView {
+    confidence: Confidence,
+    files: Vec<String>,
+}
+
+impl From<&DuplicateCluster> for DuplicateClusterView {
+    fn from(cluster: &DuplicateCluster) -> Self {
+        Self {
+            confidence: cluster.confidence,
+            files: cluster
+                .files
                 .iter()
-                .map(|x| FileNameGroupV(x.clone()))
-                .collect::<Vec<_>>();
+                .map(|x| x.to_string_lossy().to_string())
+                .collect(),
+        }
+    }
+}
 
-            let res = DropView { files, file_count };
-            window
-                .emit("COLLECTION_SUCCESS", res)
-                .expect("send message to FE to work");
+#[derive(Debug, serde::Serialize, Clone)]
+struct DupesDone {
+    clusters: usize,
+    skipped: usize,
+    cancelled: bool,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct FindDuplicatesPayload {
+    #[serde(default)]
+    similar_threshold: Option<u32>,
+}
+
+#[tauri::command]
+fn cancel_duplicates_cmd(state: tauri::State<'_, Arc<AppState>>) -> Result<(), String> {
+    let mut handles = state.dupes_handles.lock().unwrap();
+
+    for (_, token) in handles.iter() {
+        token.cancel();
+    }
+
+    while let Some((handle, _)) = handles.pop() {
+        handle
+            .join()
+            .expect("Could not join one of duplicate threads");
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+fn find_duplicates_cmd(
+    state: tauri::State<'_, Arc<AppState>>,
+    window: Window,
+    payload: FindDuplicatesPayload,
+) -> Result<(), String> {
+    let input_path = { state.source.lock().unwrap().clone() };
+    let options = { state.collect_options.lock().unwrap().clone() };
+    let hash_store = Arc::clone(&state.hash_store);
+    let cancel_token = CancelToken::new();
+    let mut finder = DuplicateFinder::new().cancel_token(cancel_token.clone());
+    if let Some(threshold) = payload.similar_threshold {
+        finder = finder.similar(threshold);
+    }
+    let scanner = scan(&input_path, &options)?;
+
+    let handle = thread::spawn(move || {
+        let files = scanner
+            .filter_map(|x| match x {
+                ScanEvent::File(file) => Some(file.src.value().to_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // The store is not locked while the files are read, an other search
+        // works on its own copy.
+        let mut store = hash_store.lock().unwrap().clone();
+        let summary = finder.find(
+            &mut store,
+            &files,
+            |progress| {
+                window
+                    .emit("DUPES_PROGRESS", progress)
+                    .expect("send message to the FE");
+            },
+            |cluster| {
+                window
+                    .emit("DUPES_CLUSTER", DuplicateClusterView::from(&cluster))
+                    .expect("send message to the FE");
+            },
+        );
+        hash_store.lock().unwrap().merge(store);
+
+        window
+            .emit(
+                "DUPES_DONE",
+                DupesDone {
+                    clusters: summary.clusters,
+                    skipped: summary.skipped.len(),
+                    cancelled: summary.cancelled,
+                },
+            )
+            .expect("send message to FE");
+    });
+
+    state
+        .dupes_handles
+        .lock()
+        .unwrap()
+        .push((handle, cancel_token));
+
+    Ok(())
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResolveAction {
+    Trash,
+    Hardlink,
+    Quarantine { dir: PathBuf },
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ResolveDuplicatesPayload {
+    clusters: Vec<Vec<PathBuf>>,
+    policy: KeepPolicy,
+    action: ResolveAction,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+struct ResolvedClusterView {
+    kept: String,
+    resolved: Vec<String>,
+    errors: Vec<String>,
+}
+
+#[tauri::command]
+fn resolve_duplicates_cmd(
+    state: tauri::State<'_, Arc<AppState>>,
+    app_handle: AppHandle,
+    window: Window,
+    payload: ResolveDuplicatesPayload,
+) -> Result<(), String> {
+    let fs = eximd::config::RealFileSystem::new(&eximd::config::RunType::Exec);
+    let log_dir = app_handle
+        .path_resolver()
+        .app_data_dir()
+        .ok_or("Failed to resolve the app data dir")?;
+    std::fs::create_dir_all(&log_dir).map_err(|err| err.to_string())?;
+    let mut log = UndoLog::create(&log_dir.join("dedupe-undo.jsonl"))?;
+    let action = match payload.action {
+        ResolveAction::Trash => DedupeAction::Trash(Trash::from_env()?),
+        ResolveAction::Hardlink => DedupeAction::Hardlink,
+        ResolveAction::Quarantine { dir } => {
+            let root = { state.source.lock().unwrap().clone() };
+            DedupeAction::Quarantine { dir, root }
         }
-        Err(err) => {
-            eprintln!("ERROR: we could not collect files {:?}", err);
+    };
+
+    thread::spawn(move || {
+        for cluster in payload.clusters {
+            let candidates = cluster
+                .iter()
+                .map(|x| Candidate::from_path(x))
+                .collect::<Vec<_>>();
+            let resolution =
+                eximd::dedupe::resolve_set(&fs, &candidates, &payload.policy, &action, &mut |x| {
+                    if let Err(err) = log.append(x) {
+                        eprintln!("ERROR: {}", err);
+                    }
+                });
+            let Some(resolution) = resolution else {
+                continue;
+            };
+            let view = ResolvedClusterView {
+                kept: resolution.kept.to_string_lossy().to_string(),
+                resolved: resolution
+                    .outcomes
+                    .iter()
+                    .filter(|x| x.error.is_none())
+                    .map(|x| x.path.to_string_lossy().to_string())
+                    .collect(),
+                errors: resolution
+                    .outcomes
+                    .iter()
+                    .filter_map(|x| x.error.clone())
+                    .collect(),
+            };
+            window
+                .emit("DUPES_RESOLVED", view)
+                .expect("send message to the FE");
         }
+
+        window
+            .emit("DUPES_RESOLVE_DONE", "")
+            .expect("send message to FE");
     });
 
     Ok(())
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct PreviewEventsPayload {
+    #[serde(default)]
+    options: EventOptions,
+    // The labels by the day the events start on, `2024-08-16`.
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+struct EventView {
+    name: String,
+    folder: String,
+    start: String,
+    end: String,
+    items: Vec<FileNameGroupKey>,
+}
+
+// The events of the groups with their exif data, for the FE to show before
+// the files are moved.
+#[tauri::command]
+fn preview_events_cmd(
+    state: tauri::State<'_, Arc<AppState>>,
+    payload: PreviewEventsPayload,
+) -> Result<Vec<EventView>, String> {
+    let file_group = state.file_group.lock().unwrap();
+    let mut events = eximd::events::cluster(&file_group, &payload.options);
+    eximd::events::label_events(&mut events, |x| {
+        payload
+            .labels
+            .get(&x.start.format("%Y-%m-%d").to_string())
+            .cloned()
+    });
+
+    Ok(events
+        .iter()
+        .map(|x| EventView {
+            name: x.name(),
+            folder: x.folder().to_string_lossy().to_string(),
+            start: x.start.to_string(),
+            end: x.end.to_string(),
+            items: x
+                .groups
+                .iter()
+                .map(|i| file_group[*i].group_key().clone())
+                .collect(),
+        })
+        .collect())
+}
+
 fn main() {
     tauri::Builder::default()
         .manage(Arc::new(AppState::default()))
@@ -462,10 +766,16 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             // TODO: command cancle exif collection if the app canceles or drops new files
             drop_input_cmd,
+            get_collect_options_cmd,
+            set_collect_options_cmd,
             collect_rename_files_cmd,
             start_exif_collection_cmd,
             cancel_exif_collection_cmd,
             commit_rename_groups_cmd,
+            find_duplicates_cmd,
+            cancel_duplicates_cmd,
+            resolve_duplicates_cmd,
+            preview_events_cmd,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
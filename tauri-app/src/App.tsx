@@ -45,8 +45,18 @@ const collectionDoneListener = fromCallback(({ sendBack }) => {
             }
         });
     });
+    // The groups come in per directory while the scan is still running.
+    const unlistenGroups = listen<{ file_count: number, files: FileGroupType[] }>('COLLECTION_GROUPS', async (event) => {
+        sendBack({
+            type: "COLLECTION_GROUPS", payload: {
+                filesCount: event.payload.file_count,
+                fileGroups: event.payload.files
+            }
+        });
+    });
     return () => {
         unlistenCollect.then((fn) => fn());
+        unlistenGroups.then((fn) => fn());
     }
 });
 
@@ -76,6 +86,9 @@ const dropMachine = setup({
         | { type: "CANCEL" }
         | {
             type: "COLLECTION_SUCCESS", payload: { filesCount: number, fileGroups: FileGroupType[] }
+        }
+        | {
+            type: "COLLECTION_GROUPS", payload: { filesCount: number, fileGroups: FileGroupType[] }
         },
         output: {
             validated_source: null | Path,
@@ -161,6 +174,12 @@ const dropMachine = setup({
                 }
             },
             on: {
+                COLLECTION_GROUPS: {
+                    actions: assign({
+                        filesCount: ({ event }) => event.payload.filesCount,
+                        fileGroups: ({ context, event }) => [...context.fileGroups, ...event.payload.fileGroups]
+                    })
+                },
                 COLLECTION_SUCCESS: {
                     target: 'done',
                     actions: assign({
@@ -315,7 +334,7 @@ function Selection({ onRename, onDuplicates }: {
     )
 }
 
-function CollectFeedback() {
+function CollectFeedback({ filesCount }: { filesCount: number | null }) {
     const [time, setTime] = useState(0);
 
     useEffect(() => {
@@ -340,7 +359,9 @@ function CollectFeedback() {
     // TODO: add a restrart logic if something goes wrong.
     return (
         <div className="w-[84vw] h-[440px] relative flex items-center justify-center">
-            {time >= 10000 ? (
+            {filesCount ? (
+                <span>Found {filesCount} files...</span>
+            ) : time >= 10000 ? (
                 <span>Something might have gone wrong.</span>
             ) : time >= 3600 ? (
                 <span style={enterFromTop({ delay: 300, duration: 300 })}>It's taking a little noger than usual.</span>
@@ -358,6 +379,7 @@ function CollectFeedback() {
 function Drop({ actorRef }: { actorRef: ActorRefFrom<typeof dropMachine> }) {
     const [isLeaving, navDelay] = useNavDelay(LEAVE_TIME);
     const isCollecting = useSelector(actorRef, state => state.matches("collecting"));
+    const filesCount = useSelector(actorRef, state => state.context.filesCount);
 
     return (
         <div className="h-full flex flex-col items-center justify-around">
@@ -368,7 +390,7 @@ function Drop({ actorRef }: { actorRef: ActorRefFrom<typeof dropMachine> }) {
             </div>
             <div style={isLeaving ? leaveToDown({ duration: 100 }) : enterFromDown()}>
                 {isCollecting ? (
-                    <CollectFeedback />
+                    <CollectFeedback filesCount={filesCount} />
                 ) : (
                     <button
                         className="w-[84vw] py-24 h-[440px] border-4 border-dashed border-green-500 group shadow-2xl translate-y-0 hover:-translate-y-2 hover:shadow-green-800/10 rounded-[52px] bg-neutral-900 hover:bg-neutral-800 transition-all ease-in-out duration-150">
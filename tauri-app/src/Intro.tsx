@@ -5,6 +5,8 @@ import { ActorRefFrom, assign, fromCallback, fromPromise, raise, sendParent, set
 import { listen } from '@tauri-apps/api/event';
 import { raiseErrorToUI } from './utils';
 import { invoke } from '@tauri-apps/api';
+import { useEffect, useState } from 'react';
+import { CollectOptions } from './config';
 
 type Props = {
     actorRef: ActorRefFrom<typeof introMachine>
@@ -105,6 +107,89 @@ const introMachine = setup({
     },
 });
 
+// The options of the walk, read by the next drop. One glob per line.
+function CollectSettings() {
+    const [options, setOptions] = useState<CollectOptions | null>(null);
+
+    useEffect(() => {
+        invoke<CollectOptions>("get_collect_options_cmd")
+            .then(setOptions)
+            .catch((error) => raiseErrorToUI({ event: { error } }));
+    }, []);
+
+    if (!options) {
+        return null;
+    }
+
+    const update = (change: Partial<CollectOptions>) => {
+        const next = { ...options, ...change };
+        setOptions(next);
+        invoke("set_collect_options_cmd", { payload: next })
+            .catch((error) => raiseErrorToUI({ event: { error } }));
+    };
+    const globs = (value: string) => value.split("\n").map((x) => x.trim()).filter((x) => x.length > 0);
+
+    return (
+        <details className="mt-8 p-8 text-md text-neutral-800 rounded-md bg-neutral-100 dark:bg-neutral-800 dark:text-neutral-300">
+            <summary className="text-lg font-medium cursor-pointer">Settings</summary>
+            <div className="grid grid-cols-2 gap-4 mt-4 text-sm">
+                <label className="flex flex-col">
+                    Include
+                    <textarea
+                        className="mt-1 p-2 rounded bg-white dark:bg-neutral-900"
+                        placeholder="**/*.jpg"
+                        defaultValue={options.include.join("\n")}
+                        onBlur={(e) => update({ include: globs(e.target.value) })}
+                    />
+                </label>
+                <label className="flex flex-col">
+                    Exclude
+                    <textarea
+                        className="mt-1 p-2 rounded bg-white dark:bg-neutral-900"
+                        placeholder="Backups/"
+                        defaultValue={options.exclude.join("\n")}
+                        onBlur={(e) => update({ exclude: globs(e.target.value) })}
+                    />
+                </label>
+                <label className="flex items-center gap-2">
+                    <input
+                        type="checkbox"
+                        checked={!options.skip_hidden}
+                        onChange={(e) => update({ skip_hidden: !e.target.checked })}
+                    />
+                    Hidden and OS files (.DS_Store, Thumbs.db, ...)
+                </label>
+                <label className="flex items-center gap-2">
+                    <input
+                        type="checkbox"
+                        checked={options.use_ignore_files}
+                        onChange={(e) => update({ use_ignore_files: e.target.checked })}
+                    />
+                    Read the .eximdignore files
+                </label>
+                <label className="flex items-center gap-2">
+                    <input
+                        type="checkbox"
+                        checked={options.follow_symlinks}
+                        onChange={(e) => update({ follow_symlinks: e.target.checked })}
+                    />
+                    Follow the symlinks
+                </label>
+                <label className="flex items-center gap-2">
+                    Max depth
+                    <input
+                        type="number"
+                        min={0}
+                        className="w-20 p-1 rounded bg-white dark:bg-neutral-900"
+                        value={options.max_depth ?? ""}
+                        onChange={(e) => update({ max_depth: e.target.value === "" ? null : Number(e.target.value) })}
+                    />
+                </label>
+            </div>
+        </details>
+    );
+}
+
 function Intro({ actorRef }: Props) {
     const rename = useSelector(actorRef, (state) => state.matches({ type: "rename" }));
     // const dedup = useSelector(actorRef, (state) => state.matches({ type: "dedup" }));
@@ -149,6 +234,7 @@ function Intro({ actorRef }: Props) {
                     </p>
                 )}
             </div>
+            <CollectSettings />
         </div>
     )
 }
@@ -5,6 +5,7 @@ import { invoke } from "@tauri-apps/api";
 import { listen } from "@tauri-apps/api/event";
 import { FileGroupToDisplay, FileGroupType, FileGroupUncertain, FileGroupUnsupported, Path, SrcFile } from "./config";
 import clsx from 'clsx';
+import { useState } from "react";
 
 type Props = {
     actorRef: ActorRefFrom<typeof renameMachine>
@@ -28,6 +29,19 @@ type ExifFileDataEvent = {
     ext: string,
 }
 
+type EventView = {
+    name: string,
+    folder: string,
+    start: string,
+    end: string,
+    items: string[],
+}
+
+type ExifProgress = {
+    done: number,
+    total: number,
+}
+
 const tauriExifDataListener = fromCallback(({ sendBack }) => {
     const unlisten = listen<{
         key: string,
@@ -46,11 +60,15 @@ const tauriExifDataListener = fromCallback(({ sendBack }) => {
             } as ExifFileDataEvent
         })
     })
+    const progressUnlisten = listen<ExifProgress>("EXIF_COLLECTION_PROGRESS", (data) => {
+        sendBack({ type: "EXIF_COLLECTION_PROGRESS", payload: data.payload });
+    });
     const doneUnlisten = listen("EXIF_COLLECTION_DONE", () => {
         sendBack({ type: "EXIF_COLLECTION_DONE" });
     });
     return () => {
         unlisten.then(fn => fn())
+        progressUnlisten.then(fn => fn())
         doneUnlisten.then(fn => fn())
     }
 });
@@ -209,9 +227,11 @@ const renameMachine = setup({
             renameGroupCount: number,
             selected_all: boolean,
             selected_count: number,
+            exifProgress: ExifProgress,
         },
         events: { type: "TOGGLE_SELECTION_ALL" }
         | { type: "EXIF_FILE_DATA", payload: ExifFileDataEvent }
+        | { type: "EXIF_COLLECTION_PROGRESS", payload: ExifProgress }
         | { type: "NAV_DROP_INPUT" }
         | { type: "EXIF_COLLECTION_DONE" }
         | { type: "SELECT_ITEM" }
@@ -304,6 +324,7 @@ const renameMachine = setup({
                 renameGroupCount: 0,
                 selected_all: true,
                 selected_count: 0,
+                exifProgress: { done: 0, total: 0 },
             }
         },
         type: 'parallel',
@@ -338,6 +359,11 @@ const renameMachine = setup({
                                             ({ event }) => ({ type: "SET_NEXT_STEM", payload: event.payload })
                                         ),
                                     },
+                                    EXIF_COLLECTION_PROGRESS: {
+                                        actions: assign({
+                                            exifProgress: ({ event }) => event.payload,
+                                        })
+                                    },
                                     EXIF_COLLECTION_DONE: '#rename-machine.view.ready',
                                 }
                             },
@@ -654,6 +680,28 @@ function UncertainItem({ actorRef }: {
     );
 }
 
+// The events the groups would be moved into, the same as `cli rename --events`.
+function EventsPreview({ events }: { events: EventView[] }) {
+    if (events.length === 0) {
+        return <p className="pt-[10vh] text-center text-neutral-500">No files with a date</p>;
+    }
+    return events.map((event) => (
+        <div
+            key={event.folder}
+            className="grid grid-cols-[minmax(50px,1fr)_300px] items-center py-2 pl-1.5 border-b border-neutral-800"
+        >
+            <div className="flex items-center whitespace-nowrap">
+                <svg className="w-4 ml-3 mr-4" fill="currentColor" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 512 512"><path className="fa-secondary" opacity=".4" d="M64 480H448c35.3 0 64-28.7 64-64V160c0-35.3-28.7-64-64-64H288c-10.1 0-19.6-4.7-25.6-12.8L243.2 57.6C231.1 41.5 212.1 32 192 32H64C28.7 32 0 60.7 0 96V416c0 35.3 28.7 64 64 64z" /></svg>
+                <span>{event.folder}</span>
+                <span className="ml-4 text-sm text-neutral-500">{event.items.length} groups</span>
+            </div>
+            <div className="flex whitespace-nowrap text-sm text-neutral-500">
+                {event.start} - {event.end}
+            </div>
+        </div>
+    ));
+}
+
 function Rename({ actorRef }: Props) {
     const source = useSelector(actorRef, (state) => {
         console.log("reanme state", state);
@@ -663,6 +711,7 @@ function Rename({ actorRef }: Props) {
     const unsupported = useSelector(actorRef, state => state.context.unsupported);
     const uncertain = useSelector(actorRef, state => state.context.uncertain);
     const isExifing = useSelector(actorRef, state => state.matches({ view: "exifing" }));
+    const exifProgress = useSelector(actorRef, state => state.context.exifProgress);
     const isCommitting = useSelector(actorRef, state => state.matches({ view: "committing" }));
     const isReady = useSelector(actorRef, state => state.matches({ view: "ready" }));
     const isDone = useSelector(actorRef, state => state.matches({ view: "done" }));
@@ -674,6 +723,16 @@ function Rename({ actorRef }: Props) {
     });
 
     const [isLeaving, navDelay] = useNavDelay(LEAVE_TIME - 200);
+    const [events, setEvents] = useState<EventView[] | null>(null);
+    const toggleEvents = () => {
+        if (events) {
+            setEvents(null);
+            return;
+        }
+        invoke<EventView[]>("preview_events_cmd", { payload: {} })
+            .then(setEvents)
+            .catch((error) => raiseErrorToUI({ event: { error } }));
+    };
     const nav = useSelector(actorRef, state => ({
         toRename: state.matches({ nav: "toRename" }),
         uncertain: state.matches({ nav: "uncertain" }),
@@ -695,6 +754,11 @@ function Rename({ actorRef }: Props) {
                                 info
                             </button>
                         </h2>
+                        {isExifing && exifProgress.total > 0 && (
+                            <span className="ml-20 text-sm text-neutral-500">
+                                Reading metadata {exifProgress.done}/{exifProgress.total}
+                            </span>
+                        )}
                     </div>
                     <div
                         className={clsx("flex p-2.5 h-[56px] mb-8 rounded-lg items-center shadow-lg bg-neutral-200 dark:bg-neutral-800", {
@@ -750,13 +814,24 @@ function Rename({ actorRef }: Props) {
                                 </svg>
                             </div>
                         ) : isReady ? (
-                            <button
-                                onClick={() => actorRef.send({ type: "COMMIT_RENAME_GROUPS" })}
-                                disabled={isExifing}
-                                className="ml-auto font-medium rounded-md px-6 py-1.5 text-black shadow-md bg-green-500 hover:bg-green-400 disabled:bg-green-300"
-                            >
-                                Rename
-                            </button>
+                            <>
+                                <button
+                                    onClick={toggleEvents}
+                                    className={clsx("ml-auto mr-4 relative px-4 py-1.5 rounded-md font-medium text-sm", {
+                                        "bg-white text-black": events,
+                                        "text-neutral-300 hover:text-neutral-400": !events
+                                    })}
+                                >
+                                    Events
+                                </button>
+                                <button
+                                    onClick={() => actorRef.send({ type: "COMMIT_RENAME_GROUPS" })}
+                                    disabled={isExifing}
+                                    className="font-medium rounded-md px-6 py-1.5 text-black shadow-md bg-green-500 hover:bg-green-400 disabled:bg-green-300"
+                                >
+                                    Rename
+                                </button>
+                            </>
                         ) : null}
                     </div>
 
@@ -789,6 +864,8 @@ function Rename({ actorRef }: Props) {
                                     </button>
                                 </div>
                             </div>
+                        ) : events && isReady ? (
+                            <EventsPreview events={events} />
                         ) : (
                             <>
                                 {nav.toRename ? (
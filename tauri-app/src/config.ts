@@ -4,7 +4,8 @@ export type SrcFile = {
     ext: string,
     src: string,
     src_relative: string,
-    stem: string
+    stem: string,
+    lossy: boolean,
 }
 
 export type FileGroupImage = {
@@ -44,3 +45,40 @@ export type FileGroupUnsupported = {
 
 export type FileGroupType = FileGroupImage | FileGroupVideo | FileGroupLiveImage | FileGroupUnsupported | FileGroupUncertain;
 export type FileGroupToDisplay = FileGroupImage | FileGroupVideo | FileGroupLiveImage;
+
+export type CollectOptions = {
+    include: string[],
+    exclude: string[],
+    skip_hidden: boolean,
+    use_ignore_files: boolean,
+    max_depth: number | null,
+    follow_symlinks: boolean,
+    threads: number,
+}
+
+export type DuplicateCluster = {
+    confidence: "exact" | "similar",
+    files: Path[],
+}
+
+export type DupesProgress = {
+    stage: "size" | "partial_hash" | "full_hash" | "perceptual_hash",
+    done: number,
+    total: number,
+}
+
+export type DupesDone = {
+    clusters: number,
+    skipped: number,
+    cancelled: boolean,
+}
+
+export type KeepPolicy = "oldest" | "largest" | "best_metadata" | { folder: Path };
+
+export type DedupeAction = { type: "trash" } | { type: "hardlink" } | { type: "quarantine", dir: Path };
+
+export type ResolvedCluster = {
+    kept: Path,
+    resolved: Path[],
+    errors: string[],
+}